@@ -3,506 +3,2258 @@ Abstract WFA using match and traceback functions
  */
 use std::cmp;
 
+use super::cigar::CigarOp;
+use super::degenerate::{self, DegenerateText};
+use super::elastic::ElasticText;
 use super::types;
 use super::utils::{self};
+use super::wf_adapt::wf_reduce;
+use super::wf_backtrace::wf_traceback_piggyback;
+use super::wf_bialign::{self, wf_bialign};
+use super::wf_elastic::{wf_align_elastic, wf_score_elastic, wf_search_elastic};
 use super::wf_extend::wf_extend;
 use super::wf_next as core;
-use super::wf_traceback::wf_traceback;
+use super::wf_traceback::{wf_traceback, wf_traceback_ends_free, wf_traceback_extension};
 use fbox::macros::max;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
-// TODO: return Result type
+/// Aligns reads while recycling wavefront memory between calls
+///
+/// Owns the [types::Config] and the wavefronts of the last alignment.
+/// Every call to [Aligner::align] hands those wavefronts back to the pool
+/// so that aligning many queries against one text does not reallocate them.
+pub struct Aligner {
+    config: types::Config,
+    wavefronts: types::WaveFronts,
+
+    /// The wavefronts of the alternatives of the last alignment against a
+    /// text with alternatives longer than one position, see [align_elastic]
+    alternatives: Vec<types::WaveFronts>,
+}
+
+impl Aligner {
+    pub fn new(config: types::Config) -> Self {
+        Self {
+            wavefronts: new_wavefronts(0, 0, &config),
+            alternatives: Vec::new(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &types::Config {
+        &self.config
+    }
+
+    /// Same as [wf_align] but reuses the wavefronts of previous alignments
+    pub fn align<F, G>(
+        &mut self,
+        tlen: u32,
+        qlen: u32,
+        match_lambda: &mut F,
+        traceback_lambda: &mut G,
+    ) -> Result<types::Alignment, types::AlignError>
+    where
+        F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+        G: FnMut((i32, i32), (i32, i32)) -> bool,
+    {
+        self.config.validate()?;
+        check_memory_mode(&self.config)?;
+        self.wavefronts.reset(tlen, qlen);
+        wf_align_in(
+            &mut self.wavefronts,
+            tlen,
+            qlen,
+            &self.config,
+            match_lambda,
+            traceback_lambda,
+        )
+    }
+
+    /// Same as [align_elastic] but reuses the wavefronts of the alternatives
+    /// of previous alignments
+    pub fn align_elastic(
+        &mut self,
+        text: &ElasticText,
+        query: &[u8],
+    ) -> Result<types::Alignment, types::AlignError> {
+        align_segments(&mut self.alternatives, text.segments(), query, &self.config)
+    }
+
+    /// Same as [align_edt] but reuses the wavefronts of the alternatives of
+    /// previous alignments
+    #[cfg(feature = "eds")]
+    pub fn align_edt(
+        &mut self,
+        edt: &eds::EDT,
+        query: &[u8],
+    ) -> Result<types::Alignment, types::AlignError> {
+        self.align_elastic(&elastic_of(edt)?, query)
+    }
+
+    /// Same as [align_degenerate] but reuses the wavefronts of previous alignments
+    pub fn align_degenerate<T: DegenerateText + ?Sized>(
+        &mut self,
+        text: &T,
+        query: &[u8],
+    ) -> Result<types::Alignment, types::AlignError> {
+        if degenerate::has_long_alternatives(text) {
+            let segments: degenerate::Segments = degenerate::to_segments(text)?;
+            let mut alignment =
+                align_segments(&mut self.alternatives, &segments, query, &self.config)
+                    .map_err(|err| with_partial_segments(err, text, query))?;
+            alignment.segments = degenerate::segment_alternatives(text, query, &alignment.cigar);
+
+            return Ok(alignment);
+        }
+
+        // there are no wavefronts to reuse when aligning from both ends
+        if self.config.memory_mode == types::MemoryMode::Ultralow {
+            return align_degenerate(text, query, &self.config);
+        }
+
+        let mut match_lambda = degenerate::match_lambda(text, query);
+        let mut traceback_lambda = degenerate::traceback_lambda(text, query);
+
+        let mut alignment = self
+            .align(
+                text.len() as u32,
+                query.len() as u32,
+                &mut match_lambda,
+                &mut traceback_lambda,
+            )
+            .map_err(|err| with_partial_segments(err, text, query))?;
+        alignment.segments = degenerate::segment_alternatives(text, query, &alignment.cigar);
+
+        Ok(alignment)
+    }
+}
+
+/// Align a query against a degenerate text
+/// The match and traceback lambdas are derived from the text
+///
+/// A text with an alternative longer than one position is aligned by
+/// [align_elastic] instead, so that the alignment follows one alternative
+/// from the start of each segment to its end. Each such segment and the run
+/// of positions after it have their own wavefronts, which costs more time
+/// and memory the more of them there are.
+pub fn align_degenerate<T: DegenerateText + ?Sized>(
+    text: &T,
+    query: &[u8],
+    config: &types::Config,
+) -> Result<types::Alignment, types::AlignError> {
+    let mut match_lambda = degenerate::match_lambda(text, query);
+    let mut traceback_lambda = degenerate::traceback_lambda(text, query);
+
+    let mut alignment = if degenerate::has_long_alternatives(text) {
+        align_segments(
+            &mut Vec::new(),
+            &degenerate::to_segments(text)?,
+            query,
+            config,
+        )
+        .map_err(|err| with_partial_segments(err, text, query))?
+    } else {
+        match config.memory_mode {
+            types::MemoryMode::High | types::MemoryMode::Low => wf_align(
+                text.len() as u32,
+                query.len() as u32,
+                config,
+                &mut match_lambda,
+                &mut traceback_lambda,
+            )
+            .map_err(|err| with_partial_segments(err, text, query))?,
+            types::MemoryMode::Ultralow => {
+                let rev_text = degenerate::Reversed(text);
+                let rev_query: Vec<u8> = query.iter().rev().cloned().collect();
+                let mut reverse_match_lambda = degenerate::match_lambda(&rev_text, &rev_query);
+
+                wf_align_bidirectional(
+                    text.len() as u32,
+                    query.len() as u32,
+                    config,
+                    &mut match_lambda,
+                    &mut reverse_match_lambda,
+                    &mut traceback_lambda,
+                )?
+            }
+        }
+    };
+    alignment.segments = degenerate::segment_alternatives(text, query, &alignment.cigar);
+
+    Ok(alignment)
+}
+
+/// Report the segment alternatives of the partial alignment a give up carries
+fn with_partial_segments<T: DegenerateText + ?Sized>(
+    mut err: types::AlignError,
+    text: &T,
+    query: &[u8],
+) -> types::AlignError {
+    if let types::AlignError::ScoreLimitExceeded {
+        partial: Some(alignment),
+        ..
+    } = &mut err
+    {
+        alignment.segments = degenerate::segment_alternatives(text, query, &alignment.cigar);
+    }
+
+    err
+}
+
+/// Align a query against an elastic degenerate text parsed by the eds crate
+/// The text is aligned as an [ElasticText], see [align_elastic]
+#[cfg(feature = "eds")]
+pub fn align_edt(
+    edt: &eds::EDT,
+    query: &[u8],
+    config: &types::Config,
+) -> Result<types::Alignment, types::AlignError> {
+    align_elastic(&elastic_of(edt)?, query, config)
+}
+
+#[cfg(feature = "eds")]
+fn elastic_of(edt: &eds::EDT) -> Result<ElasticText, types::AlignError> {
+    ElasticText::try_from(edt).map_err(|err| {
+        types::AlignError::InvalidConfig(format!("the elastic degenerate text is invalid: {err}"))
+    })
+}
+
+/// Align a query against an elastic degenerate text
+///
+/// The alternatives of a segment can have different lengths, including
+/// empty ones. Each alternative has its own wavefronts so the alignment is
+/// optimal over every string the text spells, its cigar spells the first of
+/// the alternatives it reports for each segment.
+pub fn align_elastic(
+    text: &ElasticText,
+    query: &[u8],
+    config: &types::Config,
+) -> Result<types::Alignment, types::AlignError> {
+    align_segments(&mut Vec::new(), text.segments(), query, config)
+}
+
+/// Align one alternative of each segment at a time, see [align_elastic]
+/// The wavefronts of the alternatives are taken from the pool and go back to it
+fn align_segments<P: Clone>(
+    pool: &mut Vec<types::WaveFronts>,
+    segments: &[Vec<Vec<P>>],
+    query: &[u8],
+    config: &types::Config,
+) -> Result<types::Alignment, types::AlignError>
+where
+    [P]: DegenerateText,
+{
+    config.validate()?;
+
+    if config.memory_mode != types::MemoryMode::High {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "texts with alternatives can only be aligned in the high memory mode",
+        )));
+    }
+
+    check_alternatives(segments, config)?;
+
+    wf_align_elastic(pool, segments, query, config)
+}
+
+/// What aligning each alternative on its own can't do
+fn check_alternatives<P>(
+    segments: &[Vec<Vec<P>>],
+    config: &types::Config,
+) -> Result<(), types::AlignError> {
+    if config.span != types::AlignmentSpan::EndToEnd {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "texts with alternatives can only be aligned end to end",
+        )));
+    }
+
+    // the transformed penalties only rank alignments that cover as much of the text
+    let same_length = |alts: &Vec<Vec<P>>| alts.iter().all(|alt| alt.len() == alts[0].len());
+    if config.penalties.has_bonus() && !segments.iter().all(same_length) {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "texts whose alternatives differ in length can't be aligned with a match bonus",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Align a query of qlen characters to a text of tlen characters over the
+/// span of the config, the text and query are only seen through the lambdas
+///
+/// The match lambda is called at (v, h) on a diagonal and returns true after
+/// moving past a match, it has to advance both v and h when it does.
+/// The traceback lambda is asked about every run of matches of the cigar,
+/// query\[q_start..q_stop\] against text\[t_start..t_stop\], and has to agree
+/// with the match lambda that they match. A run it rejects fails the
+/// alignment with [types::AlignError::LambdaContractViolation], it used to
+/// be left out of the cigar which then didn't span the query and the text.
 pub fn wf_align<F, G>(
     tlen: u32,
     qlen: u32,
     config: &types::Config,
     match_lambda: &mut F,
     traceback_lambda: &mut G,
-) -> Result<(usize, String), String>
+) -> Result<types::Alignment, types::AlignError>
 where
     F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
     G: FnMut((i32, i32), (i32, i32)) -> bool,
 {
-    if config.verbosity > 1 {
-        eprintln!("[wflambda::wf_align]");
+    config.validate()?;
+    check_memory_mode(config)?;
+    let mut all_wavefronts = new_wavefronts(tlen, qlen, config);
+
+    wf_align_in(
+        &mut all_wavefronts,
+        tlen,
+        qlen,
+        config,
+        match_lambda,
+        traceback_lambda,
+    )
+}
+
+/// Align in O(s) memory by computing wavefronts from both ends (BiWFA)
+///
+/// The reverse match lambda is the match lambda of the reversed text and
+/// query i.e. it compares query\[qlen - 1 - v\] with text\[tlen - 1 - h\].
+/// The score is that of [wf_align], on ties the cigar can be another
+/// alignment with the same score. The match lambdas of a degenerate text
+/// start over at a breakpoint so a run of matches across it can take a
+/// different alternative on each side, [align_degenerate] only uses them on
+/// texts whose alternatives are one position long.
+pub fn wf_align_bidirectional<F, R, G>(
+    tlen: u32,
+    qlen: u32,
+    config: &types::Config,
+    match_lambda: &mut F,
+    reverse_match_lambda: &mut R,
+    traceback_lambda: &mut G,
+) -> Result<types::Alignment, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    R: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    config.validate()?;
+
+    let bonus_config: types::Config;
+    let scoring: &types::Config = if config.penalties.has_bonus() {
+        bonus_config = without_bonus(config);
+        &bonus_config
+    } else {
+        config
+    };
+
+    let mut alignment = wf_bialign(
+        tlen,
+        qlen,
+        scoring,
+        match_lambda,
+        reverse_match_lambda,
+        traceback_lambda,
+        wf_bialign::FALLBACK_SCORE,
+    )?;
+
+    // end to end, the alignment ends on (qlen, tlen)
+    let k: i32 = tlen as i32 - qlen as i32;
+    alignment.score = user_score(config, alignment.score as usize, k, tlen as i32);
+
+    Ok(alignment)
+}
+
+/// The number of scores the next wavefront is computed from, with the
+/// penalties the wavefronts are computed with
+pub(crate) fn score_window(scoring: &types::Config) -> usize {
+    let p: &types::Penalties = &scoring.penalties;
+    let (o2, e2): (i32, i32) = scoring.distance.second_gap_piece().unwrap_or((0, 0));
+    max!(p.mismatch, p.gap_open + p.gap_extend, o2 + e2) as usize + 1
+}
+
+/// Wavefronts that keep what the memory mode of the config needs
+fn new_wavefronts(tlen: u32, qlen: u32, config: &types::Config) -> types::WaveFronts {
+    match config.memory_mode {
+        types::MemoryMode::Low if config.penalties.has_bonus() => {
+            types::WaveFronts::piggybacked(tlen, qlen, score_window(&without_bonus(config)))
+        }
+        types::MemoryMode::Low => types::WaveFronts::piggybacked(tlen, qlen, score_window(config)),
+        _ => types::WaveFronts::new(tlen, qlen),
+    }
+}
+
+/// Aligning from one end keeps every wavefront
+fn check_memory_mode(config: &types::Config) -> Result<(), types::AlignError> {
+    if config.memory_mode == types::MemoryMode::Ultralow {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "the ultralow memory mode needs a reverse match lambda, use wf_align_bidirectional",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Compute only the score of aligning a query against a degenerate text
+/// See [align_degenerate] for texts with alternatives longer than one position
+pub fn score_degenerate<T: DegenerateText + ?Sized>(
+    text: &T,
+    query: &[u8],
+    config: &types::Config,
+) -> Result<types::AlignmentScore, types::AlignError> {
+    if degenerate::has_long_alternatives(text) {
+        let segments: degenerate::Segments = degenerate::to_segments(text)?;
+        config.validate()?;
+        check_alternatives(&segments, config)?;
+        return wf_score_elastic(&segments, query, config);
+    }
+
+    let mut match_lambda = degenerate::match_lambda(text, query);
+
+    wf_score(
+        text.len() as u32,
+        query.len() as u32,
+        config,
+        &mut match_lambda,
+    )
+}
+
+/// Compute only the score of the alignment, there is no traceback so no
+/// traceback lambda
+///
+/// Only the wavefronts of the last max(mismatch, gap_open + gap_extend)
+/// scores, the ones the next wavefront is computed from, are kept.
+/// With two gap pieces gap_open2 + gap_extend2 counts too.
+pub fn wf_score<F>(
+    tlen: u32,
+    qlen: u32,
+    config: &types::Config,
+    match_lambda: &mut F,
+) -> Result<types::AlignmentScore, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    config.validate()?;
+
+    let _span = tracing::debug_span!("wf_score", tlen, qlen).entered();
+
+    let bonus_config: types::Config;
+    let scoring: &types::Config = if config.penalties.has_bonus() {
+        bonus_config = without_bonus(config);
+        &bonus_config
+    } else {
+        config
+    };
+
+    let mut all_wavefronts = types::WaveFronts::windowed(tlen, qlen, score_window(scoring));
+
+    let (score, k, offset) = match config.span {
+        types::AlignmentSpan::Extension { .. } => wf_compute_extension(
+            &mut all_wavefronts,
+            tlen,
+            qlen,
+            config,
+            scoring,
+            match_lambda,
+        )?,
+        _ => wf_compute(&mut all_wavefronts, tlen, qlen, scoring, match_lambda)?,
+    };
+    let (v, h) = (utils::compute_v(offset, k), utils::compute_h(offset, k));
+
+    Ok(types::AlignmentScore {
+        score: user_score(config, score, k, offset),
+        query_end: v as u32,
+        text_end: h as u32,
+    })
+}
+
+/// Find where a query occurs in a degenerate text with a score of at most max_score
+/// See [wf_search], a text with an alternative longer than one position is
+/// searched one alternative of each segment at a time like [align_degenerate]
+pub fn search_degenerate<T: DegenerateText + ?Sized>(
+    text: &T,
+    query: &[u8],
+    config: &types::Config,
+    max_score: usize,
+) -> Result<Vec<types::Occurrence>, types::AlignError> {
+    let mut match_lambda = degenerate::match_lambda(text, query);
+    let mut traceback_lambda = degenerate::traceback_lambda(text, query);
+
+    let mut occurrences = if degenerate::has_long_alternatives(text) {
+        check_search(config)?;
+        wf_search_elastic(&degenerate::to_segments(text)?, query, config, max_score)?
+    } else {
+        wf_search(
+            text.len() as u32,
+            query.len() as u32,
+            config,
+            max_score,
+            &mut match_lambda,
+            &mut traceback_lambda,
+        )?
+    };
+
+    let segment_starts: Vec<usize> = degenerate::segment_starts(text);
+    for occurrence in occurrences.iter_mut() {
+        occurrence.segments = degenerate::alternatives_from(
+            text,
+            query,
+            &occurrence.cigar,
+            occurrence.text_start as usize,
+            &segment_starts,
+        );
+    }
+
+    Ok(occurrences)
+}
+
+/// Find where a query occurs in an elastic degenerate text parsed by the eds crate
+/// See [search_elastic]
+#[cfg(feature = "eds")]
+pub fn search_edt(
+    edt: &eds::EDT,
+    query: &[u8],
+    config: &types::Config,
+    max_score: usize,
+) -> Result<Vec<types::Occurrence>, types::AlignError> {
+    search_elastic(&elastic_of(edt)?, query, config, max_score)
+}
+
+/// Find where a query occurs in an elastic degenerate text with a score of at most max_score
+/// See [wf_search], each occurrence follows one alternative of every segment
+/// it overlaps like [align_elastic]. Text positions are counted along the
+/// shortest path through the segments before them.
+pub fn search_elastic(
+    text: &ElasticText,
+    query: &[u8],
+    config: &types::Config,
+    max_score: usize,
+) -> Result<Vec<types::Occurrence>, types::AlignError> {
+    check_search(config)?;
+    wf_search_elastic(text.segments(), query, config, max_score)
+}
+
+/// Find where the whole query aligns to part of the text with a score of at
+/// most max_score, in text order
+///
+/// The query can start on any diagonal of the text. An occurrence is reported
+/// at its best ends, those with a score no higher than the ends next to them.
+/// The ends around it aren't, the wavefronts only keep the furthest point of
+/// each diagonal and can miss them or overestimate their score.
+/// For an edit distance of at most k use a mismatch and gap_extend of 1,
+/// a gap_open of 0 and k as max_score.
+pub fn wf_search<F, G>(
+    tlen: u32,
+    qlen: u32,
+    config: &types::Config,
+    max_score: usize,
+    match_lambda: &mut F,
+    traceback_lambda: &mut G,
+) -> Result<Vec<types::Occurrence>, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    check_search(config)?;
+
+    let _span = tracing::debug_span!("wf_search", tlen, qlen, max_score).entered();
+
+    let mut all_wavefronts = types::WaveFronts::new(tlen, qlen);
+    wf_begin_free(&mut all_wavefronts, tlen, qlen, tlen, 0);
+
+    // the lowest score and diagonal each end of the text is reached with
+    let mut ends: Vec<Option<(usize, i32)>> = vec![None; tlen as usize + 1];
+
+    for score in 0..=max_score {
+        if score > 0 {
+            core::wf_next(&mut all_wavefronts, score, config)?;
+        }
+
+        if all_wavefronts.get_m_wavefront(score as i32).is_none() {
+            continue;
+        }
+
+        let m_wf: &mut types::WaveFront =
+            all_wavefronts.get_mut(score).unwrap().m.as_mut().unwrap();
+        wf_extend(m_wf, match_lambda, score)?;
+
+        // diagonals that consumed the whole query
+        for k in m_wf.lo..=m_wf.hi {
+            let offset: i32 = m_wf.get_offset(k).unwrap().max();
+            if offset >= 0 && utils::compute_v(offset, k) == qlen as i32 {
+                ends[offset as usize].get_or_insert((score, k));
+            }
+        }
+    }
+
+    // an end missing from ends is worse than any that was found
+    let score_at = |h: Option<usize>| -> usize {
+        h.and_then(|h| ends.get(h).copied().flatten())
+            .map_or(usize::MAX, |(score, _)| score)
+    };
+
+    let mut occurrences: Vec<types::Occurrence> = Vec::new();
+    for (text_end, end) in ends.iter().enumerate() {
+        let Some((score, k)) = *end else {
+            continue;
+        };
+
+        // the best end of each occurrence
+        if score > score_at(text_end.checked_sub(1)) || score > score_at(Some(text_end + 1)) {
+            continue;
+        }
+
+        let cigar = wf_traceback_ends_free(
+            &all_wavefronts,
+            score,
+            (k, text_end as i32),
+            config,
+            traceback_lambda,
+        )?;
+
+        // drop the text before and after the occurrence
+        let mut ops: Vec<CigarOp> = cigar.ops().collect();
+        ops.truncate(ops.len() - (tlen as usize - text_end));
+        let text_start: usize = ops
+            .iter()
+            .take_while(|op| **op == CigarOp::Insertion)
+            .count();
+
+        occurrences.push(types::Occurrence {
+            score,
+            text_start: text_start as u32,
+            text_end: text_end as u32,
+            cigar: ops.into_iter().skip(text_start).collect(),
+            segments: Vec::new(),
+        });
+    }
+
+    Ok(occurrences)
+}
+
+/// What a search can't be computed with
+fn check_search(config: &types::Config) -> Result<(), types::AlignError> {
+    config.validate()?;
+    check_memory_mode(config)?;
+
+    if config.adapt {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "a search needs every diagonal, adapt would drop some",
+        )));
+    }
+
+    if config.memory_mode == types::MemoryMode::Low {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "a search traces back from ends of any score, it can't use the low memory mode",
+        )));
+    }
+
+    if config.span != types::AlignmentSpan::EndToEnd {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "a search frees both ends of the text, leave the span as end to end",
+        )));
+    }
+
+    // occurrences of different lengths can't be ranked by transformed penalties
+    if config.penalties.has_bonus() {
+        return Err(types::AlignError::InvalidConfig(String::from(
+            "a search can't have a match bonus, max_score bounds the penalties",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Align using wavefronts that have been reset for this tlen and qlen
+fn wf_align_in<F, G>(
+    all_wavefronts: &mut types::WaveFronts,
+    tlen: u32,
+    qlen: u32,
+    config: &types::Config,
+    match_lambda: &mut F,
+    traceback_lambda: &mut G,
+) -> Result<types::Alignment, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    let _span = tracing::debug_span!("wf_align", tlen, qlen).entered();
+
+    let bonus_config: types::Config;
+    let scoring: &types::Config = if config.penalties.has_bonus() {
+        bonus_config = without_bonus(config);
+        &bonus_config
+    } else {
+        config
+    };
+
+    if let types::AlignmentSpan::Extension { .. } = config.span {
+        let (score, k, offset) =
+            wf_compute_extension(all_wavefronts, tlen, qlen, config, scoring, match_lambda)?;
+        let cigar = wf_traceback_extension(
+            all_wavefronts,
+            score,
+            (k, offset),
+            scoring,
+            traceback_lambda,
+        )?;
+
+        return Ok(types::Alignment {
+            score: user_score(config, score, k, offset),
+            cigar,
+            segments: Vec::new(),
+        });
+    }
+
+    let (score, k, offset) = match wf_compute(all_wavefronts, tlen, qlen, scoring, match_lambda) {
+        Err(types::AlignError::ScoreLimitExceeded {
+            score,
+            max_score,
+            query_end,
+            text_end,
+            ..
+        }) if config.partial_alignment => {
+            let partial: Option<types::Alignment> =
+                wf_traceback_partial(all_wavefronts, score, config, scoring, traceback_lambda)?;
+
+            return Err(types::AlignError::ScoreLimitExceeded {
+                score,
+                max_score,
+                query_end,
+                text_end,
+                partial: partial.map(Box::new),
+            });
+        }
+        res => res?,
+    };
+    let cigar = match config.span {
+        _ if config.memory_mode == types::MemoryMode::Low => wf_traceback_piggyback(
+            all_wavefronts,
+            score,
+            (k, offset),
+            scoring,
+            traceback_lambda,
+        )?,
+        types::AlignmentSpan::EndToEnd => {
+            wf_traceback(all_wavefronts, score, scoring, traceback_lambda)?
+        }
+        types::AlignmentSpan::EndsFree { .. } => wf_traceback_ends_free(
+            all_wavefronts,
+            score,
+            (k, offset),
+            scoring,
+            traceback_lambda,
+        )?,
+        types::AlignmentSpan::Extension { .. } => unreachable!("extensions return early"),
+    };
+
+    Ok(types::Alignment {
+        score: user_score(config, score, k, offset),
+        cigar,
+        segments: Vec::new(),
+    })
+}
+
+/// The alignment to the cell furthest along the antidiagonals out of the M
+/// wavefronts up to the given score that are still kept, traced back from
+/// the lowest score that reaches it. None when no wavefront has a cell
+fn wf_traceback_partial<G>(
+    all_wavefronts: &types::WaveFronts,
+    score: usize,
+    config: &types::Config,
+    scoring: &types::Config,
+    traceback_lambda: &mut G,
+) -> Result<Option<types::Alignment>, types::AlignError>
+where
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    // the wavefront of the last score can have no cell, or hold one that a
+    // lower score reaches too
+    let furthest = (0..=score)
+        .filter_map(|s| {
+            let (k, offset) = utils::furthest_cell(all_wavefronts.get_m_wavefront(s as i32))?;
+            Some((s, k, offset))
+        })
+        .max_by_key(|(s, k, offset)| {
+            let (v, h) = (utils::compute_v(*offset, *k), utils::compute_h(*offset, *k));
+            (v + h, cmp::Reverse(*s))
+        });
+    let Some((score, k, offset)) = furthest else {
+        return Ok(None);
+    };
+
+    // an end to end alignment starts on the only diagonal of the initial wavefront
+    let cigar = if config.memory_mode == types::MemoryMode::Low {
+        wf_traceback_piggyback(
+            all_wavefronts,
+            score,
+            (k, offset),
+            scoring,
+            traceback_lambda,
+        )?
+    } else {
+        wf_traceback_ends_free(
+            all_wavefronts,
+            score,
+            (k, offset),
+            scoring,
+            traceback_lambda,
+        )?
+    };
+
+    // drop the rest of the query and text that the traceback leaves unaligned
+    let tlen: i32 = all_wavefronts.max_k as i32;
+    let qlen: i32 = -all_wavefronts.min_k as i32;
+    let (v, h) = (utils::compute_v(offset, k), utils::compute_h(offset, k));
+    let mut ops: Vec<CigarOp> = cigar.ops().collect();
+    ops.truncate(ops.len() - (qlen - v + tlen - h) as usize);
+
+    Ok(Some(types::Alignment {
+        score: user_score(config, score, k, offset),
+        cigar: ops.into_iter().collect(),
+        segments: Vec::new(),
+    }))
+}
+
+/// Compute wavefronts until one reaches the end of the alignment
+/// Returns its score and the (k, offset) it ended on
+fn wf_compute<F>(
+    all_wavefronts: &mut types::WaveFronts,
+    tlen: u32,
+    qlen: u32,
+    config: &types::Config,
+    match_lambda: &mut F,
+) -> Result<(usize, i32, i32), types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    // compute the central diagonal, a_k.
+    let a_k: i32 = all_wavefronts.a_k;
+
+    // the offset the central diagonal reaches at the end, offsets are along the text
+    let a_offset: u32 = tlen;
+
+    let max_score: usize = config.score_limit(tlen, qlen);
+
+    // score
+    let mut score: usize = 0;
+
+    // the (v, h) furthest along the antidiagonals so far
+    let mut reached: (i32, i32) = (0, 0);
+
+    // the furthest offset on the central diagonal so far, for the progress hook
+    let mut a_k_offset: i32 = 0;
+
+    // every alignment starts from offset 0 on diagonal 0 at score 0
+    let start: &types::Offset = all_wavefronts
+        .get_m_wavefront(0)
+        .and_then(|m_wf| m_wf.get_offset(0))
+        .ok_or_else(|| {
+            types::AlignError::Internal(String::from(
+                "no offset on diagonal 0 of the M wavefront at score 0",
+            ))
+        })?;
+
+    if start.as_slice() != [0] {
+        return Err(types::AlignError::Internal(format!(
+            "the alignment should start at offset 0, not {:?}",
+            start.as_slice()
+        )));
+    }
+
+    if let types::AlignmentSpan::EndsFree {
+        text_begin_free,
+        query_begin_free,
+        ..
+    } = config.span
+    {
+        wf_begin_free(
+            all_wavefronts,
+            tlen,
+            qlen,
+            text_begin_free,
+            query_begin_free,
+        );
+    }
+
+    tracing::debug!(a_k, a_offset, max_score, "computing wavefronts");
+
+    loop {
+        // Extend the current wavefront
+        if all_wavefronts.get_m_wavefront(score as i32).is_some() {
+            let m_wf_mut: &mut types::WaveFront =
+                all_wavefronts.get_mut(score).unwrap().m.as_mut().unwrap();
+
+            wf_extend(m_wf_mut, match_lambda, score)?;
+        }
+
+        if config.adapt {
+            wf_reduce(all_wavefronts, score, tlen as i32, qlen as i32, config);
+        }
+
+        if let Some(progress) = config.progress.as_deref() {
+            let offset: Option<i32> = all_wavefronts
+                .get_m_wavefront(score as i32)
+                .and_then(|m_wf| m_wf.get_offset(a_k))
+                .map(|offsets| offsets.max());
+            a_k_offset = max!(a_k_offset, offset.unwrap_or(0));
+            progress.update(score, a_k_offset as u32, a_offset);
+        }
+
+        // Check whether we have reached the final point
+        // Get the m-wavefront with the current score
+        let end: Option<(i32, i32)> = match config.span {
+            types::AlignmentSpan::EndToEnd => {
+                utils::end_reached(all_wavefronts.get_m_wavefront(score as i32), a_k, a_offset)
+                    .then_some((a_k, a_offset as i32))
+            }
+            types::AlignmentSpan::EndsFree {
+                text_end_free,
+                query_end_free,
+                ..
+            } => utils::end_reached_free(
+                all_wavefronts.get_m_wavefront(score as i32),
+                tlen as i32,
+                qlen as i32,
+                text_end_free as i32,
+                query_end_free as i32,
+            ),
+            types::AlignmentSpan::Extension { .. } => {
+                unreachable!("extensions are computed by wf_compute_extension")
+            }
+        };
+
+        if let Some((k, offset)) = end {
+            if let Some(progress) = config.progress.as_deref() {
+                progress.finish();
+            }
+            return Ok((score, k, offset));
+        }
+
+        reached = utils::furthest_reached(all_wavefronts.get_m_wavefront(score as i32), reached);
+
+        // give up, only once the end has been checked for at this score
+        if score >= max_score {
+            if let Some(progress) = config.progress.as_deref() {
+                progress.finish();
+            }
+            return Err(types::AlignError::ScoreLimitExceeded {
+                score,
+                max_score,
+                query_end: reached.0 as u32,
+                text_end: reached.1 as u32,
+                partial: None,
+            });
+        }
+
+        score += 1;
+
+        // TODO: compute the next wavefront
+        core::wf_next(all_wavefronts, score, config)?;
+    }
+}
+
+/// The config with the match bonus folded into the penalties that the
+/// wavefronts are computed with, see [types::Penalties::without_bonus]
+pub(crate) fn without_bonus(config: &types::Config) -> types::Config {
+    let mut scoring: types::Config = config.clone();
+    scoring.penalties = config.penalties.without_bonus();
+    scoring.distance = config.distance.without_bonus(config.penalties.matches);
+    scoring
+}
+
+/// The score, in the scoring scheme of the config, of an alignment that
+/// ends on (k, offset) with the given score under [without_bonus]
+pub(crate) fn user_score(config: &types::Config, score: usize, k: i32, offset: i32) -> i32 {
+    if !config.penalties.has_bonus() {
+        return score as i32;
+    }
+
+    let (v, h) = (utils::compute_v(offset, k), utils::compute_h(offset, k));
+    config.penalties.bonus_score(score as i32, v, h)
+}
+
+/// Compute the wavefronts of an extension, scored by the penalties of
+/// scoring, until its score drops too far below the best one
+/// Returns the score and the (k, offset) of the best scoring cell
+fn wf_compute_extension<F>(
+    all_wavefronts: &mut types::WaveFronts,
+    tlen: u32,
+    qlen: u32,
+    config: &types::Config,
+    scoring: &types::Config,
+    match_lambda: &mut F,
+) -> Result<(usize, i32, i32), types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    let p: &types::Penalties = &config.penalties;
+    let drop: types::ScoreDrop = match config.span {
+        types::AlignmentSpan::Extension { drop } => drop,
+        _ => unreachable!("only extensions have a score drop"),
+    };
+
+    // the empty alignment at the start scores 0
+    let mut best: (i32, usize, i32, i32) = (0, 0, 0, 0);
+    let mut score: usize = 0;
+
+    loop {
+        if all_wavefronts.get_m_wavefront(score as i32).is_some() {
+            let m_wf: &mut types::WaveFront =
+                all_wavefronts.get_mut(score).unwrap().m.as_mut().unwrap();
+            wf_extend(m_wf, match_lambda, score)?;
+
+            // the best cell of this wavefront
+            let current: Option<(i32, i32, i32)> = (m_wf.lo..=m_wf.hi)
+                .filter_map(|k| {
+                    let offset: i32 = m_wf.get_offset(k).unwrap().max();
+                    (offset >= 0).then_some((k, offset))
+                })
+                .map(|(k, offset)| {
+                    let (v, h) = (utils::compute_v(offset, k), utils::compute_h(offset, k));
+                    (p.bonus_score(score as i32, v, h), k, offset)
+                })
+                .max_by_key(|(bonus_score, _, _)| *bonus_score);
+
+            // a wavefront can be left with no cell in bounds, the gaps of
+            // later scores may still reach some
+            if let Some((current_score, k, offset)) = current {
+                if current_score > best.0 {
+                    best = (current_score, score, k, offset);
+                }
+
+                let allowed: i32 = match drop {
+                    types::ScoreDrop::XDrop(x) => x as i32,
+                    types::ScoreDrop::ZDrop(z) => {
+                        let best_k: i32 = best.2;
+                        z as i32 + p.gap_extend * (k - best_k).abs()
+                    }
+                };
+
+                if best.0 - current_score > allowed {
+                    break;
+                }
+            }
+        }
+
+        // no cell left can score higher than the best
+        if p.matches * (tlen + qlen) as i32 - (score as i32) < 2 * best.0 {
+            break;
+        }
+
+        if config.max_score.is_some_and(|max_score| score >= max_score) {
+            break;
+        }
+
+        if config.adapt {
+            wf_reduce(all_wavefronts, score, tlen as i32, qlen as i32, scoring);
+        }
+
+        score += 1;
+        core::wf_next(all_wavefronts, score, scoring)?;
     }
 
-    // compute the central diagonal, a_k.
-    let a_k: i32 = (tlen as isize - qlen as isize) as i32;
+    let (_, score, k, offset) = best;
+    Ok((score, k, offset))
+}
+
+/// Start the alignment on every diagonal whose start skips at most
+/// text_begin_free text or query_begin_free query characters
+fn wf_begin_free(
+    all_wavefronts: &mut types::WaveFronts,
+    tlen: u32,
+    qlen: u32,
+    text_begin_free: u32,
+    query_begin_free: u32,
+) {
+    let hi: i32 = cmp::min(text_begin_free, tlen) as i32;
+    let lo: i32 = -(cmp::min(query_begin_free, qlen) as i32);
+
+    let m_wf: &mut types::WaveFront = all_wavefronts.get_mut(0).unwrap().m.as_mut().unwrap();
+    m_wf.widen(hi, lo);
+
+    // h = k past the start of the text or v = -k past the start of the query
+    for k in lo..=hi {
+        *m_wf.get_offset_mut(k).unwrap() = types::Offset::from([cmp::max(k, 0)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn test_config() -> types::Config {
+        types::Config {
+            penalties: types::Penalties {
+                mismatch: 1,
+                matches: 0,
+                gap_open: 2,
+                gap_extend: 1,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A linear congruential generator, the same seed gives the same inputs
+    struct Random(u32);
+
+    impl Random {
+        /// A number below n
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+            (self.0 >> 16) as usize % n
+        }
+
+        /// A text of len random bases
+        fn bases(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| b"ACGT"[self.below(4)]).collect()
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let text = segmented("ATC{TA,GA}A");
+        let alignment = align_degenerate(&text, b"ATCGAA", &test_config()).unwrap();
+
+        assert_eq!(
+            (0, "MMMMMM"),
+            (alignment.score, alignment.cigar.to_raw_string().as_str())
+        );
+        assert_eq!(alignment.segments[0].alternatives, vec![1]);
+    }
+
+    // also tests replacement
+    #[test]
+    fn test_artifact_match() {
+        let text = segmented("ATC{TA,GC}A");
+        let alignment = align_degenerate(&text, b"ATCGAA", &test_config()).unwrap();
+
+        assert_eq!(
+            (1, "MMMXMM"),
+            (alignment.score, alignment.cigar.to_raw_string().as_str())
+        );
+        assert_eq!(alignment.cigar.to_sam_string(), "6M");
+    }
+
+    #[test]
+    fn test_snps() {
+        let query = b"TGGGCACTATCCCTTGTACGTTCGGAGTTTCATATTGTGTATCAAATATATTTATTAG\
+                      CTCTTTTGAGCCTGACGAGCTGGGTAG";
+        let text = segmented(
+            "TAGGC{TGG,ACT}ATCCCTT{TAA,GTA}{AT,CG}TTCTCA{C,G}TTTC\
+             CA{TGG,ATT}{C,G}TGAATCAAATGTATTTAT{TCGG,TAGG}CT{A,C}TT\
+             TTGAGC{AG,CT}GACTA{GTT,GCT}AGTTAG",
+        );
+        let alignment = align_degenerate(&text, query, &test_config()).unwrap();
+
+        // the best of the 1024 paths through the segments
+        assert_eq!(
+            (13, "1M1X21M2X6M2X6M1X7M1X10M1I16M1X4M1X1M1X3M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn test_aligner_reuse() {
+        let text = "ACGTACGTTGCA".as_bytes();
+        let queries = [
+            "ACGTACGTTGCA",
+            "ACGAACGTTGCA",
+            "ACGTCGTTGCA",
+            "ACGTACGGTTGCA",
+        ];
+
+        let config = test_config();
+        let tlen = text.len();
+
+        let mut aligner = Aligner::new(test_config());
+
+        for query in queries.iter() {
+            let query = query.as_bytes();
+            let qlen = query.len();
+
+            let mut match_lambda =
+                |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| -> bool {
+                    if *v < 0 || *h < 0 || *h as usize >= tlen || *v as usize >= qlen {
+                        return false;
+                    }
+
+                    if text[*h as usize] != query[*v as usize] {
+                        return false;
+                    }
+
+                    offsets.iter_mut().for_each(|offset| *offset += 1);
+                    *v += 1;
+                    *h += 1;
+
+                    true
+                };
+
+            let mut traceback_lambda = |_: (i32, i32), _: (i32, i32)| -> bool { true };
+
+            let expected = wf_align(
+                tlen as u32,
+                qlen as u32,
+                &config,
+                &mut match_lambda,
+                &mut traceback_lambda,
+            );
+
+            let reused = aligner.align(
+                tlen as u32,
+                qlen as u32,
+                &mut match_lambda,
+                &mut traceback_lambda,
+            );
+
+            assert_eq!(expected, reused);
+        }
+
+        // resetting hands every wavefront back to the pool
+        aligner.wavefronts.reset(0, 0);
+        assert_eq!(aligner.wavefronts.len(), 1);
+        assert!(aligner.wavefronts.pool_len() > 0);
+
+        // the wavefronts of the alternatives go back to a pool of their own
+        let text: ElasticText = "ACT{A,ATTG,}CA{GT,C}TTACG".parse().unwrap();
+        for query in [&b"ACTATGCAGTTTACG"[..], b"ACTCACTTAG", b"ACTATTGCATTACG"] {
+            let expected = align_elastic(&text, query, &config);
+            assert_eq!(aligner.align_elastic(&text, query), expected);
+            assert_eq!(aligner.alternatives.len(), 8);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let text = "ACGT".as_bytes();
+        let tlen = text.len();
+
+        let mut traceback_lambda = |_: (i32, i32), _: (i32, i32)| -> bool { true };
+
+        // the score can't grow with a zero mismatch penalty
+        let mut config = test_config();
+        config.penalties.mismatch = 0;
+        let mut match_lambda = |_: &mut i32, _: &mut i32, _: &mut types::Offset| false;
+        let res = wf_align(4, 4, &config, &mut match_lambda, &mut traceback_lambda);
+        assert!(matches!(res, Err(types::AlignError::InvalidConfig(_))));
+
+        // a match that doesn't move forward
+        let config = test_config();
+        let mut match_lambda = |_: &mut i32, _: &mut i32, _: &mut types::Offset| true;
+        let res = wf_align(4, 4, &config, &mut match_lambda, &mut traceback_lambda);
+        assert!(matches!(
+            res,
+            Err(types::AlignError::LambdaContractViolation(_))
+        ));
+
+        // a traceback lambda that disagrees with the match lambda
+        let mut match_lambda = |v: &mut i32, h: &mut i32, _: &mut types::Offset| {
+            let matched = *v < 4 && *h < 4;
+            if matched {
+                (*v, *h) = (*v + 1, *h + 1);
+            }
+            matched
+        };
+        let mut reject = |_: (i32, i32), _: (i32, i32)| false;
+        let res = wf_align(4, 4, &config, &mut match_lambda, &mut reject);
+        assert!(matches!(
+            res,
+            Err(types::AlignError::LambdaContractViolation(_))
+        ));
+
+        // wavefronts that don't start from offset 0 are a bug, not a bad config
+        let mut wavefronts = types::WaveFronts::new(4, 4);
+        let start: &mut types::Offset = wavefronts
+            .get_mut(0)
+            .and_then(|wf_set| wf_set.m.as_mut())
+            .and_then(|m_wf| m_wf.get_offset_mut(0))
+            .unwrap();
+        *start = types::Offset::from([1]);
+        let mut match_lambda = |_: &mut i32, _: &mut i32, _: &mut types::Offset| false;
+        let res = wf_compute(&mut wavefronts, 4, 4, &config, &mut match_lambda);
+        assert!(matches!(res, Err(types::AlignError::Internal(_))));
+
+        // a query much longer than the text
+        let query = "ACGTTTTTTTTTTTTTTTTT".as_bytes();
+        let qlen = query.len();
+        let mut match_lambda = |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| -> bool {
+            if *v < 0 || *h < 0 || *h as usize >= tlen || *v as usize >= qlen {
+                return false;
+            }
+
+            if text[*h as usize] != query[*v as usize] {
+                return false;
+            }
+
+            offsets.iter_mut().for_each(|offset| *offset += 1);
+            *v += 1;
+            *h += 1;
+
+            true
+        };
+        let alignment = wf_align(
+            tlen as u32,
+            qlen as u32,
+            &config,
+            &mut match_lambda,
+            &mut traceback_lambda,
+        )
+        .unwrap();
+        assert_eq!(alignment.score, 18);
+        assert_eq!(alignment.cigar.query_len(), qlen);
+
+        // gives up one short of it, with all but one deletion done
+        let mut config = test_config();
+        config.max_score = Some(17);
+        let res = wf_align(
+            tlen as u32,
+            qlen as u32,
+            &config,
+            &mut match_lambda,
+            &mut traceback_lambda,
+        );
+        assert_eq!(
+            res,
+            Err(types::AlignError::ScoreLimitExceeded {
+                score: 17,
+                max_score: 17,
+                query_end: 19,
+                text_end: 4,
+                partial: None,
+            })
+        );
+
+        // and traces back to how far it got on request
+        config.partial_alignment = true;
+        for memory_mode in [types::MemoryMode::High, types::MemoryMode::Low] {
+            config.memory_mode = memory_mode;
+            let res = wf_align(
+                tlen as u32,
+                qlen as u32,
+                &config,
+                &mut match_lambda,
+                &mut traceback_lambda,
+            );
+            let Err(types::AlignError::ScoreLimitExceeded {
+                partial: Some(partial),
+                ..
+            }) = res
+            else {
+                panic!("expected a partial alignment, got {res:?}");
+            };
+            assert_eq!(
+                (partial.score, partial.cigar.to_string().as_str()),
+                (17, "4M15D")
+            );
+        }
 
-    // the furthest offset we expect the central diagonal to reach
-    // subtract 1 because of the zero index
-    // let a_offset: u32 = max![tlen, qlen];
-    let a_offset: u32 = tlen;
+        // a degenerate text reports the alternatives of the partial alignment
+        let degenerate_text: Vec<Vec<u8>> =
+            vec![vec![b'A'], vec![b'C', b'G'], vec![b'G'], vec![b'T']];
+        let res = align_degenerate(&degenerate_text, query, &config);
+        let Err(types::AlignError::ScoreLimitExceeded {
+            partial: Some(partial),
+            ..
+        }) = res
+        else {
+            panic!("expected a partial alignment, got {res:?}");
+        };
+        assert_eq!(
+            partial.segments,
+            vec![types::SegmentAlternatives {
+                segment: 1,
+                alternatives: vec![0],
+            }]
+        );
 
-    // eprintln!("\t a_k {} a_offset {}", a_k, a_offset);
+        // an alignment that scores max_score is still found
+        config.memory_mode = types::MemoryMode::High;
+        config.partial_alignment = false;
+        config.max_score = Some(18);
+        let res = wf_align(
+            tlen as u32,
+            qlen as u32,
+            &config,
+            &mut match_lambda,
+            &mut traceback_lambda,
+        );
+        assert_eq!(res.map(|alignment| alignment.score), Ok(18));
+
+        // at least one thread and more only with rayon
+        let mut config = test_config();
+        config.thread_count = 0;
+        let res = align_degenerate(text, query, &config);
+        assert!(matches!(res, Err(types::AlignError::InvalidConfig(_))));
+        config.thread_count = 2;
+        let res = align_degenerate(text, query, &config);
+        assert_eq!(
+            matches!(res, Err(types::AlignError::InvalidConfig(_))),
+            cfg!(not(feature = "rayon"))
+        );
+    }
 
-    let max_possible_score = max![
-        // longer * mismatch_score
-        config.penalties.mismatch as u32 * a_offset,
-        // gap_ext * longer + gap_open
-        config.penalties.gap_extend as u32 * a_offset + config.penalties.gap_open as u32
-    ] as usize;
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_thread_count() {
+        // unrelated texts so that the wavefronts grow wide enough to be split
+        let mut random = Random(3);
+        let text = random.bases(2300);
+        let query = random.bases(2200);
+
+        for distance in [types::Distance::Edit, types::Distance::GapAffine] {
+            let mut config = test_config();
+            config.distance = distance;
+            config.penalties = match distance {
+                types::Distance::Edit => types::Penalties::edit(),
+                _ => types::Penalties {
+                    mismatch: 1,
+                    matches: 0,
+                    gap_open: 0,
+                    gap_extend: 1,
+                },
+            };
+            let expected = score_degenerate(&text[..], &query, &config).unwrap();
 
-    let hi: i32 = 0;
-    let lo: i32 = 0;
+            config.thread_count = 4;
+            assert_eq!(score_degenerate(&text[..], &query, &config), Ok(expected));
+        }
+    }
 
-    // Initial conditions
-    let wf_set = types::WaveFrontSet {
-        i: None,
-        d: None,
-        m: Some(types::WaveFront::new(hi, lo)),
-    };
+    #[test]
+    fn test_adapt() {
+        // a pseudo random text with a few edits in the query
+        let text: Vec<u8> = Random(7).bases(400);
+        let mut query = text.clone();
+        query[50] = if query[50] == b'A' { b'C' } else { b'A' };
+        query.remove(200);
+        query.insert(300, b'T');
+
+        let widest = |aligner: &Aligner| -> usize {
+            (0..aligner.wavefronts.len())
+                .filter_map(|s| aligner.wavefronts.get_m_wavefront(s as i32))
+                .map(|m_wf| m_wf.len())
+                .max()
+                .unwrap()
+        };
 
-    let mut all_wavefronts = types::WaveFronts {
-        wavefront_set: vec![Some(wf_set)],
-        min_k: -(qlen as isize),
-        max_k: tlen as isize,
-        a_k,
-    };
+        let mut exact = Aligner::new(test_config());
+        let expected = exact.align_degenerate(&text[..], &query).unwrap();
 
-    // score
-    let mut score: usize = 0;
+        let mut config = test_config();
+        config.adapt = true;
+        config.adapt_params = types::AdaptParams {
+            min_wavefront_length: 5,
+            max_distance_threshold: 10,
+        };
+        let mut adaptive = Aligner::new(config);
+        let alignment = adaptive.align_degenerate(&text[..], &query).unwrap();
 
-    /*
-    // Progress bar
-    let progress_bar = ProgressBar::new(a_offset as u64);
-    let template = "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}]  {pos:>7}/{len:7}  {msg} segments ({eta_precise})";
-    let progress_style = ProgressStyle::default_bar()
-        .template(template)
-        .progress_chars("=> ");
-    progress_bar.set_style(progress_style);
-    */
-
-    // set score at start to 0
-    // unnecessary
-    // score ... diagonal
-    // all_wavefronts.wavefront_set[0].m.vals[0] = 0;
-    if *all_wavefronts
-        .get_m_wavefront(score as i32)
-        .expect(&format!(
-            "[wfa::wf_align] no m-wavefront at score {}",
-            score
-        ))
-        .get_offset(0 as i32)
-        .expect(&format!(
-            "[wfa::wf_align] no offset on a_k = {a_k} m-wavefront at score {score}"
-        ))
-        != types::Offset::from([0])
-    {
-        panic!("[wfa::wf_align] start score should be zero");
-    }
-
-    // Print config
-    if config.verbosity > 0 {
-        eprintln!(
-            "Config {{\n\
-             {0:two_spaces$}tlen: {1},\n\
-             {0:two_spaces$}qlen: {2}\n\
-             {0:two_spaces$}a_k: {3}\n\
-             {0:two_spaces$}a_offset: {4}\n\
-             }}",
-            "",
-            tlen,
-            qlen,
-            a_k,
-            a_offset,
-            two_spaces = 2
-        );
+        assert_eq!(alignment, expected);
+        assert!(widest(&adaptive) < widest(&exact));
     }
-    //let mut prev: u64 = 0;
-    loop {
-        /*
-        // update the progress bar
-        if let Some(offsets) = all_wavefronts
-            .get_m_wavefront(score as i32)
-            .and_then(|m_wf| m_wf.get_offset(a_k))
-        {
-            let m_s_k = offsets.max() as u64;
-            let delta = m_s_k - prev;
-
-            progress_bar.inc(delta);
-            prev = m_s_k;
-        };
-         */
 
-        // Extend the current wavefront
-        if all_wavefronts.get_m_wavefront(score as i32).is_some() {
-            let m_wf_mut: &mut types::WaveFront = &mut all_wavefronts.wavefront_set[score]
-                .as_mut()
-                .unwrap()
-                .m
-                .as_mut()
-                .unwrap();
+    #[test]
+    fn test_wf_score() {
+        let config = test_config();
 
-            wf_extend(m_wf_mut, match_lambda, &config, score);
+        let text = segmented("ATC{TA,GC}A");
+        for query in [&b"ATCGAA"[..], &b"ATCTCA"[..], &b"AGCA"[..]] {
+            let expected = align_degenerate(&text, query, &config).unwrap();
+            assert_eq!(
+                score_degenerate(&text, query, &config),
+                Ok(types::AlignmentScore {
+                    score: expected.score,
+                    query_end: query.len() as u32,
+                    text_end: text.len() as u32,
+                })
+            );
         }
 
-        // give up
-        if score >= max_possible_score {
-            let e = format!("Gave up. {} {}", score, max_possible_score);
-            return Err(e);
-        }
-        // Check whether we have reached the final point
-        // Get the m-wavefront with the current score
-        if utils::end_reached(
-            all_wavefronts.get_m_wavefront(score as i32),
-            a_k,
-            a_offset,
-            config,
-        ) {
-            break;
+        // only the scores the next wavefront is computed from are kept
+        let text = b"GATTACAGATTACA";
+        let query = b"GATCAGTTTACA";
+        let mut match_lambda = degenerate::match_lambda(&text[..], query);
+        let mut wavefronts = types::WaveFronts::windowed(14, 12, 4);
+        let (score, _, _) =
+            wf_compute(&mut wavefronts, 14, 12, &config, &mut match_lambda).unwrap();
+
+        assert!(score > 4);
+        assert_eq!(wavefronts.wavefront_set.len(), 4);
+        assert!(wavefronts.option_get(score - 4).is_none());
+        assert!(wavefronts.get_m_wavefront(score as i32).is_some());
+    }
+
+    #[test]
+    fn test_align_bidirectional() {
+        let text: Vec<u8> = Random(11).bases(400);
+        let mut query = text.clone();
+        query[20] = if query[20] == b'A' { b'C' } else { b'A' };
+        query.drain(150..154);
+        query.insert(320, b'G');
+
+        let expected = align_degenerate(&text[..], &query, &test_config()).unwrap();
+
+        let mut config = test_config();
+        config.memory_mode = types::MemoryMode::Ultralow;
+
+        let alignment = align_degenerate(&text[..], &query, &config).unwrap();
+        assert_eq!(alignment.score, expected.score);
+        assert_eq!(alignment.cigar.text_len(), text.len());
+        assert_eq!(alignment.cigar.query_len(), query.len());
+
+        // each alternative of a segment is aligned on its own, from the start
+        let text = segmented("ATC{TA,GC}A");
+        assert!(matches!(
+            align_degenerate(&text, b"ATCGAA", &config),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+
+        // aligning from one end can't run in O(s) memory
+        let mut match_lambda = degenerate::match_lambda(&text, b"ATCGAA");
+        let mut traceback_lambda = degenerate::traceback_lambda(&text, b"ATCGAA");
+        let res = wf_align(6, 6, &config, &mut match_lambda, &mut traceback_lambda);
+        assert!(matches!(res, Err(types::AlignError::InvalidConfig(_))));
+
+        config.adapt = true;
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_align_low_memory() {
+        let text: Vec<u8> = Random(5).bases(400);
+        let mut query = text.clone();
+        query[60] = if query[60] == b'A' { b'C' } else { b'A' };
+        query.drain(150..156);
+        query.insert(300, b'T');
+
+        let mut config = test_config();
+        config.memory_mode = types::MemoryMode::Low;
+
+        // only the scores the next wavefront is computed from are kept
+        let mut aligner = Aligner::new(config.clone());
+        for distance in [types::Distance::GapAffine, types::Distance::GapLinear] {
+            let mut config = config.clone();
+            config.distance = distance;
+            config.penalties.gap_open = if distance == types::Distance::GapLinear {
+                0
+            } else {
+                2
+            };
+
+            let mut high = config.clone();
+            high.memory_mode = types::MemoryMode::High;
+            let expected = align_degenerate(&text[..], &query, &high).unwrap();
+
+            let alignment = align_degenerate(&text[..], &query, &config).unwrap();
+            assert_eq!(alignment.score, expected.score);
+            assert_eq!(alignment.cigar.text_len(), text.len());
+            assert_eq!(alignment.cigar.query_len(), query.len());
         }
 
-        score += 1;
+        let expected = align_degenerate(&text[..], &query, &test_config()).unwrap();
+        let alignment = aligner.align_degenerate(&text[..], &query).unwrap();
+        assert_eq!(alignment.score, expected.score);
+        assert_eq!(aligner.wavefronts.wavefront_set.len(), 4);
+
+        // aligning each alternative on its own keeps every wavefront
+        let text = segmented("ATC{TA,GC}A");
+        assert!(matches!(
+            aligner.align_degenerate(&text, b"ATCGAA"),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+
+        let text = b"GGGGACGTACGGGG";
+        config.span = types::AlignmentSpan::EndsFree {
+            text_begin_free: text.len() as u32,
+            text_end_free: text.len() as u32,
+            query_begin_free: 0,
+            query_end_free: 0,
+        };
+        let alignment = align_degenerate(&text[..], b"ACGTAC", &config).unwrap();
+        assert_eq!(
+            (0, "4I6M4I"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-        // TODO: compute the next wavefront
-        core::wf_next(&mut all_wavefronts, score, config);
+        // the best cell of an extension and the ends of a search can be
+        // out of the window
+        config.span = types::AlignmentSpan::EndToEnd;
+        let res = search_degenerate(&text[..], b"ACGTAC", &config, 2);
+        assert!(matches!(res, Err(types::AlignError::InvalidConfig(_))));
+
+        config.penalties.matches = 1;
+        config.span = types::AlignmentSpan::Extension {
+            drop: types::ScoreDrop::XDrop(10),
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
     }
 
-    let cigar = wf_traceback(&all_wavefronts, score, config, traceback_lambda);
+    #[test]
+    fn test_align_ends_free() {
+        let text = b"GGGGACGTACGGGG";
+        let query = b"ACGTAC";
+
+        let mut config = test_config();
+        config.span = types::AlignmentSpan::EndsFree {
+            text_begin_free: text.len() as u32,
+            text_end_free: text.len() as u32,
+            query_begin_free: 0,
+            query_end_free: 0,
+        };
 
-    Ok((score, cigar))
-}
+        let alignment = align_degenerate(&text[..], query, &config).unwrap();
+        assert_eq!(
+            (0, "4I6M4I"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-#[cfg(test)]
-mod tests {
+        let score = score_degenerate(&text[..], query, &config).unwrap();
+        assert_eq!((score.score, score.text_end, score.query_end), (0, 10, 6));
 
-    use super::*;
+        // only the text prefix is free, the suffix is a gap
+        config.span = types::AlignmentSpan::EndsFree {
+            text_begin_free: 4,
+            text_end_free: 0,
+            query_begin_free: 0,
+            query_end_free: 0,
+        };
+        let alignment = align_degenerate(&text[..], query, &config).unwrap();
+        assert_eq!(
+            (6, "4I6M4I"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-    fn test_config() -> types::Config {
-        types::Config {
-            adapt: false,
-            verbosity: 1,
-            penalties: types::Penalties {
-                mismatch: 1,
-                matches: 0,
-                gap_open: 2,
-                gap_extend: 1,
-            },
-        }
+        // a free query prefix
+        config.span = types::AlignmentSpan::EndsFree {
+            text_begin_free: 0,
+            text_end_free: 0,
+            query_begin_free: 3,
+            query_end_free: 0,
+        };
+        let alignment = align_degenerate(&b"ACGT"[..], b"TTTACGT", &config).unwrap();
+        assert_eq!(
+            (0, "3D4M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+
+        config.memory_mode = types::MemoryMode::Ultralow;
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+
+        config.memory_mode = types::MemoryMode::High;
+        let text: ElasticText = "ACT{A,ATTG,}CA".parse().unwrap();
+        assert!(matches!(
+            align_elastic(&text, b"ACTCA", &config),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
     }
 
     #[test]
-    fn test_matches() {
-        let query = "ATCGAA".as_bytes();
-        let ed_string = "ATC{TA,GA}A";
+    fn test_align_linear() {
+        let mut config = test_config();
+        config.distance = types::Distance::Edit;
+        config.penalties = types::Penalties::edit();
+
+        let alignment = align_degenerate(&b"ACGTCA"[..], b"ACTCA", &config).unwrap();
+        assert_eq!(
+            (1, "2M1I3M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-        let config = test_config();
-        let edt = eds::EDT::from_str(ed_string);
-        let dt: eds::DT = edt.extract_inelastic();
+        let text = segmented("ATC{TA,GC}A");
+        let alignment = align_degenerate(&text, b"ATCGAA", &config).unwrap();
+        assert_eq!(
+            (1, "3M1X2M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-        let verbosity = config.verbosity;
+        // a mismatch costs more than a gap in and out
+        config.distance = types::Distance::GapLinear;
+        config.penalties.mismatch = 3;
+        let alignment = align_degenerate(&b"ACGTCA"[..], b"ACCTCA", &config).unwrap();
+        assert_eq!(
+            (2, "2M1I1D3M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-        let tlen = dt.p();
-        let qlen = query.len();
+        config.penalties.gap_open = 1;
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+
+        config.distance = types::Distance::Edit;
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+
+        config.penalties = types::Penalties::edit();
+        config.memory_mode = types::MemoryMode::Ultralow;
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+    }
 
-        let mut match_lambda = |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| -> bool {
-            if verbosity > 1 {
-                eprint!("v ({}, {}) ", h, v);
-            }
+    #[test]
+    fn test_align_match_bonus() {
+        let mut config = test_config();
+        config.penalties.matches = 1;
+
+        // 7 matches and a mismatch
+        let alignment = align_degenerate(&b"ACGTACGT"[..], b"ACGTTCGT", &config).unwrap();
+        assert_eq!(
+            (6, "4M1X3M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+        assert_eq!(
+            6,
+            score_degenerate(&b"ACGTACGT"[..], b"ACGTTCGT", &config)
+                .unwrap()
+                .score
+        );
 
-            if *v < 0 || *h < 0 || *h as usize >= tlen || *v as usize >= qlen {
-                return false;
-            }
+        config.memory_mode = types::MemoryMode::Ultralow;
+        let alignment = align_degenerate(&b"ACGTACGT"[..], b"ACGTTCGT", &config).unwrap();
+        assert_eq!(6, alignment.score);
 
-            let text_chars: &Vec<u8> = &dt[*h as usize];
-            let query_char: u8 = query[*v as usize];
-            let z = text_chars.len();
+        // more mismatches than matches
+        config.memory_mode = types::MemoryMode::High;
+        let alignment = align_degenerate(&b"AAAAC"[..], b"TTTTC", &config).unwrap();
+        assert_eq!(
+            (-3, "4X1M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-            let l: usize = offsets.data.len();
+        assert!(matches!(
+            search_degenerate(&b"ACGTACGT"[..], b"GTA", &config, 2),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
 
-            if z > l {
-                // copy over
-                let prev: i32 = offsets.max();
-                *offsets = types::Offset::from_vec(&vec![prev; z]);
-            }
+        config.span = types::AlignmentSpan::EndsFree {
+            text_begin_free: 4,
+            text_end_free: 4,
+            query_begin_free: 0,
+            query_end_free: 0,
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+
+        config.span = types::AlignmentSpan::EndToEnd;
+        config.penalties.matches = -1;
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+    }
 
-            if z < l {
-                let furthest: i32 = offsets.max();
-                *offsets = types::Offset::from_vec(&vec![furthest; z]);
-            }
+    #[test]
+    fn test_align_two_piece() {
+        let text = b"AAAACCCCCCCCCCGGGG";
+        let query = b"AAAAGGGG";
 
-            //let r = row_chars.iter().map(|c| *c as char).collect::<Vec<char>>();
+        let mut config = test_config();
+        config.penalties.gap_extend = 2;
 
-            if verbosity > 2 {
-                // eprint!("offsets {:?} q {} row {:?}", offsets, col_char as char, r);
-            }
+        let alignment = align_degenerate(&text[..], query, &config).unwrap();
+        assert_eq!(
+            (22, "4M10I4M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+
+        // the long gap is cheaper on the second piece
+        config.distance = types::Distance::GapAffine2p {
+            gap_open2: 8,
+            gap_extend2: 1,
+        };
+        let alignment = align_degenerate(&text[..], query, &config).unwrap();
+        assert_eq!(
+            (18, "4M10I4M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+        assert_eq!(
+            18,
+            score_degenerate(&text[..], query, &config).unwrap().score
+        );
 
-            let mut found = false;
-            let mut increment_once = false;
+        // and a short one on the first
+        let alignment = align_degenerate(&b"AAAACGGGG"[..], query, &config).unwrap();
+        assert_eq!(
+            (4, "4M1I4M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-            for (idx, offset) in offsets.data.iter_mut().enumerate() {
-                if text_chars[idx] == query_char {
-                    *offset += 1;
-                    found = true;
+        config.distance = types::Distance::GapAffine2p {
+            gap_open2: 8,
+            gap_extend2: 0,
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+    }
 
-                    if increment_once == false {
-                        *v += 1;
-                        *h += 1;
+    #[test]
+    fn test_align_extension() {
+        let text = b"ACGTACGTGGGGGGGG";
+        let query = b"ACGTACGTCCCCCC";
+
+        let mut config = test_config();
+        config.penalties.matches = 1;
+        config.span = types::AlignmentSpan::Extension {
+            drop: types::ScoreDrop::XDrop(3),
+        };
 
-                        increment_once = true;
-                    }
-                }
-            }
+        // the mismatches after the matches don't pay off
+        let alignment = align_degenerate(&text[..], query, &config).unwrap();
+        assert_eq!(
+            (8, "8M6S"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-            if verbosity > 2 {
-                // eprint!("found={} {:?}", found, offsets);
-                eprintln!();
-            }
+        let score = score_degenerate(&text[..], query, &config).unwrap();
+        assert_eq!((score.score, score.text_end, score.query_end), (8, 8, 8));
 
-            found
+        // a gap is worth it when the matches after it make up for it
+        config.span = types::AlignmentSpan::Extension {
+            drop: types::ScoreDrop::ZDrop(3),
         };
+        let alignment =
+            align_degenerate(&b"ACGTAACGTACGT"[..], b"ACGTACGTACGTCC", &config).unwrap();
+        assert_eq!(
+            (9, "5M1I7M2S"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
 
-        let mut traceback_lambda = |_query @ (_q_start, _q_stop): (i32, i32),
-                                    _text @ (_t_start, _t_stop): (i32, i32)|
-         -> bool { true };
+        config.penalties.matches = 0;
+        assert!(matches!(
+            align_degenerate(&text[..], query, &config),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+    }
 
-        let (score, cigar) = wf_align(
-            tlen as u32,
-            qlen as u32,
-            &config,
-            &mut match_lambda,
-            &mut traceback_lambda,
-        )
-        .expect("[wfa::dwflambda::align::tests::test_matches] Alignment failed");
+    #[test]
+    fn test_search_degenerate() {
+        // edit distance
+        let mut config = test_config();
+        config.penalties.gap_open = 0;
+
+        let search = |text: &degenerate::SegmentedText, query: &str, max_score: usize| {
+            search_degenerate(text, query.as_bytes(), &config, max_score)
+                .unwrap()
+                .into_iter()
+                .map(|o| (o.text_start, o.text_end, o.score, o.cigar.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let text = segmented("TTACGTTTTACCTTT");
+        assert_eq!(
+            search(&text, "ACGT", 1),
+            vec![
+                (2, 6, 0, "4M".to_string()),
+                (9, 13, 1, "2M1X1M".to_string())
+            ]
+        );
+        assert!(search(&text, "GGGG", 2).is_empty());
+
+        let text = segmented("TT{A,C}CGTTACG");
+        let occurrences = search_degenerate(&text, b"ACGT", &config, 1).unwrap();
+        assert_eq!((occurrences[0].text_start, occurrences[0].score), (2, 0));
+        assert_eq!(
+            occurrences[0].segments,
+            vec![types::SegmentAlternatives {
+                segment: 1,
+                alternatives: vec![0]
+            }]
+        );
+        // the second occurrence doesn't overlap the degenerate segment
+        assert_eq!((occurrences[1].text_end, occurrences[1].score), (10, 1));
+        assert!(occurrences[1].segments.is_empty());
+
+        config.adapt = true;
+        assert!(matches!(
+            search_degenerate(&text, b"ACGT", &config, 1),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+
+        // the occurrence opens a gap inside the second alternative, where a path
+        // through the segment after it got further on the same diagonal
+        config.adapt = false;
+        config.distance = types::Distance::GapAffine2p {
+            gap_open2: 12,
+            gap_extend2: 1,
+        };
+        config.penalties = types::Penalties {
+            mismatch: 4,
+            matches: 0,
+            gap_open: 4,
+            gap_extend: 2,
+        };
+        let text = segmented("AG{TG,GA}CAA");
+        let occurrences = search_degenerate(&text, b"TGGCA", &config, 10).unwrap();
+        let occurrence = occurrences.iter().find(|o| o.score == 10).unwrap();
+        assert_eq!(occurrence.cigar.to_string(), "1X2M1D1M");
+        assert_eq!(occurrence.segments[0].alternatives, vec![1]);
+    }
 
-        assert_eq!("MMMMMM", cigar);
-        assert_eq!(0, score);
+    fn segmented(ed_string: &str) -> degenerate::SegmentedText {
+        let text: ElasticText = ed_string.parse().unwrap();
+        degenerate::SegmentedText::try_from(&text).unwrap()
     }
 
-    // also tests replacement
     #[test]
-    fn test_artifact_match() {
-        let query = "ATCGAA".as_bytes();
-        let ed_string = "ATC{TA,GC}A";
-
+    fn test_align_degenerate() {
         let config = test_config();
-        let edt = eds::EDT::from_str(ed_string);
-        let dt: eds::DT = edt.extract_inelastic();
 
-        let verbosity = config.verbosity;
+        let text = segmented("ATC{TA,GA}A");
+        let alignment = align_degenerate(&text, b"ATCGAA", &config).unwrap();
+        assert_eq!(
+            (0, "6M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+        assert_eq!(
+            alignment.segments,
+            vec![types::SegmentAlternatives {
+                segment: 1,
+                alternatives: vec![1]
+            }]
+        );
 
-        let tlen = dt.p();
-        let qlen = query.len();
+        let text = segmented("ATC{TA,GC}A");
+        let alignment = align_degenerate(&text, b"ATCGAA", &config).unwrap();
+        assert_eq!(
+            (1, "3M1X2M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+        // either alternative mismatches once, the cigar spells the first
+        assert_eq!(alignment.segments[0].alternatives, vec![0]);
+
+        // plain texts are degenerate texts with one alternative
+        let alignment = align_degenerate(&b"ACGTCA"[..], b"ACTCA", &config).unwrap();
+        assert_eq!(
+            (3, "2M1I3M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+        assert!(alignment.segments.is_empty());
 
-        let mut match_lambda = |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| -> bool {
-            if verbosity > 4 {
-                eprint!("v ({}, {})\n", h, v);
-            }
+        let mut aligner = Aligner::new(test_config());
+        assert_eq!(
+            aligner.align_degenerate(&text, b"ATCGAA"),
+            align_degenerate(&text, b"ATCGAA", &config)
+        );
+    }
 
-            if *v < 0 || *h < 0 || *h as usize >= tlen || *v as usize >= qlen {
-                return false;
-            }
+    #[test]
+    fn test_adjacent_segments() {
+        let config = test_config();
 
-            let text_chars: &Vec<u8> = &dt[*h as usize];
-            let query_char: u8 = query[*v as usize];
-            let z = text_chars.len();
+        // {A,G}{C,T}, A of the first segment then T of the second
+        let text: Vec<Vec<u8>> = vec![b"AG".to_vec(), b"CT".to_vec()];
+        let alignment = align_degenerate(&text, b"AT", &config).unwrap();
+        assert_eq!(
+            (0, "2M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+        assert_eq!(
+            alignment.segments,
+            vec![
+                types::SegmentAlternatives {
+                    segment: 0,
+                    alternatives: vec![0]
+                },
+                types::SegmentAlternatives {
+                    segment: 1,
+                    alternatives: vec![1]
+                }
+            ]
+        );
 
-            let l: usize = offsets.offset_count();
+        // the same when the text keeps its segments
+        let text = segmented("{A,G}{C,T}");
+        let alignment = align_degenerate(&text, b"AT", &config).unwrap();
+        assert_eq!((alignment.score, alignment.segments.len()), (0, 2));
+
+        // TAA of the first segment then CG of the second
+        let text = segmented("{TAA,GTA}{AT,CG}");
+        let alignment = align_degenerate(&text, b"TAACG", &config).unwrap();
+        assert_eq!(
+            (0, "5M"),
+            (alignment.score, alignment.cigar.to_string().as_str())
+        );
+    }
 
-            if z > l {
-                // copy over
-                let prev: i32 = offsets.max();
-                *offsets = types::Offset::from_vec(&vec![prev; z]);
+    #[test]
+    fn test_degenerate_alternatives() {
+        let mut config = test_config();
+        config.penalties = types::Penalties {
+            mismatch: 4,
+            matches: 0,
+            gap_open: 6,
+            gap_extend: 2,
+        };
 
-                if z > 1 {
-                    offsets.abandoned_all_null();
-                }
+        // the score of the best path through the segments, aligned one at a time
+        let brute_force = |segments: &[Vec<Vec<u8>>], query: &[u8], config: &types::Config| {
+            let mut paths: Vec<Vec<u8>> = vec![Vec::new()];
+            for alts in segments {
+                paths = paths
+                    .iter()
+                    .flat_map(|path| alts.iter().map(move |alt| [&path[..], alt].concat()))
+                    .collect();
             }
 
-            if z < l {
-                let furthest: i32 = offsets.max();
-                *offsets = types::Offset::from_vec(&vec![furthest; z]);
+            paths
+                .iter()
+                .map(|path| align_degenerate(&path[..], query, config).unwrap().score)
+                .min()
+                .unwrap()
+        };
 
-                if z == 1 {
-                    offsets.abdandoned = None;
+        // the cigar spells the first alternative reported for each segment
+        let check = |segments: &[Vec<Vec<u8>>], query: &[u8], config: &types::Config| {
+            let text = degenerate::SegmentedText::new(segments).unwrap();
+            let alignment = align_degenerate(&text, query, config).unwrap();
+            let case = format!("{segments:?} {query:?} {}", alignment.cigar);
+            assert_eq!(
+                alignment.score,
+                brute_force(segments, query, config),
+                "{case}"
+            );
+
+            let path: Vec<u8> = segments
+                .iter()
+                .enumerate()
+                .flat_map(|(index, alts)| {
+                    let chosen = alignment.segments.iter().find(|s| s.segment == index);
+                    assert_eq!(chosen.is_some(), alts.len() > 1, "{case}");
+                    alts[chosen.map_or(0, |s| s.alternatives[0])].clone()
+                })
+                .collect();
+
+            let (mut v, mut h) = (0, 0);
+            for op in alignment.cigar.ops() {
+                match op {
+                    CigarOp::Match => assert_eq!(path[h], query[v], "{case}"),
+                    CigarOp::Mismatch => assert_ne!(path[h], query[v], "{case}"),
+                    _ => {}
                 }
+                h += op.consumes_text() as usize;
+                v += op.consumes_query() as usize;
             }
+            assert_eq!((v, h), (query.len(), path.len()), "{case}");
 
-            if verbosity > 2 {
-                // eprint!("offsets {:?} q {} row {:?}", offsets, col_char as char, r);
-            }
+            alignment
+        };
 
-            let mut found = false;
-            let mut increment_once = false;
+        let parse = |text: &str| -> Vec<Vec<Vec<u8>>> {
+            text.parse::<ElasticText>().unwrap().segments().to_vec()
+        };
 
-            for idx in 0..z {
-                if text_chars[idx] == query_char && !offsets.is_abandoned(idx) {
-                    offsets.data[idx] += 1;
+        // a path that changed alternatives within a segment scored 4 and 8
+        let alignment = check(&parse("A{GGA,CGC}TG"), b"AGACTG", &config);
+        assert_eq!(alignment.score, 8);
+        let alignment = check(&parse("A{GATAC,ATCTG,TTTCC}"), b"AATCC", &config);
+        assert_eq!(alignment.score, 12);
+
+        // and spelled 2M1I2M, matching C against the second alternative
+        let alignment = check(&parse("A{GCTA,CGGC}"), b"ACTA", &config);
+        assert_eq!(
+            (alignment.score, alignment.cigar.to_string().as_str()),
+            (8, "1M1I3M")
+        );
+        assert_eq!(alignment.segments[0].alternatives, vec![0]);
 
-                    found = true;
+        let mut random = Random(7);
 
-                    if increment_once == false {
-                        *v += 1;
-                        *h += 1;
+        for (mismatch, gap_open, gap_extend) in [(4, 6, 2), (1, 2, 1)] {
+            config.penalties = types::Penalties {
+                mismatch,
+                matches: 0,
+                gap_open,
+                gap_extend,
+            };
 
-                        increment_once = true;
-                    }
+            for _ in 0..500 {
+                let mut segments: Vec<Vec<Vec<u8>>> = Vec::new();
+                for _ in 0..1 + random.below(4) {
+                    let len: usize = 1 + random.below(4);
+                    let count: usize = 1 + random.below(3);
+                    segments.push((0..count).map(|_| random.bases(len)).collect());
                 }
+                let query_len: usize = 1 + random.below(8);
+                let query: Vec<u8> = random.bases(query_len);
 
-                if text_chars[idx] != query_char {
-                    offsets.set_abandon(idx);
-                }
+                check(&segments, &query, &config);
             }
+        }
+    }
 
-            if verbosity > 4 {
-                eprint!("\tfound={} {:?}", found, offsets);
-                eprintln!();
-            }
+    #[test]
+    fn test_align_plain() {
+        let mut config = test_config();
+
+        // scores wf_next got too low when it computed a kernel without
+        // both gap wavefronts or took the first offset of a diagonal
+        let cases = [
+            ("AGTAGAAA", "GTGT", (2, 3, 1), 13),
+            ("TCGCCCT", "CATGTTCT", (2, 3, 1), 12),
+            ("TGCGGGCCC", "AAGCG", (4, 6, 2), 28),
+            ("CTATCAAGCA", "AGTTTA", (4, 6, 2), 30),
+        ];
+
+        for (text, query, (mismatch, gap_open, gap_extend), expected) in cases {
+            config.penalties = types::Penalties {
+                mismatch,
+                matches: 0,
+                gap_open,
+                gap_extend,
+            };
+
+            let types::Alignment { score, cigar, .. } =
+                align_degenerate(text.as_bytes(), query.as_bytes(), &config)
+                    .unwrap_or_else(|err| panic!("{text} {query}: {err}"));
+            assert_eq!(score, expected, "{text} {query} {cigar}");
+            assert_eq!(
+                (cigar.text_len(), cigar.query_len()),
+                (text.len(), query.len())
+            );
+        }
+    }
 
-            found
+    #[test]
+    fn test_align_elastic() {
+        let config = test_config();
+        let align = |text: &str, query: &str| -> (i32, String) {
+            let text: ElasticText = text.parse().unwrap();
+            let alignment = align_elastic(&text, query.as_bytes(), &config).unwrap();
+            (alignment.score, alignment.cigar.to_string())
         };
 
-        let mut traceback_lambda =
-            |q @ (q_start, q_stop): (i32, i32), t @ (t_start, t_stop): (i32, i32)| -> bool {
-                if q_start < 0 || q_stop < 0 || t_start < 0 || t_stop < 0 {
-                    return false;
-                }
+        // each alternative, including the empty one
+        assert_eq!(align("ACT{A,ATTG,}CA", "ACTACA"), (0, "6M".to_string()));
+        assert_eq!(align("ACT{A,ATTG,}CA", "ACTATTGCA"), (0, "9M".to_string()));
+        assert_eq!(align("ACT{A,ATTG,}CA", "ACTCA"), (0, "5M".to_string()));
 
-                let res = (q_start as usize..q_stop as usize)
-                    .zip(t_start as usize..t_stop as usize)
-                    .fold(true, |acc, (q_index, t_index)| {
-                        dt[t_index]
-                            .iter()
-                            .copied()
-                            .any(|t_char| t_char == query[q_index])
-                            && acc
-                    });
-
-                // eprintln!("{:?} {:?} {}", q, t, res);
-                res
-            };
+        assert_eq!(
+            align("ACT{A,ATTG,}CA", "ACTATGCA"),
+            (3, "5M1I3M".to_string())
+        );
+        assert_eq!(align("A{C,}T", "AGT"), (1, "1M1X1M".to_string()));
 
-        let (score, cigar) = wf_align(
-            tlen as u32,
-            qlen as u32,
-            &config,
-            &mut match_lambda,
-            &mut traceback_lambda,
-        )
-        .expect("[wfa::dwflambda::align::tests::test_artifact_match] Alignment failed");
+        // the gap spans two segments but is only opened once
+        assert_eq!(align("AC{G,T}{A,C}GT", "ACGT"), (4, "3M2I1M".to_string()));
 
-        assert_eq!("MMMMXM", cigar);
-        assert_eq!(1, score);
+        // plain texts give the same alignment as align_degenerate
+        assert_eq!(align("ACGTCA", "ACTCA"), (3, "2M1I3M".to_string()));
+        assert_eq!(align("", "AC"), (4, "2D".to_string()));
     }
 
     #[test]
-    fn test_snps() {
+    fn test_elastic_alternatives() {
+        let config = test_config();
+        let alternatives = |text: &str, query: &str| -> Vec<(usize, Vec<usize>)> {
+            let text: ElasticText = text.parse().unwrap();
+            align_elastic(&text, query.as_bytes(), &config)
+                .unwrap()
+                .segments
+                .into_iter()
+                .map(|s| (s.segment, s.alternatives))
+                .collect()
+        };
+
+        assert_eq!(
+            alternatives("TAGGC{TGG,ACT}AT{C,G}C", "TAGGCACTATGC"),
+            vec![(1, vec![1]), (3, vec![1])]
+        );
+        assert_eq!(alternatives("ACT{A,ATTG,}CA", "ACTCA"), vec![(1, vec![2])]);
+
+        // a mismatch against either alternative is a tie
+        assert_eq!(alternatives("AC{G,T}A", "ACCA"), vec![(1, vec![0, 1])]);
+    }
+
+    #[cfg(feature = "eds")]
+    #[test]
+    fn test_align_edt() {
+        let config = test_config();
         let query = "TGGGCACTATCCCTTGTACGTTCGGAGTTTCATATTGTGTATCAAATATATTTATTAG\
                      CTCTTTTGAGCCTGACGAGCTGGGTAG";
-        let query = query.as_bytes();
         let ed_string = "TAGGC{TGG,ACT}ATCCCTT{TAA,GTA}{AT,CG}TTCTCA{C,G}TTTC\
                          CA{TGG,ATT}{C,G}TGAATCAAATGTATTTAT{TCGG,TAGG}CT{A,C}TT\
                          TTGAGC{AG,CT}GACTA{GTT,GCT}AGTTAG";
-
-        let config = test_config();
         let edt = eds::EDT::from_str(ed_string);
         let dt: eds::DT = edt.extract_inelastic();
 
-        let verbosity = config.verbosity;
-
-        let tlen = dt.p();
-        let qlen = query.len();
-
-        let mut match_lambda = |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| -> bool {
-            if verbosity > 4 {
-                eprint!("v ({}, {})\n", h, v);
-            }
-
-            if *v < 0 || *h < 0 || *h as usize >= tlen || *v as usize >= qlen {
-                return false;
-            }
-
-            let text_chars: &Vec<u8> = &dt[*h as usize];
-            let query_char: u8 = query[*v as usize];
-            let z = text_chars.len();
-
-            let l: usize = offsets.offset_count();
-
-            if z > l {
-                // copy over
-                let prev: i32 = offsets.max();
-                *offsets = types::Offset::from_vec(&vec![prev; z]);
-
-                if z > 1 {
-                    offsets.abandoned_all_null();
-                }
-            }
-
-            if z < l {
-                let furthest: i32 = offsets.max();
-                *offsets = types::Offset::from_vec(&vec![furthest; z]);
-
-                if z == 1 {
-                    offsets.abdandoned = None;
-                }
-            }
-
-            if verbosity > 2 {
-                // eprint!("offsets {:?} q {} row {:?}", offsets, col_char as char, r);
-            }
-
-            let mut found = false;
-            let mut increment_once = false;
-
-            for idx in 0..z {
-                if text_chars[idx] == query_char && !offsets.is_abandoned(idx) {
-                    offsets.data[idx] += 1;
-
-                    found = true;
-
-                    if increment_once == false {
-                        *v += 1;
-                        *h += 1;
-
-                        increment_once = true;
-                    }
-                }
-
-                if text_chars[idx] != query_char {
-                    offsets.set_abandon(idx);
-                }
-            }
+        let text: ElasticText = ed_string.parse().unwrap();
+        let expected = align_elastic(&text, query.as_bytes(), &config);
+        assert_eq!(align_edt(&edt, query.as_bytes(), &config), expected);
 
-            if verbosity > 4 {
-                eprint!("\tfound={} {:?}", found, offsets);
-                eprintln!();
-            }
+        // the same strings as the inelastic view, numbered by the segments of the text
+        let alignment = align_degenerate(&dt, query.as_bytes(), &config).unwrap();
+        assert_eq!(alignment.score, expected.as_ref().unwrap().score);
 
-            found
-        };
+        let mut aligner = Aligner::new(test_config());
+        assert_eq!(aligner.align_edt(&edt, query.as_bytes()), expected);
 
-        let mut traceback_lambda =
-            |q @ (q_start, q_stop): (i32, i32), t @ (t_start, t_stop): (i32, i32)| -> bool {
-                if q_start < 0 || q_stop < 0 || t_start < 0 || t_stop < 0 {
-                    return false;
+        // TAA of one segment then CG of the next, the inelastic view keeps
+        // the two segments apart
+        let edt = eds::EDT::from_str("{TAA,GTA}{AT,CG}");
+        let dt: eds::DT = edt.extract_inelastic();
+        assert_eq!(align_edt(&edt, b"TAACG", &config).unwrap().score, 0);
+        let alignment = align_degenerate(&dt, b"TAACG", &config).unwrap();
+        assert_eq!(
+            (alignment.score, alignment.cigar.to_string().as_str()),
+            (0, "5M")
+        );
+        assert_eq!(
+            alignment.segments,
+            vec![
+                types::SegmentAlternatives {
+                    segment: 0,
+                    alternatives: vec![0]
+                },
+                types::SegmentAlternatives {
+                    segment: 1,
+                    alternatives: vec![1]
                 }
+            ]
+        );
 
-                let res = (q_start as usize..q_stop as usize)
-                    .zip(t_start as usize..t_stop as usize)
-                    .fold(true, |acc, (q_index, t_index)| {
-                        dt[t_index]
-                            .iter()
-                            .copied()
-                            .any(|t_char| t_char == query[q_index])
-                            && acc
-                    });
-
-                // eprintln!("{:?} {:?} {}", q, t, res);
-                res
-            };
-
-        let (score, cigar) = wf_align(
-            tlen as u32,
-            qlen as u32,
-            &config,
-            &mut match_lambda,
-            &mut traceback_lambda,
-        )
-        .expect("[wfa::dwflambda::align::tests::test_artifact_match] Alignment failed");
-
-        // assert_eq!("MMMMXM", cigar);
-        // assert_eq!(1, score);
+        // alternatives of different lengths, including an empty one
+        let edt = eds::EDT::from_str("ACT{A,ATTG,}CA");
+        let alignment = align_edt(&edt, b"ACTATGCA", &config).unwrap();
+        assert_eq!(
+            (alignment.score, alignment.cigar.to_string().as_str()),
+            (3, "5M1I3M")
+        );
+        assert_eq!(alignment.segments[0].alternatives, vec![1]);
+        assert_eq!(align_edt(&edt, b"ACTCA", &config).unwrap().score, 0);
+
+        let mut config = test_config();
+        config.penalties.gap_open = 0;
+        let occurrences = search_edt(&edt, b"TCA", &config, 0).unwrap();
+        let found: Vec<_> = occurrences
+            .iter()
+            .map(|o| (o.text_start, o.text_end, o.segments.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(
+                2,
+                5,
+                vec![types::SegmentAlternatives {
+                    segment: 1,
+                    alternatives: vec![2]
+                }]
+            )]
+        );
     }
 }
@@ -0,0 +1,212 @@
+/*!
+Align many queries against one text on the threads of the current rayon pool
+
+Each thread of the pool aligns with its own [Aligner] so that wavefront
+memory is reused between the queries it gets. Results come out in the
+order the queries went in.
+ */
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use rayon::prelude::*;
+
+use super::align::Aligner;
+use super::degenerate::DegenerateText;
+use super::types;
+
+/// Queries taken from the iterator at a time for each thread of the pool
+const QUERIES_PER_THREAD: usize = 64;
+
+struct Batch<I: Iterator, A> {
+    queries: I,
+    align_one: A,
+    /// One aligner per thread of the pool
+    aligners: Vec<Mutex<Aligner>>,
+    /// Alignments of the queries taken so far that haven't been yielded
+    results: VecDeque<Result<types::Alignment, types::AlignError>>,
+}
+
+impl<I, A> Batch<I, A>
+where
+    I: Iterator,
+{
+    fn new(queries: I, config: &types::Config, align_one: A) -> Self {
+        let aligners: Vec<Mutex<Aligner>> = (0..rayon::current_num_threads())
+            .map(|_| Mutex::new(Aligner::new(config.clone())))
+            .collect();
+
+        Self {
+            queries,
+            align_one,
+            aligners,
+            results: VecDeque::new(),
+        }
+    }
+}
+
+impl<I, A> Iterator for Batch<I, A>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]> + Send,
+    A: Fn(&mut Aligner, &[u8]) -> Result<types::Alignment, types::AlignError> + Sync,
+{
+    type Item = Result<types::Alignment, types::AlignError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.results.is_empty() {
+            let queries: Vec<I::Item> = self
+                .queries
+                .by_ref()
+                .take(self.aligners.len() * QUERIES_PER_THREAD)
+                .collect();
+
+            let aligners: &[Mutex<Aligner>] = &self.aligners;
+            let align_one = &self.align_one;
+
+            // an indexed collect keeps the input order
+            let results: Vec<Self::Item> = queries
+                .into_par_iter()
+                .map(|query| {
+                    let thread: usize = rayon::current_thread_index().unwrap_or(0);
+                    let mut aligner = aligners[thread % aligners.len()].lock().unwrap();
+                    align_one(&mut aligner, query.as_ref())
+                })
+                .collect();
+
+            self.results.extend(results);
+        }
+
+        self.results.pop_front()
+    }
+}
+
+/// Same as [crate::align::wf_align] for each query against a text of
+/// length tlen, yields the alignments in the order of the queries
+///
+/// The lambdas take the query as their first argument so that every thread
+/// can share them, the rest is as in [crate::align::wf_align].
+pub fn wf_align_batch<'a, Q, F, G>(
+    tlen: u32,
+    queries: impl IntoIterator<Item = Q> + 'a,
+    config: &types::Config,
+    match_lambda: &'a F,
+    traceback_lambda: &'a G,
+) -> impl Iterator<Item = Result<types::Alignment, types::AlignError>> + 'a
+where
+    Q: AsRef<[u8]> + Send + 'a,
+    F: Fn(&[u8], &mut i32, &mut i32, &mut types::Offset) -> bool + Sync,
+    G: Fn(&[u8], (i32, i32), (i32, i32)) -> bool + Sync,
+{
+    Batch::new(
+        queries.into_iter(),
+        config,
+        move |aligner: &mut Aligner, query: &[u8]| {
+            aligner.align(
+                tlen,
+                query.len() as u32,
+                &mut |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| {
+                    match_lambda(query, v, h, offsets)
+                },
+                &mut |q: (i32, i32), t: (i32, i32)| traceback_lambda(query, q, t),
+            )
+        },
+    )
+}
+
+/// Same as [crate::align::align_degenerate] for each query, yields the
+/// alignments in the order of the queries
+pub fn align_degenerate_batch<'a, T, Q>(
+    text: &'a T,
+    queries: impl IntoIterator<Item = Q> + 'a,
+    config: &types::Config,
+) -> impl Iterator<Item = Result<types::Alignment, types::AlignError>> + 'a
+where
+    T: DegenerateText + Sync + ?Sized,
+    Q: AsRef<[u8]> + Send + 'a,
+{
+    Batch::new(
+        queries.into_iter(),
+        config,
+        move |aligner: &mut Aligner, query: &[u8]| aligner.align_degenerate(text, query),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::align;
+    use crate::degenerate::{self, SegmentedText};
+    use crate::elastic::ElasticText;
+
+    fn test_config() -> types::Config {
+        types::Config::default()
+    }
+
+    #[test]
+    fn test_align_batch() {
+        let config = test_config();
+
+        let text: ElasticText = "ATC{TA,GC}A".parse().unwrap();
+        let text = SegmentedText::try_from(&text).unwrap();
+
+        // more queries than one pass over the pool takes
+        let queries: Vec<Vec<u8>> = (0..rayon::current_num_threads() * QUERIES_PER_THREAD + 10)
+            .map(|i| match i % 4 {
+                0 => b"ATCTAA".to_vec(),
+                1 => b"ATCGCA".to_vec(),
+                2 => b"AGCA".to_vec(),
+                _ => b"TTCGGAA".to_vec(),
+            })
+            .collect();
+
+        let expected: Vec<types::Alignment> = queries
+            .iter()
+            .map(|query| align::align_degenerate(&text, query, &config).unwrap())
+            .collect();
+
+        let alignments: Vec<types::Alignment> = align_degenerate_batch(&text, &queries, &config)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(alignments, expected);
+
+        // the same through shared lambdas, on a text without alternatives
+        let text: &[u8] = b"ATCGCA";
+        let expected: Vec<types::Alignment> = queries
+            .iter()
+            .map(|query| align::align_degenerate(text, query, &config).unwrap())
+            .collect();
+
+        let match_lambda = |query: &[u8], v: &mut i32, h: &mut i32, offsets: &mut types::Offset| {
+            degenerate::match_lambda(text, query)(v, h, offsets)
+        };
+        let traceback_lambda = |query: &[u8], q: (i32, i32), t: (i32, i32)| {
+            degenerate::traceback_lambda(text, query)(q, t)
+        };
+        let alignments: Vec<types::Alignment> = wf_align_batch(
+            text.len() as u32,
+            &queries,
+            &config,
+            &match_lambda,
+            &traceback_lambda,
+        )
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(alignments, expected);
+
+        // errors are yielded in place
+        let mut config = test_config();
+        config.memory_mode = types::MemoryMode::Ultralow;
+        let results: Vec<_> = wf_align_batch(
+            text.len() as u32,
+            &queries[..2],
+            &config,
+            &match_lambda,
+            &traceback_lambda,
+        )
+        .collect();
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|res| matches!(res, Err(types::AlignError::InvalidConfig(_)))));
+    }
+}
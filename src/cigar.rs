@@ -0,0 +1,341 @@
+/*!
+CIGAR of an alignment stored as runs of operations
+
+The operations use the same letters as the traceback:
+ - M a query character matching a text character
+ - X a query character aligned to a different text character
+ - I a text character with no query character
+ - D a query character with no text character
+ - S a query character left out of the alignment (soft clip)
+
+SAM describes the query relative to the reference (our text) so I and D are
+swapped when exporting to SAM and swapped back by [Cigar::from_sam].
+ */
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CigarOp {
+    Match,
+    Mismatch,
+    Insertion,
+    Deletion,
+    SoftClip,
+}
+
+impl CigarOp {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            'M' | '=' => Some(CigarOp::Match),
+            'X' => Some(CigarOp::Mismatch),
+            'I' => Some(CigarOp::Insertion),
+            'D' => Some(CigarOp::Deletion),
+            'S' => Some(CigarOp::SoftClip),
+            _ => None,
+        }
+    }
+
+    /// Read a SAM op, I and D are the other way round from the traceback
+    /// M could be a match or a mismatch and is read as a match
+    pub fn from_sam_char(c: char) -> Option<Self> {
+        match c {
+            'I' => Some(CigarOp::Deletion),
+            'D' => Some(CigarOp::Insertion),
+            'M' | '=' | 'X' | 'S' => CigarOp::from_char(c),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            CigarOp::Match => 'M',
+            CigarOp::Mismatch => 'X',
+            CigarOp::Insertion => 'I',
+            CigarOp::Deletion => 'D',
+            CigarOp::SoftClip => 'S',
+        }
+    }
+
+    /// Does the op use up a text character
+    pub fn consumes_text(self) -> bool {
+        !matches!(self, CigarOp::Deletion | CigarOp::SoftClip)
+    }
+
+    /// Does the op use up a query character
+    pub fn consumes_query(self) -> bool {
+        self != CigarOp::Insertion
+    }
+}
+
+/// A run length encoded CIGAR
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cigar {
+    runs: Vec<(CigarOp, u32)>,
+}
+
+impl Cigar {
+    pub fn new() -> Self {
+        Self { runs: Vec::new() }
+    }
+
+    /// Append a single op, extending the last run when it is the same op
+    pub fn push(&mut self, op: CigarOp) {
+        self.push_run(op, 1);
+    }
+
+    pub fn push_run(&mut self, op: CigarOp, len: u32) {
+        if len == 0 {
+            return;
+        }
+
+        match self.runs.last_mut() {
+            Some((last_op, last_len)) if *last_op == op => *last_len += len,
+            _ => self.runs.push((op, len)),
+        }
+    }
+
+    /// The (op, length) runs in alignment order
+    pub fn runs(&self) -> &[(CigarOp, u32)] {
+        &self.runs
+    }
+
+    /// One op for each column of the alignment
+    pub fn ops(&self) -> impl Iterator<Item = CigarOp> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|(op, len)| std::iter::repeat_n(*op, *len as usize))
+    }
+
+    /// Number of columns in the alignment
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Number of text characters the alignment spans
+    pub fn text_len(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|(op, _)| op.consumes_text())
+            .map(|(_, len)| *len as usize)
+            .sum()
+    }
+
+    /// Number of query characters the alignment spans
+    pub fn query_len(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|(op, _)| op.consumes_query())
+            .map(|(_, len)| *len as usize)
+            .sum()
+    }
+
+    /// One character per column e.g. MMMMXM
+    pub fn to_raw_string(&self) -> String {
+        self.ops().map(CigarOp::to_char).collect()
+    }
+
+    /// Compact SAM CIGAR where matches and mismatches are both M e.g. 6M
+    pub fn to_sam_string(&self) -> String {
+        self.sam_string(false)
+    }
+
+    /// SAM CIGAR with matches as = and mismatches as X e.g. 4=1X1=
+    pub fn to_extended_string(&self) -> String {
+        self.sam_string(true)
+    }
+
+    fn sam_string(&self, extended: bool) -> String {
+        let sam_op = |op: CigarOp| -> char {
+            match op {
+                CigarOp::Match if extended => '=',
+                CigarOp::Mismatch if extended => 'X',
+                CigarOp::Match | CigarOp::Mismatch => 'M',
+                CigarOp::Insertion => 'D',
+                CigarOp::Deletion => 'I',
+                CigarOp::SoftClip => 'S',
+            }
+        };
+
+        let mut sam = String::new();
+        let mut current: Option<(char, u32)> = None;
+
+        for (op, len) in self.runs.iter() {
+            let c = sam_op(*op);
+            current = match current {
+                Some((prev, count)) if prev == c => Some((prev, count + len)),
+                Some((prev, count)) => {
+                    sam.push_str(&format!("{count}{prev}"));
+                    Some((c, *len))
+                }
+                None => Some((c, *len)),
+            };
+        }
+
+        if let Some((prev, count)) = current {
+            sam.push_str(&format!("{count}{prev}"));
+        }
+
+        sam
+    }
+
+    /// Parse a CIGAR written by [Cigar::to_sam_string] or
+    /// [Cigar::to_extended_string], see [CigarOp::from_sam_char]
+    pub fn from_sam(s: &str) -> Result<Self, ParseCigarError> {
+        parse(s, CigarOp::from_sam_char)
+    }
+}
+
+impl FromIterator<CigarOp> for Cigar {
+    fn from_iter<T: IntoIterator<Item = CigarOp>>(iter: T) -> Self {
+        let mut cigar = Cigar::new();
+        iter.into_iter().for_each(|op| cigar.push(op));
+        cigar
+    }
+}
+
+/// Run length encoded with the traceback letters e.g. 4M1X1M
+impl fmt::Display for Cigar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (op, len) in self.runs.iter() {
+            write!(f, "{}{}", len, op.to_char())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCigarError {
+    /// byte position of the offending character
+    pub position: usize,
+    pub found: char,
+}
+
+impl fmt::Display for ParseCigarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unexpected character {:?} at position {} in CIGAR",
+            self.found, self.position
+        )
+    }
+}
+
+impl std::error::Error for ParseCigarError {}
+
+/// Parses both the run length encoded (4M1X1M or 4=1X1=) and the raw
+/// (MMMMXM) forms, a missing length counts as 1
+/// I and D are read as traceback letters not SAM ones, see [Cigar::from_sam]
+impl FromStr for Cigar {
+    type Err = ParseCigarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s, CigarOp::from_char)
+    }
+}
+
+/// Read run lengths and the op each one is followed by, from_char reads the op
+fn parse(s: &str, from_char: fn(char) -> Option<CigarOp>) -> Result<Cigar, ParseCigarError> {
+    let mut cigar = Cigar::new();
+    let mut len: Option<u32> = None;
+
+    for (position, c) in s.char_indices() {
+        if let Some(digit) = c.to_digit(10) {
+            let l = len.unwrap_or(0);
+            len = Some(
+                l.checked_mul(10)
+                    .and_then(|l| l.checked_add(digit))
+                    .ok_or(ParseCigarError { position, found: c })?,
+            );
+            continue;
+        }
+
+        let op = from_char(c).ok_or(ParseCigarError { position, found: c })?;
+        cigar.push_run(op, len.take().unwrap_or(1));
+    }
+
+    // a trailing length with no op
+    if len.is_some() {
+        let position = s.len() - 1;
+        let found = s.chars().last().unwrap();
+        return Err(ParseCigarError { position, found });
+    }
+
+    Ok(cigar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_conversions() {
+        let cigar: Cigar = "MMMMXMIIDM".parse().unwrap();
+
+        assert_eq!(cigar.len(), 10);
+        assert_eq!(cigar.text_len(), 9);
+        assert_eq!(cigar.query_len(), 8);
+
+        assert_eq!(cigar.to_string(), "4M1X1M2I1D1M");
+        assert_eq!(cigar.to_raw_string(), "MMMMXMIIDM");
+        assert_eq!(cigar.to_sam_string(), "6M2D1I1M");
+        assert_eq!(cigar.to_extended_string(), "4=1X1=2D1I1=");
+
+        let cigar: Cigar = "3M1X2S".parse().unwrap();
+        assert_eq!((cigar.text_len(), cigar.query_len()), (4, 6));
+        assert_eq!(cigar.to_sam_string(), "4M2S");
+    }
+
+    #[test]
+    fn test_parse() {
+        let cigar: Cigar = "4M1X1M2I1D1M".parse().unwrap();
+        assert_eq!(cigar, "MMMMXMIIDM".parse().unwrap());
+        assert_eq!(cigar, "4=X=2IDM".parse().unwrap());
+        assert_eq!(
+            cigar.runs(),
+            &[
+                (CigarOp::Match, 4),
+                (CigarOp::Mismatch, 1),
+                (CigarOp::Match, 1),
+                (CigarOp::Insertion, 2),
+                (CigarOp::Deletion, 1),
+                (CigarOp::Match, 1),
+            ]
+        );
+
+        assert_eq!(
+            "4M2Z".parse::<Cigar>(),
+            Err(ParseCigarError {
+                position: 3,
+                found: 'Z'
+            })
+        );
+        assert!("4M2".parse::<Cigar>().is_err());
+        assert_eq!("".parse::<Cigar>(), Ok(Cigar::new()));
+    }
+
+    #[test]
+    fn test_sam_round_trip() {
+        let cigar: Cigar = "4M1X1M2I1D1M".parse().unwrap();
+        assert_eq!(Cigar::from_sam(&cigar.to_extended_string()), Ok(cigar));
+
+        // the compact form doesn't tell matches and mismatches apart
+        let cigar: Cigar = "3M2I1M1D2M2S".parse().unwrap();
+        assert_eq!(cigar.to_sam_string(), "3M2D1M1I2M2S");
+        assert_eq!(Cigar::from_sam(&cigar.to_sam_string()), Ok(cigar));
+        assert_eq!(Cigar::from_sam("6M2D1I1M").unwrap().to_string(), "6M2I1D1M");
+
+        assert_eq!(
+            Cigar::from_sam("4M2N"),
+            Err(ParseCigarError {
+                position: 3,
+                found: 'N'
+            })
+        );
+    }
+}
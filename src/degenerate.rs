@@ -0,0 +1,505 @@
+/*!
+Degenerate texts and the match and traceback lambdas derived from them
+ */
+use super::cigar::{Cigar, CigarOp};
+use super::elastic::ElasticText;
+use super::types;
+use std::collections::BTreeMap;
+
+/// A text where each position holds one character per alternative
+///
+/// Consecutive positions that spell the same alternatives make up a segment
+/// e.g. ATC{TA,GA}A has the segments ATC, {TA,GA} and A
+/// and position 3 holds T and G.
+/// A path has to stay on one alternative through a segment and the
+/// characters alone can't say where one ends, so the text says where its
+/// segments start, see [SegmentedText].
+pub trait DegenerateText {
+    /// Number of positions in the text
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The characters at position h, one per alternative
+    fn chars_at(&self, h: usize) -> &[u8];
+
+    /// Does position h start a new segment, position 0 always does.
+    /// A position whose number of alternatives differs from the one before
+    /// starts a segment whatever this returns
+    fn is_segment_start(&self, h: usize) -> bool;
+}
+
+/// A plain text, every position has a single alternative
+impl DegenerateText for [u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn chars_at(&self, h: usize) -> &[u8] {
+        std::slice::from_ref(&self[h])
+    }
+
+    fn is_segment_start(&self, h: usize) -> bool {
+        h == 0
+    }
+}
+
+/// Positions that don't know which segment they belong to, so every
+/// position with more than one character is a segment of its own
+/// e.g. {A,G}{C,T} spells AC, AT, GC and GT. Use [SegmentedText] for
+/// alternatives longer than one position
+impl DegenerateText for [Vec<u8>] {
+    fn len(&self) -> usize {
+        <[Vec<u8>]>::len(self)
+    }
+
+    fn chars_at(&self, h: usize) -> &[u8] {
+        &self[h]
+    }
+
+    fn is_segment_start(&self, h: usize) -> bool {
+        h == 0 || self[h].len() > 1 || self[h - 1].len() > 1
+    }
+}
+
+impl DegenerateText for Vec<Vec<u8>> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn chars_at(&self, h: usize) -> &[u8] {
+        &self[h]
+    }
+
+    fn is_segment_start(&self, h: usize) -> bool {
+        self.as_slice().is_segment_start(h)
+    }
+}
+
+/// A degenerate text that keeps where each of its segments starts, built
+/// from segments whose alternatives all have the same length
+///
+/// {TA,GC}{AT,CG} spells TAAT, TACG, GCAT and GCCG, which the positions
+/// alone can't tell from {TAA,GCC}{T,G}.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentedText {
+    /// the characters at each position, one per alternative
+    positions: Vec<Vec<u8>>,
+
+    /// the position each segment starts at, segments with empty
+    /// alternatives have none
+    starts: Vec<usize>,
+}
+
+impl SegmentedText {
+    /// segments\[i\]\[j\] is the j-th alternative of the i-th segment
+    pub fn new(segments: &[Vec<Vec<u8>>]) -> Result<Self, types::AlignError> {
+        let mut positions: Vec<Vec<u8>> = Vec::new();
+        let mut starts: Vec<usize> = Vec::new();
+
+        for (index, alts) in segments.iter().enumerate() {
+            let len: usize = match alts.first() {
+                Some(alt) if alts.iter().all(|other| other.len() == alt.len()) => alt.len(),
+                Some(_) => {
+                    return Err(types::AlignError::InvalidConfig(format!(
+                    "the alternatives of segment {index} have different lengths, use align_elastic"
+                )))
+                }
+                None => {
+                    return Err(types::AlignError::InvalidConfig(format!(
+                        "segment {index} has no alternatives"
+                    )))
+                }
+            };
+
+            if len > 0 {
+                starts.push(positions.len());
+            }
+            positions.extend((0..len).map(|h| alts.iter().map(|alt| alt[h]).collect()));
+        }
+
+        Ok(Self { positions, starts })
+    }
+
+    /// How many segments with at least one position the text has
+    pub fn segment_count(&self) -> usize {
+        self.starts.len()
+    }
+}
+
+impl TryFrom<&ElasticText> for SegmentedText {
+    type Error = types::AlignError;
+
+    fn try_from(text: &ElasticText) -> Result<Self, Self::Error> {
+        Self::new(text.segments())
+    }
+}
+
+impl DegenerateText for SegmentedText {
+    fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    fn chars_at(&self, h: usize) -> &[u8] {
+        &self.positions[h]
+    }
+
+    fn is_segment_start(&self, h: usize) -> bool {
+        self.starts.binary_search(&h).is_ok()
+    }
+}
+
+#[cfg(feature = "eds")]
+impl DegenerateText for eds::DT {
+    fn len(&self) -> usize {
+        self.p()
+    }
+
+    fn chars_at(&self, h: usize) -> &[u8] {
+        &self[h]
+    }
+
+    // the segments of the text it was extracted from
+    fn is_segment_start(&self, h: usize) -> bool {
+        self.segment_starts().binary_search(&h).is_ok()
+    }
+}
+
+/// A degenerate text read from the end, position h is position len - 1 - h
+/// of the wrapped text
+///
+/// Pass it with the reversed query to [match_lambda] to get the reverse
+/// match lambda that [crate::align::wf_align_bidirectional] needs.
+pub struct Reversed<'a, T: DegenerateText + ?Sized>(pub &'a T);
+
+impl<T: DegenerateText + ?Sized> DegenerateText for Reversed<'_, T> {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn chars_at(&self, h: usize) -> &[u8] {
+        self.0.chars_at(self.0.len() - 1 - h)
+    }
+
+    // a segment starts here if the wrapped text's segment ended just before
+    fn is_segment_start(&self, h: usize) -> bool {
+        h == 0 || self.0.is_segment_start(self.0.len() - h)
+    }
+}
+
+/// Extend along a diagonal while the query matches an alternative that
+/// has matched since the start of the segment
+///
+/// The wavefronts keep the furthest offset of a diagonal, not where each
+/// alternative got to, so on a text with alternatives a path can switch
+/// alternatives part way through a segment. [crate::align::align_degenerate]
+/// only uses it on texts whose alternatives are one position long.
+pub fn match_lambda<'a, T: DegenerateText + ?Sized>(
+    text: &'a T,
+    query: &'a [u8],
+) -> impl FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool + 'a {
+    let tlen = text.len();
+    let qlen = query.len();
+
+    move |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| -> bool {
+        if *v < 0 || *h < 0 || *h as usize >= tlen || *v as usize >= qlen {
+            return false;
+        }
+
+        let text_chars: &[u8] = text.chars_at(*h as usize);
+        let query_char: u8 = query[*v as usize];
+        let z = text_chars.len();
+
+        if z == 1 {
+            if offsets.offset_count() != 1 {
+                *offsets = types::Offset::from([offsets.max()]);
+            }
+
+            if text_chars[0] != query_char {
+                return false;
+            }
+
+            offsets[0] += 1;
+            *v += 1;
+            *h += 1;
+
+            return true;
+        }
+
+        // every alternative starts from the furthest offset when entering a
+        // segment or when the offsets come from wf_next (no abandoned set)
+        if z != offsets.offset_count()
+            || !offsets.tracks_abandoned()
+            || text.is_segment_start(*h as usize)
+        {
+            let furthest: i32 = offsets.max();
+            *offsets = types::Offset::from_vec(&vec![furthest; z]);
+        }
+
+        let mut found = false;
+
+        for (idx, text_char) in text_chars.iter().enumerate() {
+            if *text_char != query_char {
+                offsets.set_abandon(idx);
+            } else if !offsets.is_abandoned(idx) {
+                offsets[idx] += 1;
+                found = true;
+            }
+        }
+
+        if found {
+            *v += 1;
+            *h += 1;
+        }
+
+        found
+    }
+}
+
+/// Check that the query matches some alternative at every position of a
+/// run of matches, not necessarily the same one, see [match_lambda]
+pub fn traceback_lambda<'a, T: DegenerateText + ?Sized>(
+    text: &'a T,
+    query: &'a [u8],
+) -> impl FnMut((i32, i32), (i32, i32)) -> bool + 'a {
+    move |(q_start, q_stop): (i32, i32), (t_start, t_stop): (i32, i32)| -> bool {
+        if q_start < 0 || q_stop < 0 || t_start < 0 || t_stop < 0 {
+            return false;
+        }
+
+        (q_start as usize..q_stop as usize)
+            .zip(t_start as usize..t_stop as usize)
+            .all(|(q_index, t_index)| text.chars_at(t_index).contains(&query[q_index]))
+    }
+}
+
+/// Does position h start a segment, the text says so or the number of
+/// alternatives changes
+fn starts_segment<T: DegenerateText + ?Sized>(text: &T, h: usize) -> bool {
+    h == 0 || text.is_segment_start(h) || text.chars_at(h).len() != text.chars_at(h - 1).len()
+}
+
+/// Does position h carry on the alternatives of the one before
+fn carries_on<T: DegenerateText + ?Sized>(text: &T, h: usize) -> bool {
+    text.chars_at(h).len() > 1 && !starts_segment(text, h)
+}
+
+/// Does an alternative of the text span more than one position, only then
+/// can a path switch alternatives part way through a segment
+pub(crate) fn has_long_alternatives<T: DegenerateText + ?Sized>(text: &T) -> bool {
+    (1..text.len()).any(|h| carries_on(text, h))
+}
+
+/// Segments whose alternatives are degenerate texts, see [to_segments]
+pub(crate) type Segments = Vec<Vec<Vec<Vec<u8>>>>;
+
+/// The segments a path has to stay on one alternative of, the j-th
+/// alternative holds the j-th character of each of the segment's positions.
+/// The positions in between, alternatives one position long included, are
+/// segments with a single alternative that keeps every character of them
+pub(crate) fn to_segments<T: DegenerateText + ?Sized>(
+    text: &T,
+) -> Result<Segments, types::AlignError> {
+    let mut segments: Segments = Vec::new();
+    for h in 0..text.len() {
+        let chars: &[u8] = text.chars_at(h);
+        if chars.is_empty() {
+            return Err(types::AlignError::InvalidConfig(format!(
+                "position {h} of the degenerate text has no characters"
+            )));
+        }
+
+        if carries_on(text, h) {
+            let alts: &mut Vec<Vec<Vec<u8>>> = segments.last_mut().unwrap();
+            alts.iter_mut()
+                .zip(chars)
+                .for_each(|(alt, c)| alt.push(vec![*c]));
+        } else if h + 1 < text.len() && carries_on(text, h + 1) {
+            segments.push(chars.iter().map(|c| vec![vec![*c]]).collect());
+        } else {
+            match segments.last_mut() {
+                Some(alts) if alts.len() == 1 => alts[0].push(chars.to_vec()),
+                _ => segments.push(vec![vec![chars.to_vec()]]),
+            }
+        }
+    }
+
+    Ok(segments)
+}
+
+/// The alternatives of each degenerate segment that agree with the most
+/// matches and mismatches of the cigar
+///
+/// Insertions don't rule out any alternative. The alternatives reported are
+/// the ones the alignment took: they agree with every match and mismatch
+/// when the alignment stayed on one alternative through the segment, which
+/// [crate::align::align_degenerate] makes sure of. Otherwise those that
+/// disagree the fewest times are reported, so the list is never empty.
+pub fn segment_alternatives<T: DegenerateText + ?Sized>(
+    text: &T,
+    query: &[u8],
+    cigar: &Cigar,
+) -> Vec<types::SegmentAlternatives> {
+    alternatives_from(text, query, cigar, 0, &segment_starts(text))
+}
+
+/// The position each segment of the text starts at
+pub(crate) fn segment_starts<T: DegenerateText + ?Sized>(text: &T) -> Vec<usize> {
+    (0..text.len())
+        .filter(|h| starts_segment(text, *h))
+        .collect()
+}
+
+/// Same as [segment_alternatives] for a cigar that starts at text_start,
+/// only the segments the cigar overlaps are reported
+pub(crate) fn alternatives_from<T: DegenerateText + ?Sized>(
+    text: &T,
+    query: &[u8],
+    cigar: &Cigar,
+    text_start: usize,
+    segment_starts: &[usize],
+) -> Vec<types::SegmentAlternatives> {
+    let segment_of =
+        |h: usize| -> usize { segment_starts.partition_point(|start| *start <= h) - 1 };
+
+    // how many times each alternative of each overlapped segment disagrees
+    let mut disagrees: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+
+    let (mut v, mut h) = (0, text_start);
+    for op in cigar.ops() {
+        if op.consumes_text() && h < text.len() {
+            let segment: &mut Vec<usize> = disagrees
+                .entry(segment_of(h))
+                .or_insert_with(|| vec![0; text.chars_at(h).len()]);
+
+            if op != CigarOp::Insertion && v < query.len() {
+                for (idx, text_char) in text.chars_at(h).iter().enumerate() {
+                    if (*text_char == query[v]) != (op == CigarOp::Match) {
+                        segment[idx] += 1;
+                    }
+                }
+            }
+        }
+
+        if op.consumes_text() {
+            h += 1;
+        }
+
+        if op.consumes_query() {
+            v += 1;
+        }
+    }
+
+    disagrees
+        .into_iter()
+        .filter(|(_, alts)| alts.len() > 1)
+        .map(|(segment, alts)| {
+            let fewest: usize = *alts.iter().min().unwrap();
+            types::SegmentAlternatives {
+                segment,
+                alternatives: (0..alts.len()).filter(|idx| alts[*idx] == fewest).collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segmented(ed_string: &str) -> SegmentedText {
+        let text: ElasticText = ed_string.parse().unwrap();
+        SegmentedText::try_from(&text).unwrap()
+    }
+
+    #[test]
+    fn test_segment_starts() {
+        let text = segmented("ATC{TA,GA}{C,T}");
+        assert_eq!(segment_starts(&text), vec![0, 3, 5]);
+        assert_eq!(text.segment_count(), 3);
+
+        // the positions alone take each alternative as one position long
+        let positions: Vec<Vec<u8>> = (0..text.len()).map(|h| text.chars_at(h).to_vec()).collect();
+        assert_eq!(segment_starts(&positions), vec![0, 3, 4, 5]);
+
+        // empty alternatives have no position to start at
+        assert_eq!(segment_starts(&segmented("A{,}C")), vec![0, 1]);
+
+        assert!(matches!(
+            SegmentedText::try_from(&"A{C,GT}".parse::<ElasticText>().unwrap()),
+            Err(types::AlignError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_to_segments() {
+        let text = segmented("A{C,G}T{TA,GC}A");
+        assert!(has_long_alternatives(&text));
+
+        // the alternative one position long stays in the run around it
+        let run = |positions: &[&[u8]]| -> Vec<Vec<Vec<u8>>> {
+            vec![positions.iter().map(|chars| chars.to_vec()).collect()]
+        };
+        let long: Vec<Vec<Vec<u8>>> = vec![
+            vec![b"T".to_vec(), b"A".to_vec()],
+            vec![b"G".to_vec(), b"C".to_vec()],
+        ];
+        assert_eq!(
+            to_segments(&text).unwrap(),
+            vec![run(&[b"A", b"CG", b"T"]), long, run(&[b"A"])]
+        );
+
+        // adjacent segments with as many alternatives stay apart
+        let long = |alts: &[&[u8]]| -> Vec<Vec<Vec<u8>>> {
+            alts.iter()
+                .map(|alt| alt.iter().map(|c| vec![*c]).collect())
+                .collect()
+        };
+        assert_eq!(
+            to_segments(&segmented("{TAA,GTA}{AT,CG}")).unwrap(),
+            vec![long(&[b"TAA", b"GTA"]), long(&[b"AT", b"CG"])]
+        );
+
+        let positions: Vec<Vec<u8>> = vec![b"A".to_vec(), b"CG".to_vec(), b"TA".to_vec()];
+        assert!(!has_long_alternatives(&positions));
+    }
+
+    #[test]
+    fn test_segment_alternatives() {
+        let text = segmented("ATC{TA,GC}A");
+        let alternatives = |query: &[u8], cigar: &str| -> Vec<usize> {
+            let cigar: Cigar = cigar.parse().unwrap();
+            let segments = segment_alternatives(&text, query, &cigar);
+            assert_eq!(segments.len(), 1);
+            assert_eq!(segments[0].segment, 1);
+            segments[0].alternatives.clone()
+        };
+
+        assert_eq!(alternatives(b"ATCGCA", "6M"), vec![1]);
+        assert_eq!(alternatives(b"ATCGAA", "3M1X2M"), vec![0]);
+        assert_eq!(alternatives(b"ATCAA", "3M1I2M"), vec![0]);
+
+        // T of the first alternative then C of the second, both disagree once
+        assert_eq!(alternatives(b"ATCTCA", "6M"), vec![0, 1]);
+        assert_eq!(alternatives(b"ATCTCA", "3M1X2M"), vec![1]);
+    }
+
+    #[test]
+    fn test_match_lambda() {
+        let text = segmented("A{TA,GC}");
+        let query = b"AGC";
+
+        let mut match_lambda = match_lambda(&text, query);
+        let (mut v, mut h) = (0, 0);
+        let mut offsets = types::Offset::from([0]);
+
+        while match_lambda(&mut v, &mut h, &mut offsets) {}
+
+        // A then G on the second alternative which carries on to C
+        assert_eq!((v, h), (3, 3));
+        assert_eq!(offsets.as_slice(), [1, 3]);
+        assert!(offsets.is_abandoned(0));
+    }
+}
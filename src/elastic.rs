@@ -0,0 +1,212 @@
+/*!
+Elastic degenerate texts, where the alternatives of a segment can have
+different lengths
+
+Written the same way as the eds crate e.g. ACT{A,ATTG,}CA has the segments
+ACT, {A,ATTG,} and CA and the last alternative of the second one is empty.
+ */
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElasticText {
+    /// segments\[i\]\[j\] is the j-th alternative of the i-th segment
+    segments: Vec<Vec<Vec<u8>>>,
+}
+
+impl ElasticText {
+    /// Every segment needs at least one alternative, which may be empty
+    pub fn new(segments: Vec<Vec<Vec<u8>>>) -> Result<Self, ElasticTextError> {
+        if let Some(index) = segments.iter().position(|alts| alts.is_empty()) {
+            return Err(ElasticTextError::EmptySegment { index });
+        }
+
+        Ok(Self { segments })
+    }
+
+    pub fn segments(&self) -> &[Vec<Vec<u8>>] {
+        &self.segments
+    }
+
+    /// The alternatives of the i-th segment
+    pub fn alternatives(&self, i: usize) -> &[Vec<u8>] {
+        &self.segments[i]
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Length of the shortest string spelled by the text
+    pub fn min_len(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|alts| alts.iter().map(Vec::len).min().unwrap_or(0))
+            .sum()
+    }
+
+    /// Length of the longest string spelled by the text
+    pub fn max_len(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|alts| alts.iter().map(Vec::len).max().unwrap_or(0))
+            .sum()
+    }
+}
+
+/// Segments with a single alternative are written without braces
+impl fmt::Display for ElasticText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for alts in self.segments.iter() {
+            if alts.len() == 1 {
+                write!(f, "{}", String::from_utf8_lossy(&alts[0]))?;
+                continue;
+            }
+
+            let alts: Vec<_> = alts
+                .iter()
+                .map(|alt| String::from_utf8_lossy(alt))
+                .collect();
+            write!(f, "{{{}}}", alts.join(","))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElasticTextError {
+    /// A brace or comma where it can't be
+    UnexpectedChar { position: usize, found: char },
+
+    /// The brace at this position is never closed
+    Unclosed { position: usize },
+
+    /// The segment at this index has no alternatives
+    EmptySegment { index: usize },
+}
+
+impl fmt::Display for ElasticTextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElasticTextError::UnexpectedChar { position, found } => {
+                write!(f, "unexpected character {found:?} at position {position}")
+            }
+            ElasticTextError::Unclosed { position } => {
+                write!(f, "brace at position {position} is never closed")
+            }
+            ElasticTextError::EmptySegment { index } => {
+                write!(f, "segment {index} has no alternatives")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ElasticTextError {}
+
+/// The segments and alternatives of the text as the eds crate keeps them
+#[cfg(feature = "eds")]
+impl TryFrom<&eds::EDT> for ElasticText {
+    type Error = ElasticTextError;
+
+    fn try_from(edt: &eds::EDT) -> Result<Self, Self::Error> {
+        Self::new(edt.segments().to_vec())
+    }
+}
+
+impl FromStr for ElasticText {
+    type Err = ElasticTextError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments: Vec<Vec<Vec<u8>>> = Vec::new();
+        let mut current: Vec<u8> = Vec::new();
+
+        // the alternatives seen so far and where the brace was opened
+        let mut open: Option<(usize, Vec<Vec<u8>>)> = None;
+
+        for (position, c) in s.char_indices() {
+            match (c, open.as_mut()) {
+                ('{', None) => {
+                    if !current.is_empty() {
+                        segments.push(vec![std::mem::take(&mut current)]);
+                    }
+                    open = Some((position, Vec::new()));
+                }
+                (',', Some((_, alts))) => alts.push(std::mem::take(&mut current)),
+                ('}', Some(_)) => {
+                    let (_, mut alts) = open.take().unwrap();
+                    alts.push(std::mem::take(&mut current));
+                    segments.push(alts);
+                }
+                ('{' | ',' | '}', _) => {
+                    return Err(ElasticTextError::UnexpectedChar { position, found: c })
+                }
+                _ => {
+                    let mut buf = [0; 4];
+                    current.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+
+        if let Some((position, _)) = open {
+            return Err(ElasticTextError::Unclosed { position });
+        }
+
+        if !current.is_empty() {
+            segments.push(vec![current]);
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text: ElasticText = "ACT{A,ATTG,}CA".parse().unwrap();
+
+        assert_eq!(
+            text.segments(),
+            &[
+                vec![b"ACT".to_vec()],
+                vec![b"A".to_vec(), b"ATTG".to_vec(), b"".to_vec()],
+                vec![b"CA".to_vec()],
+            ]
+        );
+        assert_eq!((text.min_len(), text.max_len()), (5, 9));
+        assert_eq!(text.to_string(), "ACT{A,ATTG,}CA");
+
+        let text: ElasticText = "{A,C}{G,T}".parse().unwrap();
+        assert_eq!(text.segment_count(), 2);
+
+        assert_eq!(
+            "AC}".parse::<ElasticText>(),
+            Err(ElasticTextError::UnexpectedChar {
+                position: 2,
+                found: '}'
+            })
+        );
+        assert_eq!(
+            "A{C,{G}}".parse::<ElasticText>(),
+            Err(ElasticTextError::UnexpectedChar {
+                position: 4,
+                found: '{'
+            })
+        );
+        assert_eq!(
+            "A{C,G".parse::<ElasticText>(),
+            Err(ElasticTextError::Unclosed { position: 1 })
+        );
+        assert_eq!(
+            ElasticText::new(vec![vec![b"A".to_vec()], vec![]]),
+            Err(ElasticTextError::EmptySegment { index: 1 })
+        );
+    }
+}
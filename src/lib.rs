@@ -1,7 +1,17 @@
 mod utils;
+mod wf_adapt;
+mod wf_backtrace;
+mod wf_bialign;
+mod wf_elastic;
 mod wf_extend;
 mod wf_next;
 mod wf_traceback;
 
 pub mod align;
+#[cfg(feature = "rayon")]
+pub mod batch;
+pub mod cigar;
+pub mod degenerate;
+pub mod elastic;
+pub mod progress;
 pub mod types;
@@ -0,0 +1,124 @@
+/*!
+Report how far an alignment has got, see [crate::types::Config::progress]
+
+The wavefronts are computed a score at a time from the start, after each
+score the hook gets the furthest offset the central diagonal, the one the
+alignment ends on, has reached so far out of the text length.
+ */
+
+/// A hook called after each score of an alignment
+///
+/// It is shared through the config so it takes &self, the aligners of a
+/// batch can call it from several threads at once.
+pub trait Progress: Send + Sync {
+    /// The furthest offset on the central diagonal after the given score,
+    /// out of tlen. The score is the one the wavefronts are computed with,
+    /// see [crate::types::Penalties::without_bonus]
+    fn update(&self, score: usize, offset: u32, tlen: u32);
+
+    /// The alignment reached its end or gave up
+    fn finish(&self) {}
+}
+
+impl<F> Progress for F
+where
+    F: Fn(usize, u32, u32) + Send + Sync,
+{
+    fn update(&self, score: usize, offset: u32, tlen: u32) {
+        self(score, offset, tlen)
+    }
+}
+
+/// A progress bar of the offset on the central diagonal
+#[cfg(feature = "indicatif")]
+pub fn progress_bar() -> indicatif::ProgressBar {
+    let template = "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}]  {pos:>7}/{len:7}  {msg} ({eta_precise})";
+    let progress_style = indicatif::ProgressStyle::default_bar()
+        .template(template)
+        .progress_chars("=> ");
+
+    let progress_bar = indicatif::ProgressBar::new(0);
+    progress_bar.set_style(progress_style);
+    progress_bar
+}
+
+#[cfg(feature = "indicatif")]
+impl Progress for indicatif::ProgressBar {
+    fn update(&self, score: usize, offset: u32, tlen: u32) {
+        self.set_length(tlen as u64);
+        self.set_position(offset as u64);
+        self.set_message(format!("score {score}"));
+    }
+
+    fn finish(&self) {
+        indicatif::ProgressBar::finish(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::align;
+    use crate::types;
+
+    #[derive(Default)]
+    struct Recorder {
+        updates: Mutex<Vec<(usize, u32, u32)>>,
+        finished: Mutex<usize>,
+    }
+
+    impl Progress for Recorder {
+        fn update(&self, score: usize, offset: u32, tlen: u32) {
+            self.updates.lock().unwrap().push((score, offset, tlen));
+        }
+
+        fn finish(&self) {
+            *self.finished.lock().unwrap() += 1;
+        }
+    }
+
+    fn test_config() -> types::Config {
+        types::Config::default()
+    }
+
+    #[test]
+    fn test_progress() {
+        let text = "GATTACAGATTACA".as_bytes();
+        let query = "GATTCAGATTTACA".as_bytes();
+
+        let recorder = Arc::new(Recorder::default());
+        let mut config = test_config();
+        config.progress = Some(recorder.clone());
+
+        let alignment = align::align_degenerate(text, query, &config).unwrap();
+
+        // a call for each score up to that of the alignment, ending on the end
+        let updates = recorder.updates.lock().unwrap().clone();
+        assert_eq!(updates.len(), alignment.score as usize + 1);
+        assert!(updates.iter().enumerate().all(|(score, u)| u.0 == score));
+        assert!(updates.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(updates.last(), Some(&(alignment.score as usize, 14, 14)));
+        assert_eq!(*recorder.finished.lock().unwrap(), 1);
+
+        // giving up finishes too
+        config.max_score = Some(3);
+        let res = align::align_degenerate(text, query, &config);
+        assert!(matches!(
+            res,
+            Err(types::AlignError::ScoreLimitExceeded { .. })
+        ));
+        assert_eq!(*recorder.finished.lock().unwrap(), 2);
+
+        // a closure is a hook
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        config.max_score = None;
+        config.progress = Some(Arc::new(move |_: usize, _: u32, _: u32| {
+            *counter.lock().unwrap() += 1;
+        }));
+        align::align_degenerate(text, query, &config).unwrap();
+        assert_eq!(*calls.lock().unwrap(), alignment.score as usize + 1);
+    }
+}
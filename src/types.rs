@@ -2,8 +2,13 @@
 Configs and related WFA types
  */
 
+use super::cigar::{Cigar, CigarOp};
+use super::progress::Progress;
 use super::utils;
-use std::ops::Index;
+use fbox::macros::{max, min};
+use std::fmt;
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 
 // ---------
 // Constants
@@ -15,22 +20,538 @@ const NULL_OFFSET: i32 = -10;
 //         Config
 // ----------------------
 // TODO: use u8
+#[derive(Clone)]
 pub struct Penalties {
     pub mismatch: i32,
+
+    /// A bonus for each match, 0 scores alignments by their penalties alone
+    /// and lower scores are better. With a bonus the score is the bonuses
+    /// minus the penalties and higher scores are better, the wavefronts are
+    /// computed with [Penalties::without_bonus] and the score converted back.
     pub matches: i32,
     pub gap_open: i32,
     pub gap_extend: i32,
 }
 
+/// The affine penalties WFA2-lib and minimap2 default to
+impl Default for Penalties {
+    fn default() -> Self {
+        Self {
+            mismatch: 4,
+            matches: 0,
+            gap_open: 6,
+            gap_extend: 2,
+        }
+    }
+}
+
+impl Penalties {
+    /// Are matches rewarded
+    pub(crate) fn has_bonus(&self) -> bool {
+        self.matches > 0
+    }
+
+    /// Penalties with no match bonus that rank alignments the way these do
+    ///
+    /// An alignment reaching (v, h) with m matches, x mismatches, o gap opens
+    /// and g gap characters has v + h = 2m + 2x + g. Its score with the bonus
+    /// is then (matches * (v + h) - s) / 2 where s is its score with these
+    /// penalties, see [Penalties::bonus_score].
+    pub(crate) fn without_bonus(&self) -> Penalties {
+        Penalties {
+            mismatch: 2 * (self.mismatch + self.matches),
+            matches: 0,
+            gap_open: 2 * self.gap_open,
+            gap_extend: 2 * self.gap_extend + self.matches,
+        }
+    }
+
+    /// Score, with the match bonus, of an alignment that reaches (v, h) with
+    /// the given score under [Penalties::without_bonus]
+    pub(crate) fn bonus_score(&self, score: i32, v: i32, h: i32) -> i32 {
+        (self.matches * (v + h) - score) / 2
+    }
+
+    /// Unit costs for [Distance::Edit]
+    pub fn edit() -> Penalties {
+        Penalties {
+            mismatch: 1,
+            matches: 0,
+            gap_open: 0,
+            gap_extend: 1,
+        }
+    }
+}
+
+/// How gaps are scored, which decides the wavefronts that are computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Distance {
+    /// A gap of n characters costs gap_open + n * gap_extend, needs the
+    /// I and D wavefronts on top of the M wavefront
+    #[default]
+    GapAffine,
+
+    /// A gap of n characters costs n * gap_extend, gap_open has to be 0
+    /// Only the M wavefront is computed
+    GapLinear,
+
+    /// Levenshtein distance, a linear gap with the unit costs of [Penalties::edit]
+    Edit,
+
+    /// Two-piece affine gaps, a gap of n characters costs the least of
+    /// gap_open + n * gap_extend and gap_open2 + n * gap_extend2
+    /// Usually the second piece opens at a higher cost and extends at a lower
+    /// one so that long gaps cost less. Needs the I2 and D2 wavefronts on top
+    /// of the I and D wavefronts
+    GapAffine2p { gap_open2: i32, gap_extend2: i32 },
+}
+
+impl Distance {
+    /// Are gaps computed on I and D wavefronts, otherwise on the M wavefront
+    pub(crate) fn has_gap_wavefronts(self) -> bool {
+        matches!(self, Distance::GapAffine | Distance::GapAffine2p { .. })
+    }
+
+    /// The gap open and gap extend penalties of the second piece, if any
+    pub(crate) fn second_gap_piece(self) -> Option<(i32, i32)> {
+        match self {
+            Distance::GapAffine2p {
+                gap_open2,
+                gap_extend2,
+            } => Some((gap_open2, gap_extend2)),
+            _ => None,
+        }
+    }
+
+    /// The distance to use with [Penalties::without_bonus]
+    pub(crate) fn without_bonus(self, matches: i32) -> Distance {
+        match self {
+            Distance::GapAffine2p {
+                gap_open2,
+                gap_extend2,
+            } => Distance::GapAffine2p {
+                gap_open2: 2 * gap_open2,
+                gap_extend2: 2 * gap_extend2 + matches,
+            },
+            distance => distance,
+        }
+    }
+}
+
+/// Thresholds for the adaptive wavefront heuristic, see [Config::adapt]
+#[derive(Clone)]
+pub struct AdaptParams {
+    /// wavefronts with fewer diagonals than this are left alone
+    pub min_wavefront_length: u32,
+
+    /// how much further from the end than the closest diagonal a diagonal
+    /// can be before it is dropped
+    pub max_distance_threshold: u32,
+}
+
+impl Default for AdaptParams {
+    fn default() -> Self {
+        Self {
+            min_wavefront_length: 10,
+            max_distance_threshold: 50,
+        }
+    }
+}
+
+/// How much of the wavefronts to keep while aligning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryMode {
+    /// Keep the wavefronts of every score for the traceback, O(s^2) memory
+    #[default]
+    High,
+
+    /// Keep only the wavefronts the next score is computed from, each cell
+    /// points into a buffer of backtrace blocks that the traceback follows
+    /// instead, like WFA2's piggybacked backtrace
+    /// O(s) wavefronts plus a block for each mismatch or gap of a live path
+    Low,
+
+    /// Align from both ends towards a breakpoint and recurse on the two
+    /// halves (BiWFA), O(s) memory at the cost of recomputing wavefronts
+    /// Needs a reverse match lambda, see [crate::align::wf_align_bidirectional]
+    Ultralow,
+}
+
+/// The parts of the text and query the alignment has to cover, like WFA2's
+/// alignment span
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlignmentSpan {
+    /// Global alignment, both sequences from start to end
+    #[default]
+    EndToEnd,
+
+    /// Up to this many characters at either end of the text or query can
+    /// be left unaligned without a penalty (semi-global alignment)
+    /// e.g. free text ends place a read anywhere in a longer text
+    EndsFree {
+        text_begin_free: u32,
+        text_end_free: u32,
+        query_begin_free: u32,
+        query_end_free: u32,
+    },
+
+    /// Align from the start of both sequences for as long as it pays off
+    /// (local alignment from an anchor, like extending a seed)
+    /// Penalties::matches is the bonus for a match and the score of the
+    /// alignment is the bonuses minus the penalties, higher is better.
+    /// The alignment ends on its best scoring cell, the rest of the query
+    /// is soft clipped and the rest of the text left out of the cigar.
+    Extension { drop: ScoreDrop },
+}
+
+/// When an extension stops, see [AlignmentSpan::Extension]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreDrop {
+    /// The best score of the wavefront is more than X below the best so far
+    XDrop(u32),
+
+    /// Like XDrop but a drop along a different diagonal than the best cell's
+    /// is allowed another gap_extend per diagonal between them, as in minimap2
+    ZDrop(u32),
+}
+
+#[derive(Clone)]
 pub struct Config {
+    /// Drop diagonals that lag far behind the rest of the wavefront
+    /// Faster and uses less memory but the alignment may not be optimal
     pub adapt: bool,
+    pub adapt_params: AdaptParams,
+    pub memory_mode: MemoryMode,
+    pub span: AlignmentSpan,
+    pub distance: Distance,
     // pub segment_length: u32, // segment size in bytes
     // pub step_size: u32,
-    // pub thread_count: usize,
-    pub verbosity: u8,
+    /// Split the diagonals of wide wavefronts across this many threads of
+    /// the current rayon pool, 1 computes them on the calling thread.
+    /// More than 1 needs the rayon feature. The match lambda can't be shared
+    /// between threads so extending along the diagonals isn't split.
+    pub thread_count: usize,
+    /// Give up past this score, None gives up past the score of deleting the
+    /// query and inserting the text, or of mismatching and then gapping the
+    /// rest, whichever is lower. With a match bonus it is a score under
+    /// [Penalties::without_bonus]. An extension stops there with the best
+    /// cell it found, a search is bounded by its own max_score.
+    pub max_score: Option<usize>,
+    /// On giving up, trace back to the cell furthest along the antidiagonals
+    /// and return that alignment with [AlignError::ScoreLimitExceeded].
+    /// The low memory mode only looks at the wavefronts it still keeps.
+    /// BiWFA gives up without one.
+    pub partial_alignment: bool,
+    /// Called after each score of an alignment computed from the start, not
+    /// by BiWFA, extensions or searches. On a text with alternatives offsets
+    /// count the shortest path through the segments. See [crate::progress]
+    pub progress: Option<Arc<dyn Progress>>,
     pub penalties: Penalties,
 }
 
+/// An exact end to end alignment with the default penalties in the high
+/// memory mode on the calling thread, change the fields that matter with
+/// struct update syntax e.g. `Config { memory_mode: MemoryMode::Low, ..Default::default() }`
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            adapt: false,
+            adapt_params: AdaptParams::default(),
+            memory_mode: MemoryMode::default(),
+            span: AlignmentSpan::default(),
+            distance: Distance::default(),
+            thread_count: 1,
+            max_score: None,
+            partial_alignment: false,
+            progress: None,
+            penalties: Penalties::default(),
+        }
+    }
+}
+
+impl Config {
+    /// The cost of a gap of n characters, the cheaper piece with two of them
+    fn gap_cost(&self, n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+
+        let piece = |gap_open: i32, gap_extend: i32| gap_open as u64 + gap_extend as u64 * n;
+        let first: u64 = match self.distance {
+            Distance::GapLinear | Distance::Edit => piece(0, self.penalties.gap_extend),
+            _ => piece(self.penalties.gap_open, self.penalties.gap_extend),
+        };
+
+        match self.distance.second_gap_piece() {
+            Some((gap_open2, gap_extend2)) => min!(first, piece(gap_open2, gap_extend2)),
+            None => first,
+        }
+    }
+
+    /// The score past which an alignment of tlen text and qlen query
+    /// characters gives up, max_score or else the score of an alignment
+    /// that every optimal one scores no more than
+    pub(crate) fn score_limit(&self, tlen: u32, qlen: u32) -> usize {
+        if let Some(max_score) = self.max_score {
+            return max_score;
+        }
+
+        let (tlen, qlen) = (tlen as u64, qlen as u64);
+        let (shorter, longer) = (min!(tlen, qlen), max!(tlen, qlen));
+
+        let limit: u64 = min!(
+            // mismatch the shorter one, then a gap for the rest of the longer
+            self.penalties.mismatch as u64 * shorter + self.gap_cost(longer - shorter),
+            // delete the whole query and insert the whole text
+            self.gap_cost(tlen) + self.gap_cost(qlen)
+        );
+
+        usize::try_from(limit).unwrap_or(usize::MAX)
+    }
+
+    /// Check that the penalties can be used to compute wavefronts
+    pub fn validate(&self) -> Result<(), AlignError> {
+        let p = &self.penalties;
+
+        if p.mismatch <= 0 {
+            return Err(AlignError::InvalidConfig(format!(
+                "mismatch penalty must be positive, got {}",
+                p.mismatch
+            )));
+        }
+
+        if p.gap_extend <= 0 {
+            return Err(AlignError::InvalidConfig(format!(
+                "gap extend penalty must be positive, got {}",
+                p.gap_extend
+            )));
+        }
+
+        if p.gap_open < 0 {
+            return Err(AlignError::InvalidConfig(format!(
+                "gap open penalty must not be negative, got {}",
+                p.gap_open
+            )));
+        }
+
+        if p.matches < 0 {
+            return Err(AlignError::InvalidConfig(format!(
+                "match bonus must not be negative, got {}",
+                p.matches
+            )));
+        }
+
+        // the transformed penalties only rank alignments that cover as much
+        // of the text and query
+        if matches!(self.span, AlignmentSpan::EndsFree { .. }) && p.has_bonus() {
+            return Err(AlignError::InvalidConfig(String::from(
+                "ends free alignments can't have a match bonus",
+            )));
+        }
+
+        if self.thread_count == 0 {
+            return Err(AlignError::InvalidConfig(String::from(
+                "thread count must be at least 1",
+            )));
+        }
+
+        if cfg!(not(feature = "rayon")) && self.thread_count > 1 {
+            return Err(AlignError::InvalidConfig(format!(
+                "computing wavefronts on {} threads needs the rayon feature",
+                self.thread_count
+            )));
+        }
+
+        // the breakpoint can only be found when no diagonal has been dropped
+        if self.adapt && self.memory_mode == MemoryMode::Ultralow {
+            return Err(AlignError::InvalidConfig(String::from(
+                "adapt can't be used with the ultralow memory mode",
+            )));
+        }
+
+        if self.span != AlignmentSpan::EndToEnd && self.memory_mode == MemoryMode::Ultralow {
+            return Err(AlignError::InvalidConfig(String::from(
+                "only end to end alignments can use the ultralow memory mode",
+            )));
+        }
+
+        // the best cell of an extension can fall out of the window before the end
+        if matches!(self.span, AlignmentSpan::Extension { .. })
+            && self.memory_mode == MemoryMode::Low
+        {
+            return Err(AlignError::InvalidConfig(String::from(
+                "extensions can't use the low memory mode",
+            )));
+        }
+
+        if self.distance == Distance::GapLinear && p.gap_open != 0 {
+            return Err(AlignError::InvalidConfig(format!(
+                "linear gaps have no gap open penalty, got {}",
+                p.gap_open
+            )));
+        }
+
+        if self.distance == Distance::Edit && (p.mismatch, p.gap_open, p.gap_extend) != (1, 0, 1) {
+            return Err(AlignError::InvalidConfig(String::from(
+                "edit distance needs the unit costs of Penalties::edit",
+            )));
+        }
+
+        if let Some((gap_open2, gap_extend2)) = self.distance.second_gap_piece() {
+            if gap_extend2 <= 0 || gap_open2 < 0 {
+                return Err(AlignError::InvalidConfig(format!(
+                    "the second gap piece needs a positive gap extend and a gap open \
+                     that isn't negative, got {} and {}",
+                    gap_extend2, gap_open2
+                )));
+            }
+        }
+
+        // the breakpoint is found on the I and D wavefronts
+        if self.distance != Distance::GapAffine && self.memory_mode == MemoryMode::Ultralow {
+            return Err(AlignError::InvalidConfig(String::from(
+                "only affine gaps can use the ultralow memory mode",
+            )));
+        }
+
+        if matches!(self.span, AlignmentSpan::Extension { .. }) && p.matches <= 0 {
+            return Err(AlignError::InvalidConfig(format!(
+                "an extension needs a positive match bonus, got {}",
+                p.matches
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// ----------------------
+//        Errors
+// ----------------------
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlignError {
+    /// Reached the highest score we are willing to compute without
+    /// reaching the end of the alignment
+    /// query_end and text_end are the furthest point, along the
+    /// antidiagonals, that the alignment got to from its start, partial an
+    /// alignment to it, see [Config::partial_alignment]
+    ScoreLimitExceeded {
+        score: usize,
+        max_score: usize,
+        query_end: u32,
+        text_end: u32,
+        partial: Option<Box<Alignment>>,
+    },
+
+    /// The traceback could not find the wavefront that led to the cell
+    /// on diagonal k at the given score and offset
+    TracebackInconsistency { score: i32, k: i32, offset: i32 },
+
+    /// Penalties (or other config values) that wavefronts can't be computed with
+    InvalidConfig(String),
+
+    /// Diagonal k is not between lo and hi
+    DiagonalOutOfBounds { k: i32, lo: i32, hi: i32 },
+
+    /// The match or traceback lambda did not behave as the aligner expects
+    LambdaContractViolation(String),
+
+    /// Wavefronts for this score (or a higher one) have already been allocated
+    WaveFrontExists { score: u32, max_score: u32 },
+
+    /// Something the aligner relies on for its own wavefronts doesn't hold,
+    /// a bug in the aligner rather than a bad config or input
+    Internal(String),
+}
+
+impl fmt::Display for AlignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlignError::ScoreLimitExceeded {
+                score,
+                max_score,
+                query_end,
+                text_end,
+                ..
+            } => write!(
+                f,
+                "gave up at score {score}, the limit is {max_score}, \
+                 got as far as query {query_end} and text {text_end}"
+            ),
+            AlignError::TracebackInconsistency { score, k, offset } => write!(
+                f,
+                "no link found during backtrace at score {score} k {k} offset {offset}"
+            ),
+            AlignError::InvalidConfig(msg) => write!(f, "invalid config: {msg}"),
+            AlignError::DiagonalOutOfBounds { k, lo, hi } => {
+                write!(f, "diagonal {k} is out of bounds lo = {lo} hi = {hi}")
+            }
+            AlignError::LambdaContractViolation(msg) => {
+                write!(f, "lambda contract violation: {msg}")
+            }
+            AlignError::WaveFrontExists { score, max_score } => write!(
+                f,
+                "can't allocate wavefronts for score {score}, already have up to {max_score}"
+            ),
+            AlignError::Internal(msg) => write!(f, "internal error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for AlignError {}
+
+// ----------------------
+//     Alignment
+// ----------------------
+/// The alternatives of a degenerate segment that an alignment agrees with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentAlternatives {
+    /// index of the segment in the text
+    pub segment: usize,
+
+    /// indices of the alternatives taken, more than one on ties, never empty
+    pub alternatives: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alignment {
+    /// in the scoring scheme of the penalties, see [Penalties::matches]
+    pub score: i32,
+    pub cigar: Cigar,
+
+    /// One entry for each segment with more than one alternative, in text order
+    /// Empty when aligning with lambdas since they don't describe the text
+    pub segments: Vec<SegmentAlternatives>,
+}
+
+/// The score of an alignment without its cigar, see [crate::align::wf_score]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignmentScore {
+    /// in the scoring scheme of the penalties, see [Penalties::matches]
+    pub score: i32,
+
+    /// where the alignment ends, one past its last query and text characters
+    pub query_end: u32,
+    pub text_end: u32,
+}
+
+/// Where the query occurs in the text, see [crate::align::wf_search]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    pub score: usize,
+
+    /// the text the query aligns to, text_end is one past its last character
+    pub text_start: u32,
+    pub text_end: u32,
+
+    /// aligns the whole query to the text between text_start and text_end
+    pub cigar: Cigar,
+
+    /// One entry for each segment with more than one alternative that the
+    /// occurrence overlaps, in text order
+    pub segments: Vec<SegmentAlternatives>,
+}
+
 // ----------------------
 //     Types
 // ----------------------
@@ -43,6 +564,11 @@ pub struct AWFSet<'a> {
     pub in_i_ext: Option<&'a WaveFront>,
     pub in_d_ext: Option<&'a WaveFront>,
 
+    // In, second gap piece
+    pub in_m_gap2: Option<&'a WaveFront>,
+    pub in_i2_ext: Option<&'a WaveFront>,
+    pub in_d2_ext: Option<&'a WaveFront>,
+
     // out
     pub out_m: Option<&'a WaveFront>,
     pub out_i: Option<&'a WaveFront>,
@@ -52,11 +578,13 @@ pub struct AWFSet<'a> {
 // ----------------------
 //     Core types
 // ----------------------
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WfType {
     D,
     I,
     M,
+    D2,
+    I2,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -69,10 +597,42 @@ pub enum BacktraceOperation {
 // TODO: should all be i32
 // matrix offset, text offset & query offset
 // pub type Offset = Vec<i32>;
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// The offsets of every alternative on a diagonal
+///
+/// A single alternative is stored inline so that a wavefront is one buffer
+/// of 16 byte offsets. Degenerate positions with several alternatives keep
+/// them in a side table along with a bitset of the abandoned ones.
+#[derive(Debug, Clone)]
 pub struct Offset {
-    pub data: Vec<i32>,
-    pub abdandoned: Option<Vec<u8>>,
+    /// The offset of the only alternative, unused when spilled
+    first: i32,
+
+    /// Whether the abandoned alternatives are tracked, see [Offset::from_vec]
+    tracked: bool,
+
+    /// Whether the only alternative was abandoned, unused when spilled
+    first_abandoned: bool,
+
+    /// Only set when there are several alternatives
+    spilled: Option<Box<Spilled>>,
+}
+
+#[derive(Debug, Clone)]
+struct Spilled {
+    data: Vec<i32>,
+
+    /// bit i is set once alternative i is abandoned
+    abandoned: Vec<u64>,
+}
+
+impl Spilled {
+    fn new(data: Vec<i32>) -> Self {
+        let words = data.len().div_ceil(64);
+        Self {
+            data,
+            abandoned: vec![0; words],
+        }
+    }
 }
 
 impl Offset {
@@ -80,99 +640,301 @@ impl Offset {
     // Construct
     // ---------
     pub fn blank() -> Self {
-        Self {
-            data: vec![0],
-            abdandoned: None,
-        }
+        Self::from([0])
     }
 
     // rename from_value
     pub fn from([e]: [i32; 1]) -> Self {
         Self {
-            data: vec![e],
-            abdandoned: None,
+            first: e,
+            tracked: false,
+            first_abandoned: false,
+            spilled: None,
         }
     }
 
+    /// Offsets that track which alternatives were abandoned
     pub fn from_vec(data: &Vec<i32>) -> Self {
-        let z = data.len();
-        Self {
-            data: data.clone(),
-            abdandoned: Some(vec![0; z]),
+        let mut offsets = Self::from([data[0]]);
+        if data.len() > 1 {
+            offsets.spilled = Some(Box::new(Spilled::new(data.clone())));
         }
+        offsets.tracked = true;
+
+        offsets
     }
 
     pub fn null() -> Self {
-        Self {
-            data: vec![NULL_OFFSET],
-            abdandoned: None,
+        Self::from([NULL_OFFSET])
+    }
+
+    /// Reset to the starting offset
+    pub fn reset(&mut self) {
+        *self = Self::blank();
+    }
+
+    /// Replace the offsets of every alternative, keeps the side table of a
+    /// degenerate position allocated
+    pub fn assign<I: IntoIterator<Item = i32>>(&mut self, values: I) {
+        let mut values = values.into_iter();
+        let first: i32 = values.next().expect("types::Offset::assign");
+
+        match values.next() {
+            None => {
+                self.first = first;
+                self.first_abandoned = false;
+                self.spilled = None;
+            }
+            Some(second) => {
+                let spilled: &mut Box<Spilled> = self
+                    .spilled
+                    .get_or_insert_with(|| Box::new(Spilled::new(Vec::new())));
+                spilled.data.clear();
+                spilled.data.push(first);
+                spilled.data.push(second);
+                spilled.data.extend(values);
+                spilled.abandoned.clear();
+                spilled.abandoned.resize(spilled.data.len().div_ceil(64), 0);
+            }
         }
     }
 
     pub fn abandoned_all_null(&mut self) {
-        let z = self.data.len();
-        self.abdandoned = Some(vec![0; z]);
+        self.tracked = true;
+        self.clear_abandoned();
+    }
+
+    /// Stop tracking abandoned alternatives, they are all reset by the next
+    /// degenerate match
+    pub fn untrack_abandoned(&mut self) {
+        self.tracked = false;
+        self.clear_abandoned();
+    }
+
+    fn clear_abandoned(&mut self) {
+        self.first_abandoned = false;
+        if let Some(spilled) = self.spilled.as_mut() {
+            spilled.abandoned.iter_mut().for_each(|word| *word = 0);
+        }
+    }
+
+    pub fn tracks_abandoned(&self) -> bool {
+        self.tracked
+    }
+
+    /// The offset of each alternative
+    pub fn as_slice(&self) -> &[i32] {
+        match &self.spilled {
+            Some(spilled) => &spilled.data,
+            None => std::slice::from_ref(&self.first),
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [i32] {
+        match &mut self.spilled {
+            Some(spilled) => &mut spilled.data,
+            None => std::slice::from_mut(&mut self.first),
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, i32> {
+        self.as_slice().iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, i32> {
+        self.as_mut_slice().iter_mut()
     }
 
     pub fn first(&self) -> i32 {
-        self.data[0]
+        self.as_slice()[0]
     }
 
     pub fn get_mut(&mut self, index: usize) -> &mut i32 {
-        self.data.get_mut(index).expect("types::Offset::get_mut")
+        self.as_mut_slice()
+            .get_mut(index)
+            .expect("types::Offset::get_mut")
     }
 
     pub fn get_mut_maybe(&mut self, index: usize) -> Option<&mut i32> {
-        self.data.get_mut(index)
+        self.as_mut_slice().get_mut(index)
     }
 
     // TODO: make this a result type
     pub fn set(&mut self, index: usize, value: i32) {
-        self.data[index] = value
+        self.as_mut_slice()[index] = value
     }
 
     // TODO: make this a result type
     pub fn push(&mut self, value: i32) {
-        self.data.push(value);
+        match self.spilled.as_mut() {
+            Some(spilled) => {
+                spilled.data.push(value);
+                spilled.abandoned.resize(spilled.data.len().div_ceil(64), 0);
+            }
+            None => {
+                let mut spilled = Spilled::new(vec![self.first, value]);
+                spilled.abandoned[0] = self.first_abandoned as u64;
+                self.first_abandoned = false;
+                self.spilled = Some(Box::new(spilled));
+            }
+        }
     }
     pub fn max(&self) -> i32 {
-        *self.data.iter().max().unwrap()
+        *self.iter().max().unwrap()
     }
 
     pub fn min(&self) -> i32 {
-        *self.data.iter().min().unwrap()
+        *self.iter().min().unwrap()
     }
 
     pub fn offset_count(&self) -> usize {
-        self.data.len()
+        self.as_slice().len()
     }
 
     pub fn set_abandon(&mut self, index: usize) {
-        self.abdandoned.as_mut().map(|v: &mut Vec<u8>| v[index] = 1);
+        if !self.tracked {
+            return;
+        }
+
+        match self.spilled.as_mut() {
+            Some(spilled) => spilled.abandoned[index / 64] |= 1 << (index % 64),
+            None => {
+                assert_eq!(index, 0, "types::Offset::set_abandon");
+                self.first_abandoned = true;
+            }
+        }
     }
 
     pub fn is_abandoned(&self, index: usize) -> bool {
-        match &self.abdandoned {
-            None => false,
-            Some(v) => {
-                if v[index] == 1 {
-                    true
-                } else {
-                    false
-                }
+        if !self.tracked {
+            return false;
+        }
+
+        match &self.spilled {
+            Some(spilled) => spilled.abandoned[index / 64] & (1 << (index % 64)) != 0,
+            None => {
+                assert_eq!(index, 0, "types::Offset::is_abandoned");
+                self.first_abandoned
+            }
+        }
+    }
+
+    // ----------------------------------------
+    // The public fields offsets used to have
+    // ----------------------------------------
+    /// What the data field held, see [Offset::as_slice]
+    #[deprecated(since = "0.0.1", note = "the data field is gone, use as_slice")]
+    pub fn data(&self) -> &[i32] {
+        self.as_slice()
+    }
+
+    /// What the data field held, its length can't change through it, see
+    /// [Offset::push] and [Offset::assign]
+    #[deprecated(since = "0.0.1", note = "the data field is gone, use as_mut_slice")]
+    pub fn data_mut(&mut self) -> &mut [i32] {
+        self.as_mut_slice()
+    }
+
+    /// What the abdandoned field held: None when abandoned alternatives
+    /// aren't tracked, otherwise 1 for each abandoned alternative and 0 for
+    /// the others
+    #[deprecated(
+        since = "0.0.1",
+        note = "the abdandoned field is gone, use is_abandoned"
+    )]
+    pub fn abdandoned(&self) -> Option<Vec<u8>> {
+        self.tracked.then(|| {
+            (0..self.offset_count())
+                .map(|idx| self.is_abandoned(idx) as u8)
+                .collect()
+        })
+    }
+
+    /// Set the abdandoned field as before, None stops tracking abandoned alternatives
+    #[deprecated(
+        since = "0.0.1",
+        note = "the abdandoned field is gone, use untrack_abandoned, abandoned_all_null and set_abandon"
+    )]
+    pub fn set_abdandoned(&mut self, abandoned: Option<Vec<u8>>) {
+        let Some(abandoned) = abandoned else {
+            self.untrack_abandoned();
+            return;
+        };
+
+        self.abandoned_all_null();
+        for (idx, flag) in abandoned.into_iter().enumerate() {
+            if flag != 0 {
+                self.set_abandon(idx);
             }
         }
     }
 }
 
+impl PartialEq for Offset {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+            && self.tracked == other.tracked
+            && (0..self.offset_count()).all(|idx| self.is_abandoned(idx) == other.is_abandoned(idx))
+    }
+}
+
+impl Eq for Offset {}
+
 impl Index<usize> for Offset {
     type Output = i32;
 
     fn index(&self, idx: usize) -> &Self::Output {
-        &self.data[idx]
+        &self.as_slice()[idx]
     }
 }
 
+impl IndexMut<usize> for Offset {
+    fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
+        &mut self.as_mut_slice()[idx]
+    }
+}
+
+/// No block, a cell without one is the start of its path
+pub(crate) const NO_BLOCK: u32 = u32::MAX;
+
+/// How a cell of a wavefront was reached in [MemoryMode::Low]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BacktraceCell {
+    /// The block of the last mismatch or gap on the path to the cell
+    pub(crate) block: u32,
+
+    /// The offset the cell had before its matches were extended
+    pub(crate) landed: i32,
+}
+
+impl BacktraceCell {
+    /// A path starts on diagonal k at the start of the text or of the query
+    pub(crate) fn start(k: i32) -> Self {
+        Self {
+            block: NO_BLOCK,
+            landed: max!(k, 0),
+        }
+    }
+}
+
+/// A mismatch or gap and the matches just before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BacktraceBlock {
+    /// The block of the mismatch or gap before the matches
+    pub(crate) prev: u32,
+    pub(crate) matches: i32,
+    pub(crate) op: CigarOp,
+}
+
+/// The blocks every [BacktraceCell] points into
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BacktraceBuffer {
+    pub(crate) blocks: Vec<BacktraceBlock>,
+
+    /// Drop the blocks no cell leads to once there are this many
+    pub(crate) compact_at: usize,
+}
+
 /// The a single wavefront with a score
 /// The furthest reaching point of a single wavefront
 #[derive(Debug, Clone)]
@@ -188,6 +950,10 @@ pub struct WaveFront {
     /// vals\[<last>\] is the score of the wavefront at diagonal lo
     /// length is (hi - lo) + 1
     pub offsets: Vec<Offset>,
+
+    /// Where each offset came from in [MemoryMode::Low], in the same order
+    /// Empty in the other modes and for the initial wavefront
+    pub(crate) backtrace: Vec<BacktraceCell>,
 }
 
 impl WaveFront {
@@ -200,7 +966,68 @@ impl WaveFront {
             hi,
             lo,
             offsets: vec![starting_offset; len],
+            backtrace: Vec::new(),
+        }
+    }
+
+    /// Reuse this wavefront for the diagonals lo..=hi
+    /// Keeps the offsets buffer allocated
+    pub fn reset(&mut self, hi: i32, lo: i32) {
+        let len = utils::new_compute_wave_length(lo, hi);
+
+        self.hi = hi;
+        self.lo = lo;
+
+        self.offsets.truncate(len);
+        self.offsets.iter_mut().for_each(|offset| offset.reset());
+        self.offsets.resize(len, Offset::from([0]));
+        self.backtrace.clear();
+    }
+
+    /// Grow the wavefront to cover the diagonals lo..=hi
+    /// the new diagonals hold null offsets
+    pub fn widen(&mut self, hi: i32, lo: i32) {
+        let null_cell = BacktraceCell {
+            block: NO_BLOCK,
+            landed: NULL_OFFSET,
+        };
+
+        if lo < self.lo {
+            let extra = (self.lo - lo) as usize;
+            self.offsets
+                .splice(0..0, std::iter::repeat_n(Offset::null(), extra));
+            if !self.backtrace.is_empty() {
+                self.backtrace
+                    .splice(0..0, std::iter::repeat_n(null_cell, extra));
+            }
+            self.lo = lo;
+        }
+
+        if hi > self.hi {
+            let extra = (hi - self.hi) as usize;
+            self.offsets
+                .extend(std::iter::repeat_n(Offset::null(), extra));
+            if !self.backtrace.is_empty() {
+                self.backtrace.extend(std::iter::repeat_n(null_cell, extra));
+            }
+            self.hi = hi;
+        }
+    }
+
+    /// Shrink the wavefront to the diagonals lo..=hi
+    pub fn trim(&mut self, hi: i32, lo: i32) {
+        let hi = min!(hi, self.hi);
+        let lo = max!(lo, self.lo);
+
+        self.offsets.truncate((hi - self.lo) as usize + 1);
+        self.offsets.drain(..(lo - self.lo) as usize);
+        if !self.backtrace.is_empty() {
+            self.backtrace.truncate((hi - self.lo) as usize + 1);
+            self.backtrace.drain(..(lo - self.lo) as usize);
         }
+
+        self.hi = hi;
+        self.lo = lo;
     }
 
     pub fn len(&self) -> usize {
@@ -213,7 +1040,7 @@ impl WaveFront {
         wave_len
     }
 
-    pub fn k_index(&self, k: i32) -> usize {
+    pub fn k_index(&self, k: i32) -> Result<usize, AlignError> {
         utils::new_compute_k_index(k, self.lo, self.hi)
     }
 
@@ -221,17 +1048,25 @@ impl WaveFront {
         utils::k_in_bounds(k, self.lo, self.hi)
     }
 
+    /// How the offset on diagonal k was reached, see [MemoryMode::Low]
+    pub(crate) fn backtrace_cell(&self, k: i32) -> BacktraceCell {
+        match self.backtrace.get((k - self.lo) as usize) {
+            Some(cell) => *cell,
+            // only the initial wavefront has no backtrace
+            None => BacktraceCell::start(k),
+        }
+    }
+
     // TODO: just return the i32
     /// return the offset at the k diagonal
     /// Computes the k-index internally
     /// takes the diagonal k (not k-index)
     pub fn get_offset(&self, k: i32) -> Option<&Offset> {
         if !utils::k_in_bounds(k, self.lo, self.hi) {
-            // eprintln!("out of bounds");
             return None;
         }
 
-        let k_index = utils::new_compute_k_index(k, self.lo, self.hi);
+        let k_index = utils::new_compute_k_index(k, self.lo, self.hi).ok()?;
 
         self.offsets
             .get(k_index)
@@ -241,11 +1076,10 @@ impl WaveFront {
     // TODO: rename to get_matrix offset
     pub fn get_offset_mut(&mut self, k: i32) -> Option<&mut Offset> {
         if !utils::k_in_bounds(k, self.lo, self.hi) {
-            // eprintln!("out of bounds mut");
             return None;
         }
 
-        let k_index = utils::new_compute_k_index(k, self.lo, self.hi);
+        let k_index = utils::new_compute_k_index(k, self.lo, self.hi).ok()?;
         self.offsets
             .get_mut(k_index)
             .and_then(|offset: &mut Offset| Some(offset))
@@ -254,11 +1088,10 @@ impl WaveFront {
     // Actual get offset
     pub fn bar(&self, k: i32) -> Option<&Offset> {
         if !utils::k_in_bounds(k, self.lo, self.hi) {
-            // eprintln!("out of bounds mut");
             return None;
         }
 
-        let k_index = utils::new_compute_k_index(k, self.lo, self.hi);
+        let k_index = utils::new_compute_k_index(k, self.lo, self.hi).ok()?;
         self.offsets
             .get(k_index)
             .and_then(|offset: &Offset| Some(offset))
@@ -267,11 +1100,10 @@ impl WaveFront {
     // Actual get offset mut
     pub fn foo(&mut self, k: i32) -> Option<&mut Offset> {
         if !utils::k_in_bounds(k, self.lo, self.hi) {
-            // eprintln!("out of bounds mut");
             return None;
         }
 
-        let k_index = utils::new_compute_k_index(k, self.lo, self.hi);
+        let k_index = utils::new_compute_k_index(k, self.lo, self.hi).ok()?;
         self.offsets
             .get_mut(k_index)
             .and_then(|offset: &mut Offset| Some(offset))
@@ -290,37 +1122,283 @@ pub struct WaveFrontSet {
     /// match wavefront
     /// $\tilde{M}_{s,k}$ is the value of the m wavefront at diagonal k
     pub m: Option<WaveFront>,
+
+    /// insertion and deletion wavefronts of the second gap piece
+    /// only used with [Distance::GapAffine2p]
+    pub i2: Option<WaveFront>,
+    pub d2: Option<WaveFront>,
+}
+
+impl WaveFrontSet {
+    pub fn empty() -> Self {
+        Self {
+            i: None,
+            d: None,
+            m: None,
+            i2: None,
+            d2: None,
+        }
+    }
+
+    pub fn get(&self, wf_type: WfType) -> Option<&WaveFront> {
+        match wf_type {
+            WfType::M => self.m.as_ref(),
+            WfType::I => self.i.as_ref(),
+            WfType::D => self.d.as_ref(),
+            WfType::I2 => self.i2.as_ref(),
+            WfType::D2 => self.d2.as_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, wf_type: WfType) -> &mut Option<WaveFront> {
+        match wf_type {
+            WfType::M => &mut self.m,
+            WfType::I => &mut self.i,
+            WfType::D => &mut self.d,
+            WfType::I2 => &mut self.i2,
+            WfType::D2 => &mut self.d2,
+        }
+    }
+
+    /// Every wavefront of the set, for recycling
+    fn into_wavefronts(self) -> impl Iterator<Item = WaveFront> {
+        [self.i, self.d, self.m, self.i2, self.d2]
+            .into_iter()
+            .flatten()
+    }
+
+    pub(crate) fn wavefronts(&self) -> impl Iterator<Item = &WaveFront> {
+        [&self.i, &self.d, &self.m, &self.i2, &self.d2]
+            .into_iter()
+            .flatten()
+    }
+
+    pub(crate) fn wavefronts_mut(&mut self) -> impl Iterator<Item = &mut WaveFront> {
+        [
+            &mut self.i,
+            &mut self.d,
+            &mut self.m,
+            &mut self.i2,
+            &mut self.d2,
+        ]
+        .into_iter()
+        .flatten()
+    }
 }
 
 /// All the wavefronts
-#[derive(Clone)]
 pub struct WaveFronts {
     /// The set of wavefronts with each score, the index represents the score
     /// and, each element is a wavefront.
-    /// WF_s is wavefront_set\[s\]
+    /// WF_s is wavefront_set\[s - first_score\] or wavefront_set\[s % window\]
+    /// when windowed
     pub wavefront_set: Vec<Option<WaveFrontSet>>,
 
     pub min_k: isize, // -qlen
     pub max_k: isize, // tlen
     pub a_k: i32,
+
+    /// Only keep this many scores, wavefront_set is then a ring buffer
+    window: Option<usize>,
+
+    /// The lowest score that has a slot in wavefront_set, see [WaveFronts::start_at]
+    first_score: usize,
+
+    /// The highest score that has a slot in wavefront_set
+    last_score: usize,
+
+    /// Wavefronts from previous alignments waiting to be reused
+    pool: Vec<WaveFront>,
+
+    /// The backtrace blocks in [MemoryMode::Low]
+    pub(crate) backtrace: Option<BacktraceBuffer>,
+}
+
+// the pool holds no alignment state so a clone starts with an empty one
+impl Clone for WaveFronts {
+    fn clone(&self) -> Self {
+        Self {
+            wavefront_set: self.wavefront_set.clone(),
+            min_k: self.min_k,
+            max_k: self.max_k,
+            a_k: self.a_k,
+            window: self.window,
+            first_score: self.first_score,
+            last_score: self.last_score,
+            pool: Vec::new(),
+            backtrace: self.backtrace.clone(),
+        }
+    }
 }
 
 impl WaveFronts {
+    /// Wavefronts for aligning a query of length qlen against a text of length tlen
+    /// Holds only the initial m-wavefront at score 0
+    pub fn new(tlen: u32, qlen: u32) -> Self {
+        let mut wavefronts = Self {
+            wavefront_set: Vec::new(),
+            min_k: 0,
+            max_k: 0,
+            a_k: 0,
+            window: None,
+            first_score: 0,
+            last_score: 0,
+            pool: Vec::new(),
+            backtrace: None,
+        };
+        wavefronts.reset(tlen, qlen);
+
+        wavefronts
+    }
+
+    /// Same as [WaveFronts::new] but only the last window scores are kept
+    /// Enough to compute the next wavefronts but not to trace back
+    pub fn windowed(tlen: u32, qlen: u32, window: usize) -> Self {
+        let mut wavefronts = Self::new(tlen, qlen);
+        wavefronts.window = Some(max!(window, 1));
+        wavefronts.reset(tlen, qlen);
+
+        wavefronts
+    }
+
+    /// Same as [WaveFronts::windowed] but the wavefronts record how each
+    /// cell was reached so that the alignment can still be traced back
+    /// See [MemoryMode::Low]
+    pub fn piggybacked(tlen: u32, qlen: u32, window: usize) -> Self {
+        let mut wavefronts = Self::windowed(tlen, qlen, window);
+        wavefronts.backtrace = Some(BacktraceBuffer::default());
+
+        wavefronts
+    }
+
+    /// Prepare for a new alignment, moving the current wavefronts into the pool
+    pub fn reset(&mut self, tlen: u32, qlen: u32) {
+        for wf_set in self.wavefront_set.drain(..).flatten() {
+            self.pool.extend(wf_set.into_wavefronts());
+        }
+
+        self.min_k = -(qlen as isize);
+        self.max_k = tlen as isize;
+        self.a_k = (tlen as isize - qlen as isize) as i32;
+
+        self.first_score = 0;
+        self.last_score = 0;
+        if let Some(buffer) = self.backtrace.as_mut() {
+            buffer.blocks.clear();
+        }
+
+        let slots = self.window.unwrap_or(1);
+        self.wavefront_set.resize_with(slots, || None);
+
+        let wf_set = WaveFrontSet {
+            m: Some(self.new_wavefront(0, 0)),
+            ..WaveFrontSet::empty()
+        };
+        self.wavefront_set[0] = Some(wf_set);
+    }
+
+    /// Hold no scores before the given one, for wavefronts whose paths only
+    /// start from seeds of that score or later. A segment of an elastic text
+    /// that is first reached at a high score doesn't keep a slot for every
+    /// score before
+    pub fn start_at(&mut self, score: usize) {
+        for wf_set in self.wavefront_set.iter_mut() {
+            if let Some(wf_set) = wf_set.take() {
+                self.pool.extend(wf_set.into_wavefronts());
+            }
+        }
+
+        self.wavefront_set.truncate(self.window.unwrap_or(1));
+        self.first_score = score;
+        self.last_score = score;
+    }
+
+    /// Index of the given score in wavefront_set
+    fn slot(&self, score: usize) -> usize {
+        match self.window {
+            Some(window) => score % window,
+            None => score - self.first_score,
+        }
+    }
+
+    /// Does wavefront_set have a slot for the score
+    /// Scores that fell out of the window or came before the first don't
+    fn holds(&self, score: usize) -> bool {
+        (self.first_score..=self.last_score).contains(&score)
+            && self
+                .window
+                .is_none_or(|window| score + window > self.last_score)
+    }
+
+    /// Make room for the wavefronts of every score up to the given one
+    /// When windowed the scores that fall out of the window go to the pool
+    pub fn advance(&mut self, score: usize) {
+        while self.last_score < score {
+            self.last_score += 1;
+
+            match self.window {
+                Some(_) => {
+                    let slot = self.slot(self.last_score);
+                    if let Some(wf_set) = self.wavefront_set[slot].take() {
+                        self.pool.extend(wf_set.into_wavefronts());
+                    }
+                }
+                None => self.wavefront_set.push(None),
+            }
+        }
+    }
+
+    /// Store the wavefronts of the given score, replacing any already there
+    pub fn set(&mut self, score: usize, wf_set: WaveFrontSet) {
+        self.advance(score);
+        self.discard(score);
+
+        let slot = self.slot(score);
+        self.wavefront_set[slot] = Some(wf_set);
+    }
+
+    pub fn get_mut(&mut self, score: usize) -> Option<&mut WaveFrontSet> {
+        if !self.holds(score) {
+            return None;
+        }
+
+        let slot = self.slot(score);
+        self.wavefront_set[slot].as_mut()
+    }
+
+    /// A wavefront spanning lo..=hi, taken from the pool when one is available
+    pub fn new_wavefront(&mut self, hi: i32, lo: i32) -> WaveFront {
+        match self.pool.pop() {
+            Some(mut wf) => {
+                wf.reset(hi, lo);
+                wf
+            }
+            None => WaveFront::new(hi, lo),
+        }
+    }
+
+    /// Number of recycled wavefronts available for reuse
+    pub fn pool_len(&self) -> usize {
+        self.pool.len()
+    }
+
     /// The scores should always be positive numbers
+    /// Panics if the score isn't held
     pub fn get(&self, score: usize) -> &Option<WaveFrontSet> {
-        &self.wavefront_set[score]
+        assert!(self.holds(score), "no slot for score {score}");
+        &self.wavefront_set[self.slot(score)]
     }
 
     pub fn option_get(&self, score: usize) -> Option<&WaveFrontSet> {
-        self.wavefront_set
-            .get(score)
-            .and_then(|maybe_wf_set| maybe_wf_set.as_ref())
+        if !self.holds(score) {
+            return None;
+        }
+
+        self.wavefront_set[self.slot(score)].as_ref()
     }
 
     pub fn get_wavefronts(&self, score: usize) -> Option<&WaveFrontSet> {
-        self.wavefront_set
-            .get(score)
-            .and_then(|maybe_wf_set| maybe_wf_set.as_ref())
+        self.option_get(score)
     }
 
     pub fn get_m_wavefront(&self, score: i32) -> Option<&WaveFront> {
@@ -362,13 +1440,76 @@ impl WaveFronts {
         }
     }
 
+    pub fn get_i2_wavefront(&self, score: i32) -> Option<&WaveFront> {
+        if score < 0 {
+            return None;
+        }
+
+        self.option_get(score as usize)
+            .and_then(|wf_set| wf_set.i2.as_ref())
+    }
+
+    pub fn get_d2_wavefront(&self, score: i32) -> Option<&WaveFront> {
+        if score < 0 {
+            return None;
+        }
+
+        self.option_get(score as usize)
+            .and_then(|wf_set| wf_set.d2.as_ref())
+    }
+
     pub fn set_i_d_m(&mut self, score: usize) {
         let wf_set: &mut Option<WaveFrontSet> = self.wavefront_set.get_mut(score).unwrap();
         let wf_set: &mut Option<WaveFrontSet> = &mut self.wavefront_set[score];
     }
 
+    /// Start a path on diagonal k of the given wavefront at the given score
+    /// keeps whichever of the current and the seeded offset is furthest
+    pub fn seed(&mut self, score: usize, wf_type: WfType, k: i32, offset: i32) {
+        self.advance(score);
+        let slot = self.slot(score);
+
+        let existing: Option<WaveFront> = self.wavefront_set[slot]
+            .as_mut()
+            .and_then(|wf_set| wf_set.get_mut(wf_type).take());
+
+        let mut wf: WaveFront = match existing {
+            Some(wf) => wf,
+            None => {
+                let mut wf = self.new_wavefront(k, k);
+                wf.offsets[0] = Offset::null();
+                wf
+            }
+        };
+
+        wf.widen(max!(wf.hi, k), min!(wf.lo, k));
+
+        let current: &mut Offset = wf.get_offset_mut(k).unwrap();
+        if current.max() < offset {
+            *current = Offset::from([offset]);
+        }
+
+        *self.wavefront_set[slot]
+            .get_or_insert_with(WaveFrontSet::empty)
+            .get_mut(wf_type) = Some(wf);
+    }
+
+    /// Move the wavefronts of the given score into the pool
+    /// Later scores can no longer be computed from them nor traced back through them
+    pub fn discard(&mut self, score: usize) {
+        if !self.holds(score) {
+            return;
+        }
+
+        let slot = self.slot(score);
+        if let Some(wf_set) = self.wavefront_set[slot].take() {
+            self.pool.extend(wf_set.into_wavefronts());
+        }
+    }
+
+    /// Number of scores computed so far, including those out of the window
     pub fn len(&self) -> usize {
-        self.wavefront_set.len()
+        self.last_score + 1
     }
 
     pub fn max_score(&self) -> u32 {
@@ -382,15 +1523,18 @@ impl WaveFronts {
         lo: i32,
         hi: i32,
         wavefronts_to_allocate: &Vec<WfType>,
-    ) -> Result<(), &str> {
+    ) -> Result<(), AlignError> {
         // should only add what is necessary
         let max_score = self.max_score();
         let len = num::abs_sub(hi, lo) as usize + 1;
 
         if max_score >= score {
             // we are trying to add a score that exists
-            // eprintln!("previous score {} score {}", prev_score, score);
-            return Err("[types::allocate_wavefronts] fuckery detected");
+            return Err(AlignError::WaveFrontExists { score, max_score });
+        }
+
+        if lo > hi {
+            return Err(AlignError::DiagonalOutOfBounds { k: lo, lo, hi });
         }
 
         for index in max_score + 1..=score {
@@ -398,32 +1542,108 @@ impl WaveFronts {
                 let wf_set = WaveFrontSet {
                     i: {
                         if wavefronts_to_allocate.contains(&WfType::I) {
-                            Some(WaveFront::new(hi, lo))
+                            Some(self.new_wavefront(hi, lo))
                         } else {
                             None
                         }
                     },
                     d: {
                         if wavefronts_to_allocate.contains(&WfType::D) {
-                            Some(WaveFront::new(hi, lo))
+                            Some(self.new_wavefront(hi, lo))
                         } else {
                             None
                         }
                     },
                     m: {
                         if wavefronts_to_allocate.contains(&WfType::M) {
-                            Some(WaveFront::new(hi, lo))
+                            Some(self.new_wavefront(hi, lo))
+                        } else {
+                            None
+                        }
+                    },
+                    i2: {
+                        if wavefronts_to_allocate.contains(&WfType::I2) {
+                            Some(self.new_wavefront(hi, lo))
+                        } else {
+                            None
+                        }
+                    },
+                    d2: {
+                        if wavefronts_to_allocate.contains(&WfType::D2) {
+                            Some(self.new_wavefront(hi, lo))
                         } else {
                             None
                         }
                     },
                 };
 
-                self.wavefront_set.push(Some(wf_set));
+                self.set(index as usize, wf_set);
             } else {
-                self.wavefront_set.push(None);
+                self.advance(index as usize);
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset() {
+        // a wavefront is a single buffer of offsets
+        assert_eq!(std::mem::size_of::<Offset>(), 16);
+
+        let mut offsets = Offset::from([3]);
+        assert_eq!(offsets.as_slice(), [3]);
+        assert!(!offsets.tracks_abandoned());
+        offsets.set_abandon(0);
+        assert!(!offsets.is_abandoned(0));
+
+        // abandoned alternatives past the first word of the bitset
+        let mut offsets = Offset::from_vec(&vec![5; 70]);
+        offsets.set_abandon(1);
+        offsets.set_abandon(65);
+        assert!(offsets.is_abandoned(1) && offsets.is_abandoned(65));
+        assert!(!offsets.is_abandoned(0) && !offsets.is_abandoned(64));
+        offsets.abandoned_all_null();
+        assert!(!offsets.is_abandoned(65));
+
+        // spilling keeps the abandoned first alternative
+        let mut offsets = Offset::from_vec(&vec![1]);
+        offsets.set_abandon(0);
+        offsets.push(2);
+        assert_eq!(offsets.as_slice(), [1, 2]);
+        assert!(offsets.is_abandoned(0) && !offsets.is_abandoned(1));
+        offsets[1] += 1;
+        assert_eq!(offsets.max(), 3);
+
+        offsets.assign([7]);
+        assert_eq!(offsets, Offset::from_vec(&vec![7]));
+        offsets.untrack_abandoned();
+        assert_eq!(offsets, Offset::from([7]));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_offset_fields() {
+        // the way match lambdas used the public fields still works
+        let mut offsets = Offset::from_vec(&vec![4, 4]);
+        assert_eq!(offsets.data().len(), 2);
+        offsets.data_mut()[1] += 1;
+        offsets
+            .data_mut()
+            .iter_mut()
+            .for_each(|offset| *offset += 1);
+        assert_eq!(offsets.data(), [5, 6]);
+
+        offsets.set_abandon(0);
+        assert_eq!(offsets.abdandoned(), Some(vec![1, 0]));
+        offsets.set_abdandoned(Some(vec![0, 1]));
+        assert!(!offsets.is_abandoned(0) && offsets.is_abandoned(1));
+        offsets.set_abdandoned(None);
+        assert_eq!(offsets.abdandoned(), None);
+        assert!(!offsets.is_abandoned(1));
+    }
+}
@@ -7,7 +7,8 @@ pub fn wflambda_backtrace_matches_check<G>(
     num_matches: u32,
     k: i32,
     traceback_lambda: &mut G,
-) where
+) -> Result<(), types::AlignError>
+where
     G: FnMut((i32, i32), (i32, i32)) -> bool,
 {
     let query_stop = utils::compute_v(*offset, k);
@@ -19,10 +20,17 @@ pub fn wflambda_backtrace_matches_check<G>(
     let query = (query_start as i32, query_stop as i32);
     let target = (target_start as i32, target_stop as i32);
 
-    if traceback_lambda(query, target) {
-        cigar.extend(utils::repeat_char('M', num_matches));
-        // *offset -= num_matches as i32;
+    if !traceback_lambda(query, target) {
+        return Err(types::AlignError::LambdaContractViolation(format!(
+            "traceback lambda rejected the matches at query {:?} text {:?}",
+            query, target
+        )));
     }
+
+    cigar.extend(utils::repeat_char('M', num_matches));
+    // *offset -= num_matches as i32;
+
+    Ok(())
 }
 
 // TODO: will this ever run in regions without a match?
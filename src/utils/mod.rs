@@ -21,20 +21,13 @@ pub fn compute_k_index(length: usize, k: i32, hi: i32) -> usize {
     length - ((hi - k) as usize) - 1
 }
 
-pub fn new_compute_k_index(k: i32, lo: i32, hi: i32) -> usize {
-    if lo > hi {
-        panic!("[utils::new_compute_k_index] lo > hi lo = {lo} hi = {hi}");
-    }
-
-    if k < lo {
-        panic!("[utils::new_compute_k_index] k < lo lo = {lo} hi = {hi} k = {k}");
+pub fn new_compute_k_index(k: i32, lo: i32, hi: i32) -> Result<usize, types::AlignError> {
+    // also covers lo > hi
+    if k_out_of_bounds(k, lo, hi) {
+        return Err(types::AlignError::DiagonalOutOfBounds { k, lo, hi });
     }
 
-    if k > hi {
-        panic!("[utils::new_compute_k_index] k > hi lo = {lo} hi = {hi} k = {k}");
-    }
-
-    (k - lo) as usize
+    Ok((k - lo) as usize)
 }
 
 pub fn new_compute_wave_length(lo: i32, hi: i32) -> usize {
@@ -76,60 +69,93 @@ pub fn compute_h(offset: i32, _: i32) -> i32 {
 }
 
 pub fn compute_v_new(offsets: &types::Offset, k: i32) -> i32 {
-    let furthest: i32 = *offsets
-        .data
-        .iter()
-        .max()
-        .expect("utils::mod::compute_v_new");
+    let furthest: i32 = *offsets.iter().max().expect("utils::mod::compute_v_new");
     (furthest as isize - k as isize) as i32
 }
 
 pub fn compute_h_new(offsets: &types::Offset, _: i32) -> i32 {
-    let furthest: i32 = *offsets
-        .data
-        .iter()
-        .max()
-        .expect("utils::mod::compute_h_new");
+    let furthest: i32 = *offsets.iter().max().expect("utils::mod::compute_h_new");
     furthest as i32
 }
 
-pub fn end_reached(
-    m_wavefront: Option<&types::WaveFront>,
-    a_k: i32,
-    a_offset: u32,
-    config: &types::Config,
-) -> bool {
-    if config.verbosity > 2 {
-        eprintln!(
-            "[wfa::utils::end_reached] m={:?} a_k={}, a_offset={}",
-            m_wavefront, a_k, a_offset
-        );
-    }
-
+pub fn end_reached(m_wavefront: Option<&types::WaveFront>, a_k: i32, a_offset: u32) -> bool {
     let m_wavefront = match m_wavefront {
         Some(wf) => wf,
         _ => return false,
     };
 
     if k_out_of_bounds(a_k, m_wavefront.lo, m_wavefront.hi) {
-        if config.verbosity > 2 {
-            eprintln!(
-                "[wfa::utils::end_reached] out of bounds lo={} hi={}",
-                m_wavefront.lo, m_wavefront.hi
-            );
-            // return false;
-        }
+        tracing::trace!(
+            a_k,
+            a_offset,
+            lo = m_wavefront.lo,
+            hi = m_wavefront.hi,
+            "central diagonal out of bounds"
+        );
     }
 
     match m_wavefront.get_offset(a_k) {
         Some(offsets) => {
             let done = |offset: &i32| -> bool { *offset as isize >= a_offset as isize };
-            offsets.data.iter().any(done)
+            offsets.iter().any(done)
         }
         None => false,
     }
 }
 
+/// Ends-free version of [end_reached]
+/// Returns the (k, offset) of a cell that reached the end of the text with at
+/// most query_end_free query characters left, or the end of the query with
+/// at most text_end_free text characters left
+pub fn end_reached_free(
+    m_wavefront: Option<&types::WaveFront>,
+    tlen: i32,
+    qlen: i32,
+    text_end_free: i32,
+    query_end_free: i32,
+) -> Option<(i32, i32)> {
+    let m_wavefront = m_wavefront?;
+
+    (m_wavefront.lo..=m_wavefront.hi).find_map(|k| {
+        let offset: i32 = m_wavefront.get_offset(k)?.max();
+        let (v, h) = (compute_v(offset, k), compute_h(offset, k));
+
+        let done = offset >= 0
+            && ((h == tlen && qlen - v <= query_end_free)
+                || (v == qlen && tlen - h <= text_end_free));
+
+        done.then_some((k, offset))
+    })
+}
+
+/// The (k, offset) of the cell of the M wavefront furthest along the antidiagonals
+pub fn furthest_cell(m_wavefront: Option<&types::WaveFront>) -> Option<(i32, i32)> {
+    let m_wavefront = m_wavefront?;
+
+    (m_wavefront.lo..=m_wavefront.hi)
+        .filter_map(|k| {
+            let offset: i32 = m_wavefront.get_offset(k)?.max();
+            (offset >= 0 && compute_v(offset, k) >= 0).then_some((k, offset))
+        })
+        .max_by_key(|(k, offset)| compute_v(*offset, *k) + compute_h(*offset, *k))
+}
+
+/// The (v, h) furthest along the antidiagonals out of reached and the cells
+/// of the M wavefront
+pub fn furthest_reached(m_wavefront: Option<&types::WaveFront>, reached: (i32, i32)) -> (i32, i32) {
+    match furthest_cell(m_wavefront) {
+        Some((k, offset)) => {
+            let (v, h) = (compute_v(offset, k), compute_h(offset, k));
+            if v + h > reached.0 + reached.1 {
+                (v, h)
+            } else {
+                reached
+            }
+        }
+        None => reached,
+    }
+}
+
 // TODO: make it a macro?
 pub fn repeat_char(c: char, count: u32) -> std::iter::Take<std::iter::Repeat<char>> {
     iter::repeat(c).take(count as usize)
@@ -236,12 +262,12 @@ pub fn print_aln(cigar: &[u8], t: &[u8], q: &[u8]) {
         }
     }
 
-    if false {
-        eprintln!();
-        eprintln!("{}", vec_u8_to_str_unsafe(&query));
-        eprintln!("{}", vec_u8_to_str_unsafe(&marker));
-        eprintln!("{}", vec_u8_to_str_unsafe(&text));
-    }
+    tracing::trace!(
+        query = vec_u8_to_str_unsafe(&query),
+        marker = vec_u8_to_str_unsafe(&marker),
+        text = vec_u8_to_str_unsafe(&text),
+        "alignment"
+    );
 }
 
 #[cfg(test)]
@@ -255,6 +281,21 @@ mod tests {
         assert_eq!(unsigned_literal_to_u8(9 as u32), 57);
     }
 
+    #[test]
+    fn test_new_compute_k_index() {
+        assert_eq!(new_compute_k_index(-2, -2, 3), Ok(0));
+        assert_eq!(new_compute_k_index(3, -2, 3), Ok(5));
+        assert_eq!(
+            new_compute_k_index(4, -2, 3),
+            Err(types::AlignError::DiagonalOutOfBounds {
+                k: 4,
+                lo: -2,
+                hi: 3
+            })
+        );
+        assert!(new_compute_k_index(0, 1, -1).is_err());
+    }
+
     #[test]
     fn test_run_length_encode() {
         let cigar = repeat_char('M', 11).map(|x| x as u8).collect::<Vec<u8>>();
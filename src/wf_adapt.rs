@@ -0,0 +1,82 @@
+/*!
+WFA-adaptive, drops the diagonals that have fallen far behind the rest of a wavefront
+ */
+use super::types;
+use fbox::macros::max;
+
+/// Trim the wavefronts with the given score to the diagonals whose distance to
+/// the end (tlen, qlen) is within [types::AdaptParams::max_distance_threshold]
+/// of the closest diagonal
+/// Diagonals are only dropped from lo and hi so the wavefront stays contiguous
+pub fn wf_reduce(
+    wavefronts: &mut types::WaveFronts,
+    score: usize,
+    tlen: i32,
+    qlen: i32,
+    config: &types::Config,
+) {
+    let params: &types::AdaptParams = &config.adapt_params;
+
+    let wf_set: &mut types::WaveFrontSet = match wavefronts.get_mut(score) {
+        Some(wf_set) => wf_set,
+        None => return,
+    };
+
+    let (lo, hi) = match wf_set.m.as_ref() {
+        Some(m_wf) if m_wf.len() >= params.min_wavefront_length as usize => {
+            // the larger of the text and query left to align
+            let distance = |k: i32| -> Option<i32> {
+                let offset: i32 = m_wf.get_offset(k)?.max();
+                if offset < 0 {
+                    return None;
+                }
+
+                Some(max!(tlen - offset, qlen - (offset - k)))
+            };
+
+            let min_distance: i32 = match (m_wf.lo..=m_wf.hi).filter_map(distance).min() {
+                Some(d) => d,
+                None => return,
+            };
+
+            let lagging = |k: i32| -> bool {
+                distance(k).is_none_or(|d| d - min_distance > params.max_distance_threshold as i32)
+            };
+
+            let mut lo: i32 = m_wf.lo;
+            while lo < m_wf.hi && lagging(lo) {
+                lo += 1;
+            }
+
+            let mut hi: i32 = m_wf.hi;
+            while hi > lo && lagging(hi) {
+                hi -= 1;
+            }
+
+            if (lo, hi) == (m_wf.lo, m_wf.hi) {
+                return;
+            }
+
+            (lo, hi)
+        }
+        _ => return,
+    };
+
+    tracing::trace!(score, lo, hi, "reduced");
+
+    for wf_type in [
+        types::WfType::M,
+        types::WfType::I,
+        types::WfType::D,
+        types::WfType::I2,
+        types::WfType::D2,
+    ] {
+        let maybe_wf: &mut Option<types::WaveFront> = wf_set.get_mut(wf_type);
+
+        match maybe_wf {
+            Some(wf) if wf.hi < lo || wf.lo > hi => *maybe_wf = None,
+            Some(wf) => wf.trim(hi, lo),
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,331 @@
+/*!
+Backtrace blocks of the low memory mode
+
+wf_next points every cell it computes at a block that holds the mismatch or
+gap the cell was reached with and the matches just before it. The blocks of
+a cell's path chain back to its start, so the traceback only needs the last
+wavefront and older wavefronts can be dropped.
+ */
+use super::cigar::{Cigar, CigarOp};
+use super::types::{self, AWFSet, BacktraceBlock, BacktraceBuffer, BacktraceCell, NO_BLOCK};
+use super::utils;
+use super::wf_traceback::check_matches;
+use fbox::macros::max;
+
+const NULL_OFFSET: i32 = -10;
+
+/// Blocks aren't compacted before there are this many
+const MIN_COMPACT_BLOCKS: usize = 1 << 16;
+
+/// A cell a wavefront can be computed from: the offset it reaches, the
+/// offset it is reached from, the backtrace of the cell it is reached from
+/// and the op in between, None for a cell of the same score
+type Candidate = (i32, i32, BacktraceCell, Option<CigarOp>);
+
+/// The furthest offset, in bounds on diagonal k, of diagonal from_k of the
+/// wavefront moved delta along the text, like wf_next computes it
+fn candidate(
+    maybe_wf: Option<&types::WaveFront>,
+    (from_k, k, delta): (i32, i32, i32),
+    op: Option<CigarOp>,
+    (tlen, qlen): (i32, i32),
+) -> Option<Candidate> {
+    let wf: &types::WaveFront = maybe_wf?;
+    let reached: i32 = wf
+        .get_offset(from_k)?
+        .iter()
+        .map(|offset| offset + delta)
+        .filter(|offset| *offset >= 0 && *offset <= tlen && offset - k <= qlen)
+        .max()?;
+
+    Some((reached, reached - delta, wf.backtrace_cell(from_k), op))
+}
+
+/// Point each cell of the wavefront at the first candidate that reaches its offset
+fn record<C>(wf: &mut types::WaveFront, blocks: &mut Vec<BacktraceBlock>, candidates: C)
+where
+    C: Fn(i32) -> [Option<Candidate>; 5],
+{
+    wf.backtrace.clear();
+
+    for k in wf.lo..=wf.hi {
+        let offset: i32 = wf.get_offset(k).unwrap().max();
+
+        let maybe_source: Option<Candidate> = candidates(k)
+            .into_iter()
+            .flatten()
+            .find(|(reached, ..)| offset >= 0 && *reached == offset);
+
+        let cell: BacktraceCell = match maybe_source {
+            Some((_, from, source, Some(op))) => {
+                blocks.push(BacktraceBlock {
+                    prev: source.block,
+                    matches: from - source.landed,
+                    op,
+                });
+                BacktraceCell {
+                    block: (blocks.len() - 1) as u32,
+                    landed: offset,
+                }
+            }
+            // the M cell is the end of the gap on the same diagonal
+            Some((_, _, source, None)) => source,
+            None => BacktraceCell {
+                block: NO_BLOCK,
+                landed: NULL_OFFSET,
+            },
+        };
+
+        wf.backtrace.push(cell);
+    }
+}
+
+/// Record how wf_next reached each cell of the wavefronts it computed from awf_set
+pub(crate) fn wf_backtrace_next(
+    awf_set: &AWFSet,
+    out_wf_set: &mut types::WaveFrontSet,
+    buffer: &mut BacktraceBuffer,
+    config: &types::Config,
+    bounds: (i32, i32),
+) {
+    let types::WaveFrontSet { m, i, d, i2, d2 } = out_wf_set;
+    let blocks: &mut Vec<BacktraceBlock> = &mut buffer.blocks;
+
+    // the gaps first, the M wavefront can end them
+    let gaps = [
+        (
+            &mut *i,
+            awf_set.in_m_gap,
+            awf_set.in_i_ext,
+            CigarOp::Insertion,
+        ),
+        (
+            &mut *d,
+            awf_set.in_m_gap,
+            awf_set.in_d_ext,
+            CigarOp::Deletion,
+        ),
+        (
+            &mut *i2,
+            awf_set.in_m_gap2,
+            awf_set.in_i2_ext,
+            CigarOp::Insertion,
+        ),
+        (
+            &mut *d2,
+            awf_set.in_m_gap2,
+            awf_set.in_d2_ext,
+            CigarOp::Deletion,
+        ),
+    ];
+    for (maybe_wf, in_m_gap, in_ext, op) in gaps {
+        let Some(wf) = maybe_wf.as_mut() else {
+            continue;
+        };
+
+        let (dk, delta): (i32, i32) = if op == CigarOp::Insertion {
+            (-1, 1)
+        } else {
+            (1, 0)
+        };
+        record(wf, blocks, |k| {
+            [
+                candidate(in_m_gap, (k + dk, k, delta), Some(op), bounds),
+                candidate(in_ext, (k + dk, k, delta), Some(op), bounds),
+                None,
+                None,
+                None,
+            ]
+        });
+    }
+
+    let Some(m_wf) = m.as_mut() else {
+        return;
+    };
+
+    let sub = |k: i32| -> Option<Candidate> {
+        candidate(awf_set.in_m_sub, (k, k, 1), Some(CigarOp::Mismatch), bounds)
+    };
+
+    // linear gaps go straight from M to M
+    if !config.distance.has_gap_wavefronts() {
+        record(m_wf, blocks, |k| {
+            [
+                sub(k),
+                candidate(
+                    awf_set.in_m_gap,
+                    (k - 1, k, 1),
+                    Some(CigarOp::Insertion),
+                    bounds,
+                ),
+                candidate(
+                    awf_set.in_m_gap,
+                    (k + 1, k, 0),
+                    Some(CigarOp::Deletion),
+                    bounds,
+                ),
+                None,
+                None,
+            ]
+        });
+        return;
+    }
+
+    let (i, d, i2, d2) = (i.as_ref(), d.as_ref(), i2.as_ref(), d2.as_ref());
+    record(m_wf, blocks, |k| {
+        [
+            sub(k),
+            candidate(i, (k, k, 0), None, bounds),
+            candidate(d, (k, k, 0), None, bounds),
+            candidate(i2, (k, k, 0), None, bounds),
+            candidate(d2, (k, k, 0), None, bounds),
+        ]
+    });
+}
+
+/// Drop the blocks that no cell of the kept wavefronts leads to once the
+/// buffer has doubled since the last time
+pub(crate) fn wf_backtrace_compact(wavefronts: &mut types::WaveFronts) {
+    let Some(buffer) = wavefronts.backtrace.as_mut() else {
+        return;
+    };
+
+    if buffer.blocks.len() < max!(buffer.compact_at, MIN_COMPACT_BLOCKS) {
+        return;
+    }
+
+    let blocks: &[BacktraceBlock] = &buffer.blocks;
+    let mut live: Vec<bool> = vec![false; blocks.len()];
+    for wf_set in wavefronts.wavefront_set.iter().flatten() {
+        for wf in wf_set.wavefronts() {
+            for cell in wf.backtrace.iter() {
+                let mut block: u32 = cell.block;
+                while block != NO_BLOCK && !live[block as usize] {
+                    live[block as usize] = true;
+                    block = blocks[block as usize].prev;
+                }
+            }
+        }
+    }
+
+    // a block always comes after the one before it on its path
+    let mut index: Vec<u32> = vec![NO_BLOCK; blocks.len()];
+    let mut kept: Vec<BacktraceBlock> = Vec::new();
+    for (old, block) in blocks.iter().enumerate() {
+        if !live[old] {
+            continue;
+        }
+
+        index[old] = kept.len() as u32;
+        kept.push(BacktraceBlock {
+            prev: if block.prev == NO_BLOCK {
+                NO_BLOCK
+            } else {
+                index[block.prev as usize]
+            },
+            ..*block
+        });
+    }
+
+    for wf_set in wavefronts.wavefront_set.iter_mut().flatten() {
+        for wf in wf_set.wavefronts_mut() {
+            for cell in wf.backtrace.iter_mut() {
+                if cell.block != NO_BLOCK {
+                    cell.block = index[cell.block as usize];
+                }
+            }
+        }
+    }
+
+    buffer.compact_at = 2 * kept.len();
+    buffer.blocks = kept;
+}
+
+/// Traceback of an alignment that ends on (k, offset) of the M wavefront
+/// with the given score, read from the backtrace blocks
+/// The unaligned ends of an ends-free alignment are insertions and deletions
+pub fn wf_traceback_piggyback<G>(
+    wavefronts: &types::WaveFronts,
+    score: usize,
+    (k, offset): (i32, i32),
+    config: &types::Config,
+    traceback_lambda: &mut G,
+) -> Result<Cigar, types::AlignError>
+where
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    let _span = tracing::debug_span!("wf_traceback_piggyback", score, k, offset).entered();
+
+    let inconsistent = types::AlignError::TracebackInconsistency {
+        score: score as i32,
+        k,
+        offset,
+    };
+
+    let blocks: &[BacktraceBlock] = &wavefronts
+        .backtrace
+        .as_ref()
+        .ok_or(inconsistent.clone())?
+        .blocks;
+    let cell: BacktraceCell = wavefronts
+        .get_m_wavefront(score as i32)
+        .filter(|m_wf| m_wf.in_bounds(k))
+        .map(|m_wf| m_wf.backtrace_cell(k))
+        .ok_or(inconsistent.clone())?;
+
+    let tlen: i32 = wavefronts.max_k as i32;
+    let qlen: i32 = -wavefronts.min_k as i32;
+
+    let mut ops: Vec<CigarOp> = Vec::new();
+
+    // the end that was left unaligned
+    let (v, h) = (utils::compute_v(offset, k), utils::compute_h(offset, k));
+    ops.extend(std::iter::repeat_n(CigarOp::Deletion, (qlen - v) as usize));
+    ops.extend(std::iter::repeat_n(CigarOp::Insertion, (tlen - h) as usize));
+
+    let push_matches = |ops: &mut Vec<CigarOp>, matches: i32| {
+        if matches < 0 {
+            return Err(inconsistent.clone());
+        }
+        ops.extend(std::iter::repeat_n(CigarOp::Match, matches as usize));
+        Ok(())
+    };
+
+    push_matches(&mut ops, offset - cell.landed)?;
+
+    // the blocks from the end of the path back to its start
+    let mut start_k: i32 = k;
+    let mut block: u32 = cell.block;
+    while block != NO_BLOCK {
+        let BacktraceBlock { prev, matches, op } = blocks[block as usize];
+
+        ops.push(op);
+        match op {
+            CigarOp::Insertion => start_k -= 1,
+            CigarOp::Deletion => start_k += 1,
+            _ => {}
+        }
+        push_matches(&mut ops, matches)?;
+
+        block = prev;
+    }
+
+    // and the start
+    match config.span {
+        types::AlignmentSpan::EndsFree { .. } => {
+            let (op, skipped) = if start_k > 0 {
+                (CigarOp::Insertion, start_k)
+            } else {
+                (CigarOp::Deletion, -start_k)
+            };
+            ops.extend(std::iter::repeat_n(op, skipped as usize));
+        }
+        _ if start_k != 0 => return Err(inconsistent),
+        _ => {}
+    }
+
+    let cigar: Cigar = ops.into_iter().rev().collect();
+    check_matches(&cigar, traceback_lambda)?;
+
+    Ok(cigar)
+}
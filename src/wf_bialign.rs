@@ -0,0 +1,572 @@
+/*!
+Bidirectional WFA (BiWFA), alignment in O(s) memory
+
+Wavefronts are computed from the start of the alignment (forward) and from
+its end (reverse) keeping only the last few scores of each. Where they
+overlap is a breakpoint that an optimal alignment goes through, the two
+halves on either side of it are then aligned the same way. Pieces whose
+score is small enough are aligned with all their wavefronts kept and traced
+back as usual.
+
+A piece starts and ends in a wavefront (M, I or D) so that a gap crossing a
+breakpoint only pays the gap open penalty in the piece where it was opened.
+The reverse wavefronts charge the gap open penalty where a gap ends instead,
+which is why joining on an I or D wavefront gives it back.
+ */
+use super::cigar::{Cigar, CigarOp};
+use super::types::{self, WfType};
+use super::utils;
+use super::wf_extend::wf_extend;
+use super::wf_next as core;
+use super::wf_traceback::{check_matches, wf_traceback_from};
+use fbox::macros::{max, min};
+
+const WF_TYPES: [WfType; 3] = [WfType::M, WfType::I, WfType::D];
+
+/// Pieces that can't score more than this are aligned without recursing
+pub const FALLBACK_SCORE: usize = 250;
+
+/// The text [t0, t1) and query [q0, q1) of the alignment and the wavefronts
+/// a path through them has to start and end in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Piece {
+    q0: i32,
+    t0: i32,
+    q1: i32,
+    t1: i32,
+    start: WfType,
+    end: WfType,
+}
+
+impl Piece {
+    fn qlen(&self) -> i32 {
+        self.q1 - self.q0
+    }
+
+    fn tlen(&self) -> i32 {
+        self.t1 - self.t0
+    }
+}
+
+/// Where the forward and the reverse wavefronts of a piece meet
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    /// score of the best alignment of the piece through the breakpoint
+    score: usize,
+
+    /// score of the forward wavefront, that of the first half
+    forward: usize,
+
+    wf_type: WfType,
+
+    // relative to the start of the piece
+    v: i32,
+    h: i32,
+}
+
+/// Run the match lambda of the whole alignment on a piece starting at (v0, h0)
+/// Stops at the end of the piece (qlen, tlen)
+fn shifted<'a, F>(
+    match_lambda: &'a mut F,
+    (v0, h0): (i32, i32),
+    (qlen, tlen): (i32, i32),
+) -> impl FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool + 'a
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    move |v: &mut i32, h: &mut i32, offsets: &mut types::Offset| -> bool {
+        // null offsets would otherwise be shifted into the piece
+        if *v < 0 || *h < 0 || *v >= qlen || *h >= tlen {
+            return false;
+        }
+
+        let (mut vv, mut hh) = (*v + v0, *h + h0);
+        let matched = match_lambda(&mut vv, &mut hh, offsets);
+        (*v, *h) = (vv - v0, hh - h0);
+
+        matched
+    }
+}
+
+fn extend<F>(
+    wavefronts: &mut types::WaveFronts,
+    score: usize,
+    match_lambda: &mut F,
+) -> Result<(), types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    let maybe_m_wf: Option<&mut types::WaveFront> = wavefronts
+        .get_mut(score)
+        .and_then(|wf_set| wf_set.m.as_mut());
+
+    match maybe_m_wf {
+        Some(m_wf) => wf_extend(m_wf, match_lambda, score),
+        None => Ok(()),
+    }
+}
+
+/// The furthest offset on diagonal k of the given wavefront
+fn furthest(wavefronts: &types::WaveFronts, score: usize, wf_type: WfType, k: i32) -> Option<i32> {
+    wavefronts
+        .option_get(score)
+        .and_then(|wf_set| wf_set.get(wf_type))
+        .and_then(|wf| wf.get_offset(k))
+        .map(|offsets| offsets.max())
+        .filter(|offset| *offset >= 0)
+}
+
+/// Giving up on a piece that got as far as reached from its start
+fn score_limit_exceeded(
+    piece: &Piece,
+    score: usize,
+    max_score: usize,
+    (v, h): (i32, i32),
+) -> types::AlignError {
+    types::AlignError::ScoreLimitExceeded {
+        score,
+        max_score,
+        query_end: (piece.q0 + v) as u32,
+        text_end: (piece.t0 + h) as u32,
+        partial: None,
+    }
+}
+
+/// Align a piece keeping every wavefront, ops are appended to ops
+fn wf_align_piece<F>(
+    piece: &Piece,
+    config: &types::Config,
+    match_lambda: &mut F,
+    max_score: usize,
+    ops: &mut Vec<CigarOp>,
+) -> Result<usize, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    let (tlen, qlen) = (piece.tlen(), piece.qlen());
+
+    let mut wavefronts = types::WaveFronts::new(tlen as u32, qlen as u32);
+    if piece.start != WfType::M {
+        wavefronts.seed(0, piece.start, 0, 0);
+    }
+
+    let a_k: i32 = wavefronts.a_k;
+    let mut score: usize = 0;
+    let mut reached: (i32, i32) = (0, 0);
+
+    loop {
+        if score > 0 {
+            core::wf_next(&mut wavefronts, score, config)?;
+        }
+        extend(&mut wavefronts, score, match_lambda)?;
+
+        if furthest(&wavefronts, score, piece.end, a_k) == Some(tlen) {
+            break;
+        }
+
+        // give up
+        reached = utils::furthest_reached(wavefronts.get_m_wavefront(score as i32), reached);
+        if score >= max_score {
+            return Err(score_limit_exceeded(piece, score, max_score, reached));
+        }
+
+        score += 1;
+    }
+
+    let is_start = |s: i32, wf_type: WfType, k: i32, offset: i32| -> bool {
+        s == 0 && k == 0 && offset == 0 && (wf_type == WfType::M || wf_type == piece.start)
+    };
+
+    let mut piece_ops: Vec<CigarOp> = Vec::new();
+    wf_traceback_from(
+        &wavefronts,
+        config,
+        (score as i32, a_k, tlen, piece.end),
+        is_start,
+        &mut piece_ops,
+    )?;
+    ops.extend(piece_ops.into_iter().rev());
+
+    Ok(score)
+}
+
+/// The best join of the forward wavefronts at score sf and the reverse ones at sr
+fn overlap(
+    forward: &types::WaveFronts,
+    sf: usize,
+    reverse: &types::WaveFronts,
+    sr: usize,
+    config: &types::Config,
+) -> Option<Breakpoint> {
+    let tlen: i32 = forward.max_k as i32;
+    let a_k: i32 = forward.a_k;
+    let o = config.penalties.gap_open as usize;
+
+    let mut best: Option<Breakpoint> = None;
+
+    for wf_type in WF_TYPES {
+        let fwd_wf = forward
+            .option_get(sf)
+            .and_then(|wf_set| wf_set.get(wf_type));
+        let rev_wf = reverse
+            .option_get(sr)
+            .and_then(|wf_set| wf_set.get(wf_type));
+        let (fwd_wf, rev_wf) = match (fwd_wf, rev_wf) {
+            (Some(fwd_wf), Some(rev_wf)) => (fwd_wf, rev_wf),
+            _ => continue,
+        };
+
+        // both ends of a gap paid for opening it
+        let score = match wf_type {
+            WfType::M => sf + sr,
+            _ => sf + sr - o,
+        };
+
+        if best.is_some_and(|bp| bp.score <= score) {
+            continue;
+        }
+
+        // diagonal k forward is diagonal a_k - k in reverse
+        let lo = max!(fwd_wf.lo, a_k - rev_wf.hi);
+        let hi = min!(fwd_wf.hi, a_k - rev_wf.lo);
+
+        for k in lo..=hi {
+            let f: i32 = fwd_wf.get_offset(k).unwrap().max();
+            let r: i32 = rev_wf.get_offset(a_k - k).unwrap().max();
+
+            if f >= 0 && r >= 0 && f + r >= tlen {
+                best = Some(Breakpoint {
+                    score,
+                    forward: sf,
+                    wf_type,
+                    v: f - k,
+                    h: f,
+                });
+                break;
+            }
+        }
+    }
+
+    best
+}
+
+/// Compute the forward and reverse wavefronts of a piece a score at a time
+/// until no later overlap can beat the best one found
+fn find_breakpoint<F, R>(
+    piece: &Piece,
+    config: &types::Config,
+    match_lambda: &mut F,
+    reverse_match_lambda: &mut R,
+    max_score: usize,
+) -> Result<Breakpoint, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    R: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    let x = config.penalties.mismatch as usize;
+    let o = config.penalties.gap_open as usize;
+    let e = config.penalties.gap_extend as usize;
+
+    // wf_next looks back at most span scores, keep a couple more to find
+    // overlaps between scores computed at different times
+    let span: usize = max!(x, o + e);
+    let keep: usize = span + 2;
+
+    // an optimal path has a point where the forward and reverse scores are
+    // within span of each other, it has been checked by the time the scores
+    // add up to this much more than its score
+    let margin: usize = o + span + 2;
+
+    let (tlen, qlen) = (piece.tlen() as u32, piece.qlen() as u32);
+
+    let mut forward = types::WaveFronts::new(tlen, qlen);
+    if piece.start != WfType::M {
+        forward.seed(0, piece.start, 0, 0);
+    }
+
+    // the gap that ends the piece is opened by the reverse wavefronts
+    let mut reverse = types::WaveFronts::new(tlen, qlen);
+    if piece.end != WfType::M {
+        reverse.discard(0);
+        reverse.seed(o, piece.end, 0, 0);
+        reverse.seed(o, WfType::M, 0, 0);
+    }
+
+    extend(&mut forward, 0, match_lambda)?;
+    extend(&mut reverse, 0, reverse_match_lambda)?;
+
+    let mut best: Option<Breakpoint> = overlap(&forward, 0, &reverse, 0, config);
+    let (mut sf, mut sr): (usize, usize) = (0, 0);
+
+    // how far the forward wavefronts got, the reverse ones don't start at the start
+    let mut reached: (i32, i32) = utils::furthest_reached(forward.get_m_wavefront(0), (0, 0));
+
+    loop {
+        match best {
+            Some(bp) if sf + sr >= bp.score + margin => return Ok(bp),
+            None if sf + sr >= max_score + margin => {
+                return Err(score_limit_exceeded(
+                    piece,
+                    max!(sf, sr),
+                    max_score,
+                    reached,
+                ))
+            }
+            _ => {}
+        }
+
+        let better = |bp: Option<Breakpoint>, best: Option<Breakpoint>| match (bp, best) {
+            (Some(bp), Some(best)) if bp.score < best.score => Some(bp),
+            (Some(bp), None) => Some(bp),
+            (_, best) => best,
+        };
+
+        sf += 1;
+        core::wf_next(&mut forward, sf, config)?;
+        extend(&mut forward, sf, match_lambda)?;
+        reached = utils::furthest_reached(forward.get_m_wavefront(sf as i32), reached);
+        if sf > keep {
+            forward.discard(sf - keep - 1);
+        }
+
+        for s in sr.saturating_sub(keep)..=sr {
+            best = better(overlap(&forward, sf, &reverse, s, config), best);
+        }
+
+        sr += 1;
+        core::wf_next(&mut reverse, sr, config)?;
+        extend(&mut reverse, sr, reverse_match_lambda)?;
+        if sr > keep {
+            reverse.discard(sr - keep - 1);
+        }
+
+        for s in sf.saturating_sub(keep)..=sf {
+            best = better(overlap(&forward, s, &reverse, sr, config), best);
+        }
+    }
+}
+
+/// Align a piece by splitting it at a breakpoint, ops are appended to ops
+#[allow(clippy::too_many_arguments)]
+fn wf_bialign_piece<F, R>(
+    piece: &Piece,
+    (qlen, tlen): (i32, i32),
+    config: &types::Config,
+    match_lambda: &mut F,
+    reverse_match_lambda: &mut R,
+    max_score: usize,
+    fallback_score: usize,
+    ops: &mut Vec<CigarOp>,
+) -> Result<usize, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    R: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+{
+    let piece_len = (piece.qlen(), piece.tlen());
+
+    if max_score <= fallback_score {
+        let mut forward_lambda = shifted(match_lambda, (piece.q0, piece.t0), piece_len);
+        return wf_align_piece(piece, config, &mut forward_lambda, max_score, ops);
+    }
+
+    let bp: Breakpoint = {
+        let mut forward_lambda = shifted(&mut *match_lambda, (piece.q0, piece.t0), piece_len);
+
+        // the reverse lambda reads both sequences from their ends
+        let mut reverse_lambda = shifted(
+            &mut *reverse_match_lambda,
+            (qlen - piece.q1, tlen - piece.t1),
+            piece_len,
+        );
+
+        find_breakpoint(
+            piece,
+            config,
+            &mut forward_lambda,
+            &mut reverse_lambda,
+            max_score,
+        )?
+    };
+
+    tracing::debug!(?piece, breakpoint = ?bp, "split");
+
+    let left = Piece {
+        q1: piece.q0 + bp.v,
+        t1: piece.t0 + bp.h,
+        end: bp.wf_type,
+        ..*piece
+    };
+
+    let right = Piece {
+        q0: piece.q0 + bp.v,
+        t0: piece.t0 + bp.h,
+        start: bp.wf_type,
+        ..*piece
+    };
+
+    // a breakpoint at either end of the piece doesn't split it
+    if left == *piece || right == *piece {
+        let mut forward_lambda = shifted(match_lambda, (piece.q0, piece.t0), piece_len);
+        return wf_align_piece(piece, config, &mut forward_lambda, bp.score, ops);
+    }
+
+    for (half, half_score) in [(left, bp.forward), (right, bp.score - bp.forward)] {
+        wf_bialign_piece(
+            &half,
+            (qlen, tlen),
+            config,
+            match_lambda,
+            reverse_match_lambda,
+            half_score,
+            fallback_score,
+            ops,
+        )?;
+    }
+
+    Ok(bp.score)
+}
+
+/// BiWFA, pieces that can't score more than fallback_score are aligned
+/// keeping all their wavefronts
+pub fn wf_bialign<F, R, G>(
+    tlen: u32,
+    qlen: u32,
+    config: &types::Config,
+    match_lambda: &mut F,
+    reverse_match_lambda: &mut R,
+    traceback_lambda: &mut G,
+    fallback_score: usize,
+) -> Result<types::Alignment, types::AlignError>
+where
+    F: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    R: FnMut(&mut i32, &mut i32, &mut types::Offset) -> bool,
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    let _span = tracing::debug_span!("wf_bialign", tlen, qlen).entered();
+
+    let (qlen, tlen) = (qlen as i32, tlen as i32);
+    let piece = Piece {
+        q0: 0,
+        t0: 0,
+        q1: qlen,
+        t1: tlen,
+        start: WfType::M,
+        end: WfType::M,
+    };
+
+    let max_possible_score: usize = config.score_limit(tlen as u32, qlen as u32);
+
+    let mut ops: Vec<CigarOp> = Vec::new();
+    let score = wf_bialign_piece(
+        &piece,
+        (qlen, tlen),
+        config,
+        match_lambda,
+        reverse_match_lambda,
+        max_possible_score,
+        fallback_score,
+        &mut ops,
+    )?;
+
+    // the match lambdas never see a whole run of matches
+    let cigar: Cigar = ops.into_iter().collect();
+    check_matches(&cigar, traceback_lambda)?;
+
+    Ok(types::Alignment {
+        score: score as i32,
+        cigar,
+        segments: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::degenerate;
+
+    fn test_config() -> types::Config {
+        types::Config {
+            memory_mode: types::MemoryMode::Ultralow,
+            ..Default::default()
+        }
+    }
+
+    /// Affine score of a cigar with the penalties of the config
+    fn cigar_score(cigar: &Cigar, config: &types::Config) -> i32 {
+        let p = &config.penalties;
+
+        cigar
+            .runs()
+            .iter()
+            .map(|&(op, len)| match op {
+                CigarOp::Match | CigarOp::SoftClip => 0,
+                CigarOp::Mismatch => p.mismatch * len as i32,
+                CigarOp::Insertion | CigarOp::Deletion => p.gap_open + p.gap_extend * len as i32,
+            })
+            .sum::<i32>()
+    }
+
+    #[test]
+    fn test_recursion() {
+        let config = test_config();
+
+        let text = b"GATTACAGATTACAGGGTTTCCCAAATTTGACGTACGTTAGCATGCATCGATCGACT";
+        let query = b"GATTACAGTTACAGGGTTTCCAAATTTTGACGTAGGTTAGCAGCATCGATCCGACT";
+
+        let rev_text: Vec<u8> = text.iter().rev().cloned().collect();
+        let rev_query: Vec<u8> = query.iter().rev().cloned().collect();
+
+        let align = |fallback_score: usize| -> types::Alignment {
+            let mut match_lambda = degenerate::match_lambda(&text[..], query);
+            let mut reverse_match_lambda = degenerate::match_lambda(&rev_text[..], &rev_query);
+            let mut traceback_lambda = degenerate::traceback_lambda(&text[..], query);
+
+            wf_bialign(
+                text.len() as u32,
+                query.len() as u32,
+                &config,
+                &mut match_lambda,
+                &mut reverse_match_lambda,
+                &mut traceback_lambda,
+                fallback_score,
+            )
+            .unwrap()
+        };
+
+        let whole = align(FALLBACK_SCORE);
+
+        // split all the way down to pieces with no edits
+        for fallback_score in [0, 10] {
+            let split = align(fallback_score);
+
+            assert_eq!(split.score, whole.score);
+            assert_eq!(cigar_score(&split.cigar, &config), split.score);
+            assert_eq!(split.cigar.text_len(), text.len());
+            assert_eq!(split.cigar.query_len(), query.len());
+        }
+
+        // a gap across the breakpoint only pays the gap open penalty once
+        let text = b"ACGTACGTACGTAAAAAAAAAAAAAAAAAAAACGTACGTACGT";
+        let query = b"ACGTACGTACGTACGTACGTACGT";
+        let rev_text: Vec<u8> = text.iter().rev().cloned().collect();
+        let rev_query: Vec<u8> = query.iter().rev().cloned().collect();
+
+        let mut match_lambda = degenerate::match_lambda(&text[..], query);
+        let mut reverse_match_lambda = degenerate::match_lambda(&rev_text[..], &rev_query);
+        let mut traceback_lambda = degenerate::traceback_lambda(&text[..], query);
+
+        let types::Alignment { score, cigar, .. } = wf_bialign(
+            text.len() as u32,
+            query.len() as u32,
+            &config,
+            &mut match_lambda,
+            &mut reverse_match_lambda,
+            &mut traceback_lambda,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(score, 6 + 2 * 19);
+        assert_eq!(cigar_score(&cigar, &config), score);
+    }
+}
@@ -0,0 +1,1220 @@
+/*!
+WFA over an elastic degenerate text
+
+Each alternative of each segment has its own wavefronts which are all
+computed one score at a time. A cell that reaches the end of an alternative
+is a path that crosses into the next segment, it is recorded at the
+boundary and seeded into every alternative of the next segment at the same
+score. Gaps keep their state (I or D) across the boundary so a gap spanning
+segments only pays the gap open penalty once.
+
+A path stays on one alternative from the start of a segment to its end so
+the alignment is optimal over every string the text spells and its cigar
+spells the alternatives it reports.
+ */
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use super::align::{score_window, user_score, without_bonus};
+use super::cigar::CigarOp;
+use super::degenerate::{self, DegenerateText};
+use super::types::{self, WfType};
+use super::utils;
+use super::wf_adapt::wf_reduce;
+use super::wf_extend::wf_extend;
+use super::wf_next as core;
+use super::wf_traceback::wf_traceback_from;
+use fbox::macros::max;
+
+const WF_TYPES: [WfType; 5] = [WfType::M, WfType::I, WfType::D, WfType::I2, WfType::D2];
+
+fn wf_type_index(wf_type: WfType) -> usize {
+    match wf_type {
+        WfType::M => 0,
+        WfType::I => 1,
+        WfType::D => 2,
+        WfType::I2 => 3,
+        WfType::D2 => 4,
+    }
+}
+
+/// Reaching a boundary at some score
+#[derive(Debug, Clone)]
+struct Arrival {
+    score: usize,
+
+    /// the alternatives of the previous segment that got here at this score,
+    /// none for a path that starts here
+    alternatives: Vec<usize>,
+}
+
+/// The start (or end) of a segment
+#[derive(Debug, Default)]
+struct Boundary {
+    /// indexed by v and then the wavefront, holds the first arrival. Only the
+    /// cells a path reached are kept
+    cells: BTreeMap<i32, [Option<Arrival>; 5]>,
+
+    /// (score, v, wavefront) in the order they arrived
+    arrivals: Vec<(usize, i32, WfType)>,
+
+    /// arrivals before this index have been seeded into the next segment
+    seeded: usize,
+}
+
+impl Boundary {
+    fn get(&self, v: i32, wf_type: WfType) -> Option<&Arrival> {
+        self.cells
+            .get(&v)
+            .and_then(|cell| cell[wf_type_index(wf_type)].as_ref())
+    }
+
+    /// The arrivals that haven't been seeded into the next segment yet
+    fn unseeded(&self) -> &[(usize, i32, WfType)] {
+        &self.arrivals[self.seeded..]
+    }
+
+    /// Only the first score to reach a cell is kept, later ones can't be optimal
+    fn arrive(&mut self, score: usize, v: i32, wf_type: WfType, alternative: Option<usize>) {
+        let cell: &mut Option<Arrival> =
+            &mut self.cells.entry(v).or_default()[wf_type_index(wf_type)];
+
+        match cell {
+            None => {
+                *cell = Some(Arrival {
+                    score,
+                    alternatives: alternative.into_iter().collect(),
+                });
+                self.arrivals.push((score, v, wf_type));
+            }
+            Some(arrival) if arrival.score == score => {
+                if let Some(alternative) = alternative {
+                    if !arrival.alternatives.contains(&alternative) {
+                        arrival.alternatives.push(alternative);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// For each wavefront, the furthest offset along the text (and its score)
+/// a cell that doesn't depend on the alternative a path took reached on a
+/// diagonal
+type Furthest = [Option<(i32, usize)>; 5];
+
+/// [Furthest] of each diagonal from the lowest one a cell can still be on,
+/// a diagonal is counted from the start of the text plus qlen
+#[derive(Debug, Default)]
+struct FurthestTable {
+    /// the diagonal of the first entry
+    base: usize,
+    entries: VecDeque<Furthest>,
+}
+
+impl FurthestTable {
+    fn get(&self, diagonal: usize) -> Option<&Furthest> {
+        self.entries.get(diagonal.checked_sub(self.base)?)
+    }
+
+    /// The entry of the diagonal, the table grows to cover it
+    fn get_mut(&mut self, diagonal: usize) -> &mut Furthest {
+        if self.entries.is_empty() {
+            self.base = diagonal;
+        }
+
+        while diagonal < self.base {
+            self.entries.push_front(Default::default());
+            self.base -= 1;
+        }
+
+        let index: usize = diagonal - self.base;
+        if index >= self.entries.len() {
+            self.entries.resize(index + 1, Default::default());
+        }
+
+        &mut self.entries[index]
+    }
+
+    /// Drop the entries of the diagonals below the given one
+    fn forget_below(&mut self, diagonal: usize) {
+        let count: usize = cmp::min(diagonal.saturating_sub(self.base), self.entries.len());
+        self.entries.drain(..count);
+        self.base += count;
+    }
+}
+
+/// A path traced back to where it started
+struct Traced {
+    /// in reverse
+    ops: Vec<CigarOp>,
+
+    /// the alternatives of the segments with more than one, in reverse
+    chosen: Vec<types::SegmentAlternatives>,
+
+    /// the segment it starts in and where in the alternative
+    start: (usize, i32),
+}
+
+/// The wavefronts of every alternative of every segment and the boundaries
+/// between the segments
+///
+/// A position of an alternative is a character or, for the runs between the
+/// segments of a degenerate text, the characters of a degenerate position
+///
+/// Only the segments a path reached have wavefronts and only the active ones
+/// are computed, so the memory and time go with the band of segments the
+/// paths are in rather than with the whole text.
+struct ElasticWaveFronts<'a, 'p, P> {
+    segments: &'a [Vec<Vec<P>>],
+    query: &'a [u8],
+
+    /// the wavefronts of each alternative, empty until a path reaches the segment
+    all_wavefronts: Vec<Vec<types::WaveFronts>>,
+    boundaries: Vec<Boundary>,
+
+    /// the segments the next score is computed for: those a path just
+    /// reached and those with a cell in the wavefronts of the last scores
+    active: BTreeSet<usize>,
+
+    /// the last score each segment had a cell with
+    last_live: Vec<usize>,
+
+    /// keep only the wavefronts of the last window scores when there is one
+    window: Option<usize>,
+
+    /// wavefronts of earlier alignments handed out to the alternatives, those
+    /// of this one go back to it
+    pool: &'p mut Vec<types::WaveFronts>,
+
+    /// where each segment starts on the shortest path through the text
+    starts: Vec<usize>,
+
+    /// shortest text left after each segment, for wf_reduce
+    remaining: Vec<usize>,
+
+    /// paths can start on any character of the text, not only at its start
+    begin_free: bool,
+
+    /// see [Furthest]. Only kept when every alternative of a segment has the
+    /// same length, otherwise diagonals don't line up across segments
+    furthest: Option<FurthestTable>,
+}
+
+impl<'a, 'p, P> ElasticWaveFronts<'a, 'p, P>
+where
+    [P]: DegenerateText,
+{
+    /// Keep only the wavefronts of the last window scores when there is one
+    fn new(
+        segments: &'a [Vec<Vec<P>>],
+        query: &'a [u8],
+        window: Option<usize>,
+        begin_free: bool,
+        pool: &'p mut Vec<types::WaveFronts>,
+    ) -> Self {
+        let segment_count: usize = segments.len();
+
+        let shortest = |segment_index: usize| -> usize {
+            segments[segment_index].iter().map(Vec::len).min().unwrap()
+        };
+
+        let mut starts: Vec<usize> = vec![0; segment_count + 1];
+        for segment_index in 0..segment_count {
+            starts[segment_index + 1] = starts[segment_index] + shortest(segment_index);
+        }
+
+        let mut remaining: Vec<usize> = vec![0; segment_count];
+        for segment_index in (0..segment_count.saturating_sub(1)).rev() {
+            remaining[segment_index] = remaining[segment_index + 1] + shortest(segment_index + 1);
+        }
+
+        let mut boundaries: Vec<Boundary> =
+            (0..=segment_count).map(|_| Boundary::default()).collect();
+        let start_count: usize = if begin_free { segment_count } else { 1 };
+        for boundary in boundaries.iter_mut().take(start_count) {
+            boundary.arrive(0, 0, WfType::M, None);
+        }
+
+        let inelastic: bool = segments
+            .iter()
+            .all(|alts| alts.iter().all(|alt| alt.len() == alts[0].len()));
+
+        Self {
+            segments,
+            query,
+            all_wavefronts: (0..segment_count).map(|_| Vec::new()).collect(),
+            boundaries,
+            active: (0..start_count).collect(),
+            last_live: vec![0; segment_count],
+            window,
+            pool,
+            starts,
+            remaining,
+            begin_free,
+            furthest: inelastic.then(FurthestTable::default),
+        }
+    }
+
+    /// Wavefronts for the alternatives of a segment a path reaches with this
+    /// score, unless it already has them
+    fn reach(&mut self, segment_index: usize, score: usize) {
+        if !self.all_wavefronts[segment_index].is_empty() {
+            return;
+        }
+
+        let qlen = self.query.len() as u32;
+        let (window, pool) = (self.window, &mut *self.pool);
+        self.all_wavefronts[segment_index] = self.segments[segment_index]
+            .iter()
+            .map(|alt| {
+                let tlen = alt.len() as u32;
+                let mut wavefronts: types::WaveFronts = match window {
+                    Some(window) => types::WaveFronts::windowed(tlen, qlen, window),
+                    None => match pool.pop() {
+                        Some(mut wavefronts) => {
+                            wavefronts.reset(tlen, qlen);
+                            wavefronts
+                        }
+                        None => types::WaveFronts::new(tlen, qlen),
+                    },
+                };
+
+                // paths only start from seeds so drop the initial wavefront
+                wavefronts.start_at(score);
+                wavefronts
+            })
+            .collect();
+    }
+
+    /// Stop computing a segment, when only the last scores are kept its
+    /// wavefronts are dropped and a path that reaches it later starts anew
+    fn retire(&mut self, segment_index: usize) {
+        self.active.remove(&segment_index);
+        if self.window.is_some() {
+            self.all_wavefronts[segment_index] = Vec::new();
+        }
+    }
+
+    /// Length of the shortest path through the text
+    fn tlen(&self) -> usize {
+        self.starts[self.segments.len()]
+    }
+
+    /// Compute the wavefronts of every alternative of the active segments
+    /// with this score, extend them and pass the paths that reach the end of
+    /// an alternative on to the next segment
+    fn compute(
+        &mut self,
+        score: usize,
+        config: &types::Config,
+        reached: &mut (i32, i32),
+    ) -> Result<(), types::AlignError> {
+        let qlen: usize = self.query.len();
+        let window: usize = score_window(config);
+
+        // a segment reached at this score is computed after the one before it
+        let mut next: usize = 0;
+        while let Some(&segment_index) = self.active.range(next..).next() {
+            next = segment_index + 1;
+
+            if !self.boundaries[segment_index].unseeded().is_empty() {
+                self.reach(segment_index, score);
+                self.last_live[segment_index] = score;
+            } else if self.last_live[segment_index] + window <= score {
+                // no wavefront this score is computed from has a cell
+                self.retire(segment_index);
+                continue;
+            }
+
+            let (before, after) = self.boundaries.split_at_mut(segment_index + 1);
+            let incoming: &mut Boundary = &mut before[segment_index];
+            let outgoing: &mut Boundary = &mut after[0];
+            let arrived: usize = outgoing.arrivals.len();
+            let start = self.starts[segment_index] as i32;
+
+            for (alt_index, alt) in self.segments[segment_index].iter().enumerate() {
+                let wavefronts: &mut types::WaveFronts =
+                    &mut self.all_wavefronts[segment_index][alt_index];
+
+                core::wf_next(wavefronts, score, config)?;
+
+                // a gap that ends a segment can also be closed at the start of the next
+                for &(_, v, wf_type) in incoming.unseeded().iter() {
+                    wavefronts.seed(score, wf_type, -v, 0);
+                    if wf_type != WfType::M {
+                        wavefronts.seed(score, WfType::M, -v, 0);
+                    }
+                }
+
+                // the starts inside the alternative, those at its start are seeded above
+                if self.begin_free && score == 0 {
+                    for h in 1..alt.len() as i32 {
+                        wavefronts.seed(0, WfType::M, h, h);
+                    }
+                }
+
+                let maybe_m_wf: Option<&mut types::WaveFront> = wavefronts
+                    .get_mut(score)
+                    .and_then(|wf_set| wf_set.m.as_mut());
+
+                if let Some(m_wf) = maybe_m_wf {
+                    let mut match_lambda = degenerate::match_lambda(alt.as_slice(), self.query);
+                    wf_extend(m_wf, &mut match_lambda, score)?;
+
+                    let (v, h) =
+                        utils::furthest_reached(Some(m_wf), (reached.0, reached.1 - start));
+                    *reached = (v, h + start);
+                }
+
+                // cells at the end of the alternative cross into the next segment
+                let alt_len = alt.len() as i32;
+                let last: bool = segment_index + 1 == self.segments.len();
+                if let Some(wf_set) = wavefronts.get_mut(score) {
+                    for wf_type in WF_TYPES {
+                        let Some(wf) = wf_set.get_mut(wf_type).as_mut() else {
+                            continue;
+                        };
+
+                        for k in wf.lo..=wf.hi {
+                            let v = alt_len - k;
+                            let offset: &mut types::Offset = wf.get_offset_mut(k).unwrap();
+                            if offset.max() != alt_len || v < 0 || v as usize > qlen {
+                                continue;
+                            }
+
+                            outgoing.arrive(score, v, wf_type, Some(alt_index));
+
+                            // the path carries on from its seed in the next segment, only
+                            // gaps in the query would be computed from here and they are
+                            // computed there too. The traceback never reads these cells
+                            if !last && !matches!(wf_type, WfType::I | WfType::I2) {
+                                *offset = types::Offset::null();
+                            }
+                        }
+                    }
+                }
+
+                if config.adapt {
+                    let tlen = alt_len + self.remaining[segment_index] as i32;
+                    wf_reduce(wavefronts, score, tlen, qlen as i32, config);
+                }
+            }
+
+            incoming.seeded = incoming.arrivals.len();
+            if outgoing.arrivals.len() > arrived && next < self.segments.len() {
+                self.active.insert(next);
+            }
+        }
+
+        self.prune(score);
+
+        for &segment_index in self.active.iter() {
+            let live: bool = self.all_wavefronts[segment_index].iter().any(|wavefronts| {
+                wavefronts.option_get(score).is_some_and(|wf_set| {
+                    wf_set.wavefronts().any(|wf| {
+                        (wf.lo..=wf.hi)
+                            .any(|k| wf.get_offset(k).is_some_and(|offset| offset.max() >= 0))
+                    })
+                })
+            });
+            if live {
+                self.last_live[segment_index] = score;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop the cells of this score that are behind a cell on the same
+    /// diagonal that got at least as far with no more score and whose way
+    /// on doesn't depend on the alternative a path took: a cell at the start
+    /// of a segment or in a segment with one alternative. Whatever follows
+    /// the cell behind follows from there too at no more cost.
+    fn prune(&mut self, score: usize) {
+        let Some(furthest) = self.furthest.as_mut() else {
+            return;
+        };
+        let qlen = self.query.len() as i32;
+
+        // the diagonal and offset along the text of each cell of this score
+        fn cells(
+            wf: &types::WaveFront,
+            start: i32,
+            qlen: i32,
+        ) -> impl Iterator<Item = (i32, i32, usize)> + '_ {
+            (wf.lo..=wf.hi).filter_map(move |k| {
+                let offset: i32 = wf.get_offset(k)?.max();
+                let v = offset - k;
+                let diagonal = (start + k + qlen) as usize;
+                (offset >= 0 && (0..=qlen).contains(&v)).then_some((k, offset, diagonal))
+            })
+        }
+
+        for &segment_index in self.active.iter() {
+            let alts: &[types::WaveFronts] = &self.all_wavefronts[segment_index];
+            let start = self.starts[segment_index] as i32;
+            for wavefronts in alts {
+                let Some(wf_set) = wavefronts.option_get(score) else {
+                    continue;
+                };
+                for (index, &wf_type) in WF_TYPES.iter().enumerate() {
+                    let Some(wf) = wf_set.get(wf_type) else {
+                        continue;
+                    };
+                    for (_, offset, diagonal) in cells(wf, start, qlen) {
+                        if alts.len() > 1 && offset != 0 {
+                            continue;
+                        }
+                        let cell = &mut furthest.get_mut(diagonal)[index];
+                        if cell.is_none_or(|(h, _)| start + offset > h) {
+                            *cell = Some((start + offset, score));
+                        }
+                    }
+                }
+            }
+        }
+
+        for &segment_index in self.active.iter() {
+            let start = self.starts[segment_index] as i32;
+            let outgoing: &Boundary = &self.boundaries[segment_index + 1];
+            let alts: &mut [types::WaveFronts] = &mut self.all_wavefronts[segment_index];
+            for (alt_index, wavefronts) in alts.iter_mut().enumerate() {
+                let Some(wf_set) = wavefronts.get_mut(score) else {
+                    continue;
+                };
+
+                // an M cell may have closed a gap of this score on its diagonal and
+                // its traceback goes through that gap cell, so the gaps behind the M
+                // cells that stay or that crossed into the next segment stay too
+                let alt_len = self.segments[segment_index][alt_index].len() as i32;
+                let mut crossed: Vec<i32> = outgoing
+                    .arrivals
+                    .iter()
+                    .rev()
+                    .take_while(|(s, _, _)| *s == score)
+                    .filter(|&&(_, v, wf_type)| {
+                        wf_type == WfType::M
+                            && outgoing
+                                .get(v, wf_type)
+                                .is_some_and(|arrival| arrival.alternatives.contains(&alt_index))
+                    })
+                    .map(|(_, v, _)| alt_len - v)
+                    .collect();
+                crossed.sort_unstable();
+                let mut reached_m: Vec<i32> = Vec::new();
+                for (index, &wf_type) in WF_TYPES.iter().enumerate() {
+                    let maybe_wf: &mut Option<types::WaveFront> = wf_set.get_mut(wf_type);
+                    let Some(wf) = maybe_wf.as_mut() else {
+                        continue;
+                    };
+                    let behind: Vec<i32> = cells(wf, start, qlen)
+                        .filter(|&(k, offset, diagonal)| {
+                            furthest
+                                .get(diagonal)
+                                .and_then(|cell| cell[index])
+                                .is_some_and(|(h, s)| {
+                                    start + offset < h || (start + offset == h && s < score)
+                                })
+                                && reached_m.binary_search(&k).is_err()
+                                && crossed.binary_search(&k).is_err()
+                        })
+                        .map(|(k, _, _)| k)
+                        .collect();
+                    for k in behind {
+                        *wf.get_offset_mut(k).unwrap() = types::Offset::null();
+                    }
+
+                    // the next wavefronts span the diagonals of this one
+                    let reached: Vec<i32> = (wf.lo..=wf.hi)
+                        .filter(|&k| wf.get_offset(k).is_some_and(|offset| offset.max() >= 0))
+                        .collect();
+                    match (reached.first(), reached.last()) {
+                        (Some(&lo), Some(&hi)) => wf.trim(hi, lo),
+                        _ => *maybe_wf = None,
+                    }
+                    if wf_type == WfType::M {
+                        reached_m = reached;
+                    }
+                }
+            }
+        }
+
+        // no cell of a segment before the first active one is computed again
+        if let Some(&first) = self.active.first() {
+            furthest.forget_below(self.starts[first]);
+        }
+    }
+
+    /// The furthest offset on the diagonal the alignment ends on, counted
+    /// along the shortest path, see [crate::progress]
+    fn central_offset(&self, score: usize) -> Option<i32> {
+        let a_k: i32 = self.tlen() as i32 - self.query.len() as i32;
+
+        self.active
+            .iter()
+            .flat_map(|&segment_index| {
+                let start = self.starts[segment_index] as i32;
+                let alts: &[types::WaveFronts] = &self.all_wavefronts[segment_index];
+                alts.iter().filter_map(move |wavefronts| {
+                    let offset: i32 = wavefronts
+                        .get_m_wavefront(score as i32)?
+                        .get_offset(a_k - start)?
+                        .max();
+                    (offset >= 0).then_some(start + offset)
+                })
+            })
+            .max()
+    }
+
+    /// Follow the path back from (score, k, offset, wavefront) on an
+    /// alternative of a segment through the segments before it
+    fn traceback(
+        &self,
+        config: &types::Config,
+        (mut segment_index, mut alternatives): (usize, Vec<usize>),
+        (mut s, mut k, mut offset, mut wf_type): (i32, i32, i32, WfType),
+    ) -> Result<Traced, types::AlignError> {
+        let mut ops: Vec<CigarOp> = Vec::new();
+        let mut chosen: Vec<types::SegmentAlternatives> = Vec::new();
+
+        loop {
+            // the cigar follows the first of the alternatives that tie
+            let alt_index: usize = alternatives[0];
+            if self.segments[segment_index].len() > 1 {
+                chosen.push(types::SegmentAlternatives {
+                    segment: segment_index,
+                    alternatives,
+                });
+            }
+
+            // paths through the alternative start from the seeds at the incoming boundary
+            let incoming: &Boundary = &self.boundaries[segment_index];
+            let begin_free: bool = self.begin_free;
+            let is_start = |s: i32, wf_type: WfType, k: i32, offset: i32| -> bool {
+                let seeded = offset == 0
+                    && incoming
+                        .get(-k, wf_type)
+                        .is_some_and(|arrival| arrival.score as i32 == s);
+                let inside = begin_free && s == 0 && wf_type == WfType::M && k > 0 && offset == k;
+                seeded || inside
+            };
+
+            (s, k, wf_type) = wf_traceback_from(
+                &self.all_wavefronts[segment_index][alt_index],
+                config,
+                (s, k, offset, wf_type),
+                is_start,
+                &mut ops,
+            )?;
+
+            let inconsistent = types::AlignError::TracebackInconsistency {
+                score: s,
+                k,
+                offset: max!(k, 0),
+            };
+
+            // started inside the alternative
+            if k > 0 {
+                return Ok(Traced {
+                    ops,
+                    chosen,
+                    start: (segment_index, k),
+                });
+            }
+
+            // started at the start of the segment
+            let v: i32 = -k;
+            if (s, v, wf_type) == (0, 0, WfType::M) && (segment_index == 0 || self.begin_free) {
+                return Ok(Traced {
+                    ops,
+                    chosen,
+                    start: (segment_index, 0),
+                });
+            }
+
+            if segment_index == 0 {
+                return Err(inconsistent);
+            }
+
+            let arrival: &Arrival = incoming
+                .get(v, wf_type)
+                .filter(|arrival| arrival.score == s as usize && !arrival.alternatives.is_empty())
+                .ok_or(inconsistent)?;
+
+            segment_index -= 1;
+            alternatives = arrival.alternatives.clone();
+
+            let alt_len = self.segments[segment_index][alternatives[0]].len() as i32;
+            (k, offset) = (alt_len - v, alt_len);
+        }
+    }
+
+    /// The path to the end of the last segment that arrived with this score
+    fn traceback_end(
+        &self,
+        config: &types::Config,
+        boundary_index: usize,
+        score: usize,
+    ) -> Result<Traced, types::AlignError> {
+        let qlen = self.query.len() as i32;
+        let arrival: &Arrival = self.boundaries[boundary_index]
+            .get(qlen, WfType::M)
+            .filter(|arrival| arrival.score == score)
+            .ok_or(types::AlignError::TracebackInconsistency {
+                score: score as i32,
+                k: -qlen,
+                offset: 0,
+            })?;
+
+        // an empty query that starts and ends here
+        if arrival.alternatives.is_empty() {
+            return Ok(Traced {
+                ops: Vec::new(),
+                chosen: Vec::new(),
+                start: (boundary_index, 0),
+            });
+        }
+
+        let segment_index: usize = boundary_index - 1;
+        let alt_len = self.segments[segment_index][arrival.alternatives[0]].len() as i32;
+
+        self.traceback(
+            config,
+            (segment_index, arrival.alternatives.clone()),
+            (score as i32, alt_len - qlen, alt_len, WfType::M),
+        )
+    }
+
+    /// The (segment, alternative, score, k, offset) of the cell furthest
+    /// along the antidiagonals out of the M wavefronts up to the given score,
+    /// the lowest score that reaches it
+    fn furthest_cell(&self, score: usize) -> Option<(usize, usize, usize, i32, i32)> {
+        let mut cells = Vec::new();
+
+        for (segment_index, alts) in self.all_wavefronts.iter().enumerate() {
+            for (alt_index, wavefronts) in alts.iter().enumerate() {
+                for s in 0..=score {
+                    if let Some((k, offset)) =
+                        utils::furthest_cell(wavefronts.get_m_wavefront(s as i32))
+                    {
+                        cells.push((segment_index, alt_index, s, k, offset));
+                    }
+                }
+            }
+        }
+
+        cells
+            .into_iter()
+            .max_by_key(|(segment_index, _, s, k, offset)| {
+                let v = utils::compute_v(*offset, *k);
+                let h = self.starts[*segment_index] as i32 + utils::compute_h(*offset, *k);
+                (v + h, cmp::Reverse(*s))
+            })
+    }
+}
+
+/// The wavefronts go back to the pool for the next alignment, those that only
+/// keep the last scores are of no use to it
+impl<P> Drop for ElasticWaveFronts<'_, '_, P> {
+    fn drop(&mut self) {
+        if self.window.is_none() {
+            self.pool.extend(self.all_wavefronts.drain(..).flatten());
+        }
+    }
+}
+
+/// An empty text still needs somewhere to delete the query
+fn segments_of<P: Clone>(segments: &[Vec<Vec<P>>]) -> Cow<'_, [Vec<Vec<P>>]> {
+    if segments.is_empty() {
+        Cow::Owned(vec![vec![Vec::new()]])
+    } else {
+        Cow::Borrowed(segments)
+    }
+}
+
+/// Compute the wavefronts until a path reaches the end of the text
+/// Returns its score, a text position is counted along the shortest path
+fn wf_compute_elastic<P>(
+    elastic_wavefronts: &mut ElasticWaveFronts<P>,
+    config: &types::Config,
+    scoring: &types::Config,
+) -> Result<usize, types::AlignError>
+where
+    [P]: DegenerateText,
+{
+    let qlen: usize = elastic_wavefronts.query.len();
+    let tlen: usize = elastic_wavefronts.tlen();
+    let segment_count: usize = elastic_wavefronts.segments.len();
+
+    // an alignment to the shortest path
+    let max_score: usize = scoring.score_limit(tlen as u32, qlen as u32);
+
+    // the (v, h) furthest along the antidiagonals so far, h counts the segments
+    // before the one it is in by their shortest alternatives
+    let mut reached: (i32, i32) = (0, 0);
+
+    // the furthest offset on the central diagonal so far, for the progress hook
+    let mut a_k_offset: i32 = 0;
+
+    let mut score: usize = 0;
+
+    loop {
+        elastic_wavefronts.compute(score, scoring, &mut reached)?;
+
+        if let Some(progress) = config.progress.as_deref() {
+            let offset: Option<i32> = elastic_wavefronts.central_offset(score);
+            a_k_offset = max!(a_k_offset, offset.unwrap_or(0));
+            progress.update(score, a_k_offset as u32, tlen as u32);
+        }
+
+        if elastic_wavefronts.boundaries[segment_count]
+            .get(qlen as i32, WfType::M)
+            .is_some()
+        {
+            if let Some(progress) = config.progress.as_deref() {
+                progress.finish();
+            }
+            return Ok(score);
+        }
+
+        // give up
+        if score >= max_score {
+            if let Some(progress) = config.progress.as_deref() {
+                progress.finish();
+            }
+            return Err(types::AlignError::ScoreLimitExceeded {
+                score,
+                max_score,
+                query_end: reached.0 as u32,
+                text_end: reached.1 as u32,
+                partial: None,
+            });
+        }
+
+        score += 1;
+    }
+}
+
+/// The scoring the wavefronts are computed with, see [without_bonus]
+fn scoring_of(config: &types::Config) -> Cow<'_, types::Config> {
+    if config.penalties.has_bonus() {
+        Cow::Owned(without_bonus(config))
+    } else {
+        Cow::Borrowed(config)
+    }
+}
+
+/// Align the whole query to a path from the start to the end of the text
+/// made of the segments, see [ElasticWaveFronts]
+///
+/// With a match bonus every path has to be as long, see [crate::elastic::ElasticText::min_len].
+/// The wavefronts of the alternatives are taken from the pool and go back to it
+pub fn wf_align_elastic<P: Clone>(
+    pool: &mut Vec<types::WaveFronts>,
+    segments: &[Vec<Vec<P>>],
+    query: &[u8],
+    config: &types::Config,
+) -> Result<types::Alignment, types::AlignError>
+where
+    [P]: DegenerateText,
+{
+    let _span = tracing::debug_span!("wf_align_elastic", qlen = query.len()).entered();
+
+    let segments = segments_of(segments);
+    let scoring = scoring_of(config);
+    let mut elastic_wavefronts = ElasticWaveFronts::new(&segments, query, None, false, pool);
+
+    let score: usize = match wf_compute_elastic(&mut elastic_wavefronts, config, &scoring) {
+        Err(types::AlignError::ScoreLimitExceeded {
+            score,
+            max_score,
+            query_end,
+            text_end,
+            ..
+        }) if config.partial_alignment => {
+            let partial: Option<types::Alignment> =
+                wf_traceback_partial(&elastic_wavefronts, score, config, &scoring)?;
+
+            return Err(types::AlignError::ScoreLimitExceeded {
+                score,
+                max_score,
+                query_end,
+                text_end,
+                partial: partial.map(Box::new),
+            });
+        }
+        res => res?,
+    };
+
+    let _traceback_span = tracing::debug_span!("wf_traceback_elastic", score).entered();
+    let traced: Traced = elastic_wavefronts.traceback_end(&scoring, segments.len(), score)?;
+
+    let (tlen, qlen) = (elastic_wavefronts.tlen() as i32, query.len() as i32);
+    Ok(types::Alignment {
+        score: user_score(config, score, tlen - qlen, tlen),
+        cigar: traced.ops.into_iter().rev().collect(),
+        segments: traced.chosen.into_iter().rev().collect(),
+    })
+}
+
+/// The alignment to the cell furthest along the antidiagonals, see
+/// [types::Config::partial_alignment]
+fn wf_traceback_partial<P>(
+    elastic_wavefronts: &ElasticWaveFronts<P>,
+    score: usize,
+    config: &types::Config,
+    scoring: &types::Config,
+) -> Result<Option<types::Alignment>, types::AlignError>
+where
+    [P]: DegenerateText,
+{
+    let Some((segment_index, alt_index, score, k, offset)) =
+        elastic_wavefronts.furthest_cell(score)
+    else {
+        return Ok(None);
+    };
+
+    let traced: Traced = elastic_wavefronts.traceback(
+        scoring,
+        (segment_index, vec![alt_index]),
+        (score as i32, k, offset, WfType::M),
+    )?;
+
+    let start = elastic_wavefronts.starts[segment_index] as i32;
+    Ok(Some(types::Alignment {
+        score: user_score(config, score, start + k, start + offset),
+        cigar: traced.ops.into_iter().rev().collect(),
+        segments: traced.chosen.into_iter().rev().collect(),
+    }))
+}
+
+/// Compute only the score of aligning the query to a path through the
+/// text, keeping only the wavefronts the next score is computed from
+pub fn wf_score_elastic<P: Clone>(
+    segments: &[Vec<Vec<P>>],
+    query: &[u8],
+    config: &types::Config,
+) -> Result<types::AlignmentScore, types::AlignError>
+where
+    [P]: DegenerateText,
+{
+    let _span = tracing::debug_span!("wf_score_elastic", qlen = query.len()).entered();
+
+    let segments = segments_of(segments);
+    let scoring = scoring_of(config);
+    let mut pool: Vec<types::WaveFronts> = Vec::new();
+    let mut elastic_wavefronts = ElasticWaveFronts::new(
+        &segments,
+        query,
+        Some(score_window(&scoring)),
+        false,
+        &mut pool,
+    );
+
+    let score: usize = wf_compute_elastic(&mut elastic_wavefronts, config, &scoring)?;
+
+    let (tlen, qlen) = (elastic_wavefronts.tlen() as i32, query.len() as i32);
+    Ok(types::AlignmentScore {
+        score: user_score(config, score, tlen - qlen, tlen),
+        query_end: qlen as u32,
+        text_end: tlen as u32,
+    })
+}
+
+/// Where an occurrence can end
+#[derive(Debug, Clone, Copy)]
+enum End {
+    /// the start of a segment, or the end of the text
+    Boundary(usize),
+
+    /// after the h-th character of an alternative, short of its end
+    Inside {
+        segment: usize,
+        alternative: usize,
+        h: usize,
+    },
+}
+
+/// Find where the whole query aligns to a path through part of the text
+/// with a score of at most max_score, in text order
+///
+/// See [crate::align::wf_search], the occurrences are reported at their
+/// best ends along each alternative. A text position is counted along the
+/// shortest path through the segments before it.
+pub fn wf_search_elastic<P: Clone>(
+    segments: &[Vec<Vec<P>>],
+    query: &[u8],
+    config: &types::Config,
+    max_score: usize,
+) -> Result<Vec<types::Occurrence>, types::AlignError>
+where
+    [P]: DegenerateText,
+{
+    let _span = tracing::debug_span!("wf_search_elastic", qlen = query.len(), max_score).entered();
+
+    let qlen: i32 = query.len() as i32;
+    let segments = segments_of(segments);
+    let mut pool: Vec<types::WaveFronts> = Vec::new();
+    let mut elastic_wavefronts = ElasticWaveFronts::new(&segments, query, None, true, &mut pool);
+
+    let mut reached: (i32, i32) = (0, 0);
+    for score in 0..=max_score {
+        elastic_wavefronts.compute(score, config, &mut reached)?;
+    }
+
+    // the lowest score and diagonal each end inside an alternative is reached
+    // with, including its start where deletions of the query go on
+    let mut inside: Vec<Vec<Vec<_>>> = segments
+        .iter()
+        .map(|alts| alts.iter().map(|alt| vec![None; alt.len()]).collect())
+        .collect();
+
+    for (segment_index, alts) in elastic_wavefronts.all_wavefronts.iter().enumerate() {
+        for (alt_index, wavefronts) in alts.iter().enumerate() {
+            let ends: &mut Vec<Option<(usize, i32)>> = &mut inside[segment_index][alt_index];
+
+            for score in 0..=max_score {
+                let Some(m_wf) = wavefronts.get_m_wavefront(score as i32) else {
+                    continue;
+                };
+
+                // diagonals that consumed the whole query
+                for k in m_wf.lo..=m_wf.hi {
+                    let offset: i32 = m_wf.get_offset(k).unwrap().max();
+                    if offset >= 0
+                        && (offset as usize) < ends.len()
+                        && utils::compute_v(offset, k) == qlen
+                    {
+                        ends[offset as usize].get_or_insert((score, k));
+                    }
+                }
+            }
+        }
+    }
+
+    // a boundary is reached from the end of the segment before it or from the
+    // start of the one after it, the alternative and diagonal of the latter
+    let at_boundary = |index: usize| -> Option<(usize, Option<(usize, i32)>)> {
+        let arrival: Option<usize> = elastic_wavefronts.boundaries[index]
+            .get(qlen, WfType::M)
+            .map(|arrival| arrival.score);
+        let start = inside.get(index).into_iter().flat_map(|alts| {
+            alts.iter().enumerate().filter_map(|(alternative, ends)| {
+                ends.first()
+                    .copied()
+                    .flatten()
+                    .map(|end| (alternative, end))
+            })
+        });
+
+        let arrival = arrival.map(|score| (score, None));
+        let start = start
+            .map(|(alternative, (score, k))| (score, Some((alternative, k))))
+            .min_by_key(|(score, _)| *score);
+        [arrival, start]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(score, _)| *score)
+    };
+
+    // an end missing from the ends is worse than any that was found
+    let score_at = |end: End| -> usize {
+        let found: Option<usize> = match end {
+            End::Boundary(index) => at_boundary(index).map(|(score, _)| score),
+            End::Inside {
+                segment,
+                alternative,
+                h,
+            } => inside[segment][alternative][h].map(|(score, _)| score),
+        };
+        found.unwrap_or(usize::MAX)
+    };
+
+    // the ends one character before and after, alternatives of length 0 are skipped
+    let neighbours = |end: End| -> Vec<End> {
+        match end {
+            End::Boundary(index) => {
+                let before = index.checked_sub(1).into_iter().flat_map(|segment| {
+                    segments[segment]
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, alt)| !alt.is_empty())
+                        .map(move |(alternative, alt)| match alt.len() {
+                            1 => End::Boundary(segment),
+                            len => End::Inside {
+                                segment,
+                                alternative,
+                                h: len - 1,
+                            },
+                        })
+                });
+                let after = segments.get(index).into_iter().flat_map(move |alts| {
+                    alts.iter()
+                        .enumerate()
+                        .filter(|(_, alt)| !alt.is_empty())
+                        .map(move |(alternative, alt)| match alt.len() {
+                            1 => End::Boundary(index + 1),
+                            _ => End::Inside {
+                                segment: index,
+                                alternative,
+                                h: 1,
+                            },
+                        })
+                });
+                before.chain(after).collect()
+            }
+            End::Inside {
+                segment,
+                alternative,
+                h,
+            } => {
+                let len: usize = segments[segment][alternative].len();
+                let at = |h: usize| -> End {
+                    match h {
+                        0 => End::Boundary(segment),
+                        h if h == len => End::Boundary(segment + 1),
+                        h => End::Inside {
+                            segment,
+                            alternative,
+                            h,
+                        },
+                    }
+                };
+                vec![at(h - 1), at(h + 1)]
+            }
+        }
+    };
+
+    // every end in text order
+    let ends = (0..=segments.len()).flat_map(|index| {
+        let inside_ends = segments.get(index).into_iter().flat_map(move |alts| {
+            alts.iter().enumerate().flat_map(move |(alternative, alt)| {
+                (1..alt.len()).map(move |h| End::Inside {
+                    segment: index,
+                    alternative,
+                    h,
+                })
+            })
+        });
+        std::iter::once(End::Boundary(index)).chain(inside_ends)
+    });
+
+    let mut occurrences: Vec<types::Occurrence> = Vec::new();
+    for end in ends {
+        let score: usize = score_at(end);
+        if score == usize::MAX {
+            continue;
+        }
+
+        // the best end of each occurrence
+        if neighbours(end)
+            .into_iter()
+            .any(|neighbour| score > score_at(neighbour))
+        {
+            continue;
+        }
+
+        let (traced, text_end): (Traced, usize) = match end {
+            End::Boundary(index) => {
+                let traced = match at_boundary(index) {
+                    Some((_, Some((alternative, k)))) => {
+                        let mut traced = elastic_wavefronts.traceback(
+                            config,
+                            (index, vec![alternative]),
+                            (score as i32, k, 0, WfType::M),
+                        )?;
+
+                        // none of the segment's text is in the occurrence
+                        traced.chosen.retain(|chosen| chosen.segment != index);
+                        traced
+                    }
+                    _ => elastic_wavefronts.traceback_end(config, index, score)?,
+                };
+                (traced, elastic_wavefronts.starts[index])
+            }
+            End::Inside {
+                segment,
+                alternative,
+                h,
+            } => {
+                let (_, k) = inside[segment][alternative][h].unwrap();
+                let traced = elastic_wavefronts.traceback(
+                    config,
+                    (segment, vec![alternative]),
+                    (score as i32, k, h as i32, WfType::M),
+                )?;
+                (traced, elastic_wavefronts.starts[segment] + h)
+            }
+        };
+
+        let (start_segment, start_h) = traced.start;
+        let text_start: usize = elastic_wavefronts.starts[start_segment] + start_h as usize;
+
+        occurrences.push(types::Occurrence {
+            score,
+            text_start: text_start as u32,
+            text_end: text_end as u32,
+            cigar: traced.ops.into_iter().rev().collect(),
+            segments: traced.chosen.into_iter().rev().collect(),
+        });
+    }
+
+    Ok(occurrences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lazy_segments() {
+        let config = types::Config::default();
+
+        // {AC,GT}ACGTACGT repeated, the query only matches the first few segments
+        let segments: Vec<Vec<Vec<u8>>> = (0..40)
+            .flat_map(|_| {
+                [
+                    vec![b"AC".to_vec(), b"GT".to_vec()],
+                    vec![b"ACGTACGT".to_vec()],
+                ]
+            })
+            .collect();
+        let query: Vec<u8> = [&b"ACACGTACGTGT"[..], &[b'T'; 30]].concat();
+
+        let mut pool: Vec<types::WaveFronts> = Vec::new();
+        let mut elastic_wavefronts =
+            ElasticWaveFronts::new(&segments, &query, None, false, &mut pool);
+        let mut reached: (i32, i32) = (0, 0);
+        for score in 0..=20 {
+            elastic_wavefronts
+                .compute(score, &config, &mut reached)
+                .unwrap();
+        }
+
+        // only the segments a path got to have wavefronts and boundary cells
+        let with_wavefronts: usize = elastic_wavefronts
+            .all_wavefronts
+            .iter()
+            .filter(|alts| !alts.is_empty())
+            .count();
+        assert!(with_wavefronts < 10);
+        assert!(elastic_wavefronts.all_wavefronts[..with_wavefronts]
+            .iter()
+            .all(|alts| !alts.is_empty()));
+        assert!(elastic_wavefronts.boundaries[with_wavefronts..]
+            .iter()
+            .all(|boundary| boundary.cells.is_empty()));
+        assert!(elastic_wavefronts
+            .active
+            .iter()
+            .all(|i| *i < with_wavefronts));
+
+        // and they go back to the pool for the next alignment
+        drop(elastic_wavefronts);
+        let alternatives: usize = segments[..with_wavefronts].iter().map(Vec::len).sum();
+        assert_eq!(pool.len(), alternatives);
+    }
+}
@@ -1,5 +1,5 @@
 use super::types::{self, AWFSet};
-use super::utils;
+use super::wf_backtrace::{wf_backtrace_compact, wf_backtrace_next};
 use fbox::macros::max;
 
 const NULL_OFFSET: i32 = -10;
@@ -23,79 +23,79 @@ fn fetch_wf<'a>(
     let maybe_in_i_ext: Option<&types::WaveFront> = wavefronts.get_i_wavefront(s_e);
     let maybe_in_d_ext: Option<&types::WaveFront> = wavefronts.get_d_wavefront(s_e);
 
+    // M at s_o2_e2 only opens a gap with a second gap piece
+    let maybe_o2_e2: Option<(i32, i32)> = config.distance.second_gap_piece();
+    let s_o2_e2: i32 = maybe_o2_e2.map_or(-1, |(o2, e2)| s - o2 - e2);
+    let s_e2: i32 = maybe_o2_e2.map_or(-1, |(_, e2)| s - e2);
+
+    let maybe_in_m_gap2: Option<&types::WaveFront> = wavefronts.get_m_wavefront(s_o2_e2);
+    let maybe_in_i2_ext: Option<&types::WaveFront> = wavefronts.get_i2_wavefront(s_e2);
+    let maybe_in_d2_ext: Option<&types::WaveFront> = wavefronts.get_d2_wavefront(s_e2);
+
     AWFSet {
         in_m_sub: maybe_in_m_sub,
         in_m_gap: maybe_in_m_gap,
         in_i_ext: maybe_in_i_ext,
         in_d_ext: maybe_in_d_ext,
 
+        in_m_gap2: maybe_in_m_gap2,
+        in_i2_ext: maybe_in_i2_ext,
+        in_d2_ext: maybe_in_d2_ext,
+
         out_m: None,
         out_i: None,
         out_d: None,
     }
 }
 
-// TODO: remove? No longer used
-fn alloc_wf(awf_set: &AWFSet) -> Vec<types::WfType> {
-    let mut wavefronts_to_allocate = vec![types::WfType::M];
-
-    // Allocate I-Wavefront
-    if awf_set.in_m_gap.is_some() || awf_set.in_i_ext.is_some() {
-        wavefronts_to_allocate.push(types::WfType::I);
-    }
-
-    // Allocate D-Wavefront
-    if awf_set.in_m_gap.is_some() || awf_set.in_d_ext.is_some() {
-        wavefronts_to_allocate.push(types::WfType::D);
-    }
-
-    wavefronts_to_allocate
-}
-
-fn foobar<'a>(
-    wavefronts: &'a mut types::WaveFronts,
-    awf_set: &AWFSet,
+/// Allocate the wavefronts of the next score from the pool
+/// They are only stored in wavefronts once computed so that the input
+/// wavefronts can be borrowed while they are written
+fn new_wavefront_set(
+    wavefronts: &mut types::WaveFronts,
+    seeds_gaps: bool,
     lo: i32,
     hi: i32,
-    score: usize,
-) -> Vec<types::WfType> {
+    config: &types::Config,
+) -> (types::WaveFrontSet, Vec<types::WfType>) {
     let mut wavefronts_to_allocate = vec![types::WfType::M];
 
-    let maybe_out_m_wf = Some(types::WaveFront::new(hi, lo));
+    let mut maybe_out_m_wf = Some(wavefronts.new_wavefront(hi, lo));
     let mut maybe_out_i_wf = None;
     let mut maybe_out_d_wf = None;
 
-    // Allocate I-Wavefront
-    if awf_set.in_m_gap.is_some() || awf_set.in_i_ext.is_some() {
-        maybe_out_i_wf = Some(types::WaveFront::new(hi, lo));
+    let mut maybe_out_i2_wf = None;
+    let mut maybe_out_d2_wf = None;
+
+    // Allocate the I and D wavefronts together
+    // either can be seeded on its own so we can't rely on both being present
+    // linear gaps are computed on the M wavefront
+    // with two gap pieces I2 and D2 are allocated along with I and D
+    if config.distance.has_gap_wavefronts() && seeds_gaps {
+        maybe_out_i_wf = Some(wavefronts.new_wavefront(hi, lo));
         wavefronts_to_allocate.push(types::WfType::I);
-    }
 
-    // Allocate D-Wavefront
-    if awf_set.in_m_gap.is_some() || awf_set.in_d_ext.is_some() {
-        maybe_out_d_wf = Some(types::WaveFront::new(hi, lo));
+        maybe_out_d_wf = Some(wavefronts.new_wavefront(hi, lo));
         wavefronts_to_allocate.push(types::WfType::D);
-    }
 
-    let prev_score = wavefronts.wavefront_set.len();
+        if config.distance.second_gap_piece().is_some() {
+            maybe_out_i2_wf = Some(wavefronts.new_wavefront(hi, lo));
+            wavefronts_to_allocate.push(types::WfType::I2);
 
-    for s in prev_score..=score {
-        if s == score {
-            let wf = types::WaveFrontSet {
-                i: maybe_out_i_wf.clone(),
-                m: maybe_out_m_wf.clone(),
-                d: maybe_out_d_wf.clone(),
-            };
-
-            wavefronts.wavefront_set.push(Some(wf));
-        } else {
-            wavefronts.wavefront_set.push(None);
+            maybe_out_d2_wf = Some(wavefronts.new_wavefront(hi, lo));
+            wavefronts_to_allocate.push(types::WfType::D2);
         }
     }
 
-    // awf_set.out_m = wavefronts.get_m_wavefront(score as i32);
+    let wf = types::WaveFrontSet {
+        i: maybe_out_i_wf.take(),
+        m: maybe_out_m_wf.take(),
+        d: maybe_out_d_wf.take(),
+        i2: maybe_out_i2_wf.take(),
+        d2: maybe_out_d2_wf.take(),
+    };
 
-    wavefronts_to_allocate
+    (wf, wavefronts_to_allocate)
 }
 
 fn compute_wf_next_limits(
@@ -117,7 +117,12 @@ fn compute_wf_next_limits(
     let s_o_e = s - o - e;
     let s_e = s - e;
 
-    let foo = |maybe_wf: Option<&types::WaveFront>, limit: WfLimit| -> Option<i32> {
+    // M at s_o2_e2 only opens a gap with a second gap piece
+    let maybe_o2_e2: Option<(i32, i32)> = config.distance.second_gap_piece();
+    let s_o2_e2: i32 = maybe_o2_e2.map_or(-1, |(o2, e2)| s - o2 - e2);
+    let s_e2: i32 = maybe_o2_e2.map_or(-1, |(_, e2)| s - e2);
+
+    let limit_of = |maybe_wf: Option<&types::WaveFront>, limit: WfLimit| -> Option<i32> {
         match maybe_wf {
             Some(wf) => match limit {
                 WfLimit::Hi => Some(wf.hi),
@@ -129,10 +134,13 @@ fn compute_wf_next_limits(
 
     // what if a hi is empty?
     let hi: Option<i32> = vec![
-        foo(wavefronts.get_m_wavefront(s_x), WfLimit::Hi),
-        foo(wavefronts.get_m_wavefront(s_o_e), WfLimit::Hi),
-        foo(wavefronts.get_i_wavefront(s_e), WfLimit::Hi),
-        foo(wavefronts.get_d_wavefront(s_e), WfLimit::Hi),
+        limit_of(wavefronts.get_m_wavefront(s_x), WfLimit::Hi),
+        limit_of(wavefronts.get_m_wavefront(s_o_e), WfLimit::Hi),
+        limit_of(wavefronts.get_i_wavefront(s_e), WfLimit::Hi),
+        limit_of(wavefronts.get_d_wavefront(s_e), WfLimit::Hi),
+        limit_of(wavefronts.get_m_wavefront(s_o2_e2), WfLimit::Hi),
+        limit_of(wavefronts.get_i2_wavefront(s_e2), WfLimit::Hi),
+        limit_of(wavefronts.get_d2_wavefront(s_e2), WfLimit::Hi),
     ]
     .iter()
     .max()
@@ -140,10 +148,13 @@ fn compute_wf_next_limits(
     .map(|x| x + 1);
 
     let maybe_los: Vec<Option<i32>> = vec![
-        foo(wavefronts.get_m_wavefront(s_x), WfLimit::Lo),
-        foo(wavefronts.get_m_wavefront(s_o_e), WfLimit::Lo),
-        foo(wavefronts.get_i_wavefront(s_e), WfLimit::Lo),
-        foo(wavefronts.get_d_wavefront(s_e), WfLimit::Lo),
+        limit_of(wavefronts.get_m_wavefront(s_x), WfLimit::Lo),
+        limit_of(wavefronts.get_m_wavefront(s_o_e), WfLimit::Lo),
+        limit_of(wavefronts.get_i_wavefront(s_e), WfLimit::Lo),
+        limit_of(wavefronts.get_d_wavefront(s_e), WfLimit::Lo),
+        limit_of(wavefronts.get_m_wavefront(s_o2_e2), WfLimit::Lo),
+        limit_of(wavefronts.get_i2_wavefront(s_e2), WfLimit::Lo),
+        limit_of(wavefronts.get_d2_wavefront(s_e2), WfLimit::Lo),
     ]
     .iter()
     .filter(|x| x.is_some())
@@ -159,47 +170,78 @@ fn compute_wf_next_limits(
     (hi, lo)
 }
 
-pub fn wf_next(wavefronts: &mut types::WaveFronts, score: usize, config: &types::Config) {
-    let verbosity = config.verbosity;
+/// Wavefronts with fewer diagonals are computed on one thread, splitting
+/// them would cost more than it saves
+#[cfg(feature = "rayon")]
+const MIN_PARALLEL_DIAGONALS: usize = 2048;
 
-    if verbosity > 1 {
-        eprintln!("\t[wflambda::wf_next]");
-    }
+/// Call assign(k, outs, index) on each diagonal k from lo on, it assigns the
+/// offsets of k that are at index in each of the out wavefronts outs
+fn for_each_diagonal<A>(
+    mut outs: Vec<&mut [types::Offset]>,
+    lo: i32,
+    config: &types::Config,
+    assign: A,
+) where
+    A: Fn(i32, &mut [&mut [types::Offset]], usize) + Sync,
+{
+    let len: usize = outs[0].len();
+
+    // split wide wavefronts into a run of diagonals per thread
+    #[cfg(feature = "rayon")]
+    if config.thread_count > 1 && len >= MIN_PARALLEL_DIAGONALS {
+        use rayon::prelude::*;
+
+        let run_len: usize = len.div_ceil(config.thread_count);
+        let mut runs: Vec<Vec<&mut [types::Offset]>> = Vec::new();
+        for out in outs {
+            for (run, offsets) in out.chunks_mut(run_len).enumerate() {
+                if run == runs.len() {
+                    runs.push(Vec::new());
+                }
+                runs[run].push(offsets);
+            }
+        }
 
-    if verbosity > 4 {
-        eprintln!("\t\tscore {}", score);
+        runs.into_par_iter()
+            .enumerate()
+            .for_each(|(run, mut outs)| {
+                let run_lo: i32 = lo + (run * run_len) as i32;
+                for index in 0..outs[0].len() {
+                    assign(run_lo + index as i32, &mut outs, index);
+                }
+            });
+
+        return;
     }
 
-    let s: i32 = score as i32;
-    let x: i32 = config.penalties.mismatch;
-    let o: i32 = config.penalties.gap_open;
-    let e: i32 = config.penalties.gap_extend;
+    #[cfg(not(feature = "rayon"))]
+    let _ = config;
 
-    let signed_s_x: i32 = s - x;
-    let signed_s_o_e: i32 = s - o - e;
-    let signed_s_e: i32 = s - e;
+    for index in 0..len {
+        assign(lo + index as i32, &mut outs, index);
+    }
+}
 
-    let wfs = &wavefronts.clone();
-    let awf_set = fetch_wf(score, wfs, config);
+pub fn wf_next(
+    wavefronts: &mut types::WaveFronts,
+    score: usize,
+    config: &types::Config,
+) -> Result<(), types::AlignError> {
+    let _span = tracing::trace_span!("wf_next", score).entered();
+
+    let awf_set = fetch_wf(score, wavefronts, config);
 
     if awf_set.in_m_sub.is_none()
         && awf_set.in_m_gap.is_none()
+        && awf_set.in_i_ext.is_none()
         && awf_set.in_d_ext.is_none()
-        && awf_set.in_d_ext.is_none()
+        && awf_set.in_m_gap2.is_none()
+        && awf_set.in_i2_ext.is_none()
+        && awf_set.in_d2_ext.is_none()
     {
-        if verbosity > 4 {
-            eprintln!("\t\tskipping score {}", score);
-        }
-        return;
-    }
-
-    if verbosity > 5 {
-        eprintln!(
-            "\t\ts {} s - o - e {} s - e {} s - x {}",
-            s, signed_s_o_e, signed_s_e, signed_s_x
-        );
-        eprint!("\t\tk\tI\tD\tM");
-        eprintln!();
+        tracing::trace!("no wavefronts to compute the score from");
+        return Ok(());
     }
 
     // --------------------------
@@ -208,30 +250,44 @@ pub fn wf_next(wavefronts: &mut types::WaveFronts, score: usize, config: &types:
 
     // compute the highest/rightmost and lowest/leftmost diagonal for a
     // wavefront with the given score will reach
-    let (hi, lo): (Option<i32>, Option<i32>) = compute_wf_next_limits(&wavefronts, score, config);
+    let (hi, lo): (Option<i32>, Option<i32>) = compute_wf_next_limits(wavefronts, score, config);
 
     if hi.is_none() || lo.is_none() {
-        if verbosity > 4 {
-            eprintln!("\t\tskipping (invalid diagonals) hi {:?} lo {:?}", lo, hi);
-        }
-        return;
+        tracing::trace!(?lo, ?hi, "no diagonals to compute");
+        return Ok(());
     }
 
     let (hi, lo) = (hi.unwrap(), lo.unwrap());
 
-    if verbosity > 4 {
-        eprintln!("\t\tscore: {} lo {} hi {}", score, lo, hi);
-    }
-
     // ----------------------------
     // Allocate the next wave front
     // ----------------------------
 
-    let wavefronts_to_allocate = foobar(wavefronts, &awf_set, lo, hi, score);
-
-    if verbosity > 4 {
-        eprintln!("\t\tWavefronts to allocate {:?}", wavefronts_to_allocate);
-    }
+    let seeds_gaps: bool = awf_set.in_m_gap.is_some()
+        || awf_set.in_i_ext.is_some()
+        || awf_set.in_d_ext.is_some()
+        || awf_set.in_m_gap2.is_some()
+        || awf_set.in_i2_ext.is_some()
+        || awf_set.in_d2_ext.is_some();
+    let (mut out_wf_set, wavefronts_to_allocate) =
+        new_wavefront_set(wavefronts, seeds_gaps, lo, hi, config);
+
+    // borrow the input wavefronts again now that the pool has been used
+    let mut backtrace: Option<types::BacktraceBuffer> = wavefronts.backtrace.take();
+    let awf_set = fetch_wf(score, wavefronts, config);
+
+    tracing::trace!(lo, hi, wavefronts = ?wavefronts_to_allocate, "allocated");
+
+    // offsets past the end of the text or the query aren't cells of the alignment
+    let tlen: i32 = wavefronts.max_k as i32;
+    let qlen: i32 = -wavefronts.min_k as i32;
+    let bounded = |k: i32, offset: i32| -> i32 {
+        if offset < 0 || offset > tlen || offset - k > qlen {
+            NULL_OFFSET
+        } else {
+            offset
+        }
+    };
 
     // TODO: remove this useless fn
     // TODO: is this necessary?
@@ -243,263 +299,169 @@ pub fn wf_next(wavefronts: &mut types::WaveFronts, score: usize, config: &types:
         wf.get_offset(k).cloned().unwrap()
     };
 
-    let assign_offsets_m = |wavefronts: &mut types::WaveFronts| {
-        let wf_set: &mut Option<types::WaveFrontSet> = &mut wavefronts.wavefront_set[score];
-        let wf_set: &mut types::WaveFrontSet = wf_set.as_mut().unwrap();
-        let out_m_wf: &mut types::WaveFront = &mut wf_set.m.as_mut().unwrap();
-        let wavefront_len = out_m_wf.len();
+    // the furthest offset of any alternative on diagonal from_k that is still
+    // in bounds on diagonal k after moving delta along the text
+    let furthest = |wf: &types::WaveFront, from_k: i32, k: i32, delta: i32| -> i32 {
+        affine_wavefront_cond_fetch(wf, from_k)
+            .iter()
+            .map(|offset| bounded(k, offset + delta))
+            .max()
+            .unwrap_or(NULL_OFFSET)
+    };
 
-        let in_m_wf: &types::WaveFront = awf_set.in_m_sub.unwrap();
+    let assign_offsets_m = |wf_set: &mut types::WaveFrontSet| -> Result<(), types::AlignError> {
+        let out_m_wf: &mut types::WaveFront = wf_set.m.as_mut().unwrap();
 
-        for k in lo..=hi {
-            // compute_k_index(wavefront_len, out_m_wf.hi, k);
-            let k_index = out_m_wf.k_index(k);
+        let in_m_wf: &types::WaveFront = awf_set.in_m_sub.unwrap();
 
+        let outs: Vec<&mut [types::Offset]> = vec![&mut out_m_wf.offsets[..]];
+        for_each_diagonal(outs, lo, config, |k, outs, index| {
             // TODO: rename offset to sub
+            // a mismatch on every alternative
             let offsets = affine_wavefront_cond_fetch(in_m_wf, k);
-            for (offset_index, mut offset) in offsets.data.iter().copied().enumerate() {
-                if offset != NULL_OFFSET {
-                    offset += 1
-                }
-
-                if out_m_wf.offsets[k_index].offset_count() <= offset_index {
-                    out_m_wf.offsets[k_index].set(offset_index, offset);
-                } else {
-                    out_m_wf.offsets[k_index].push(offset);
-                }
-            }
-        }
-    };
-
-    let assign_offsets_im = |wavefronts: &mut types::WaveFronts| {
-        let wf_set: &mut Option<types::WaveFrontSet> = &mut wavefronts.wavefront_set[score];
-        let wf_set: &mut types::WaveFrontSet = wf_set.as_mut().unwrap();
-
-        let out_m_wf: &mut types::WaveFront = &mut wf_set.m.as_mut().unwrap();
-        let out_i_wf: &mut types::WaveFront = &mut wf_set.i.as_mut().unwrap();
-
-        let in_m_sub_wf: &types::WaveFront = awf_set.in_m_sub.unwrap();
-        let in_m_gap_wf: &types::WaveFront = awf_set.in_m_gap.unwrap();
-        let in_i_ext_wf: &types::WaveFront = awf_set.in_i_ext.unwrap();
-
-        for k in lo..=hi {
-            // --------
-            // Update I
-            // --------
-
-            // comapre gap open on M and gap extend on I
-            let k_index: usize = out_i_wf.k_index(k);
-
-            let x: i32 = affine_wavefront_cond_fetch(in_m_gap_wf, k - 1).first();
-            let y: i32 = affine_wavefront_cond_fetch(in_i_ext_wf, k - 1).first();
-            let ins: i32 = *vec![x, y].iter().max().unwrap();
-
-            //out_m_wf.offsets[k_index].set(0, offset);
-            out_i_wf.offsets[k_index].set(0, ins + 1);
+            let out_offsets: &mut types::Offset = &mut outs[0][index];
+            out_offsets.assign(offsets.iter().map(|offset| bounded(k, offset + 1)));
+        });
 
-            // --------
-            // Update M
-            // --------
-
-            let k_index = out_m_wf.k_index(k);
-            let sub: i32 = affine_wavefront_cond_fetch(in_m_sub_wf, k).first() + 1;
-            let sub: i32 = *vec![sub, ins].iter().max().unwrap();
-
-            out_m_wf.offsets[k_index].set(0, sub);
-        }
+        Ok(())
     };
 
-    let assign_offsets_dm = |wavefronts: &mut types::WaveFronts| {
-        let wf_set: &mut Option<types::WaveFrontSet> = &mut wavefronts.wavefront_set[score];
-        let wf_set: &mut types::WaveFrontSet = wf_set.as_mut().unwrap();
-
-        let out_m_wf: &mut types::WaveFront = &mut wf_set.m.as_mut().unwrap();
-        let out_d_wf: &mut types::WaveFront = &mut wf_set.d.as_mut().unwrap();
-
-        let in_m_sub_wf: &types::WaveFront = awf_set.in_m_sub.unwrap();
-        let in_m_gap_wf: &types::WaveFront = awf_set.in_m_gap.unwrap();
-        let in_d_ext_wf: &types::WaveFront = awf_set.in_d_ext.unwrap();
-
-        for k in lo..=hi {
-            // Update D
-            // comapre gap open on M and gap extend on I
-            let k_index: usize = out_d_wf.k_index(k);
-
-            let x: i32 = affine_wavefront_cond_fetch(in_m_gap_wf, k - 1).first();
-            let y: i32 = affine_wavefront_cond_fetch(in_d_ext_wf, k - 1).first();
-            let del = *vec![x, y].iter().max().unwrap();
-
-            out_d_wf.offsets[k_index].set(0, del);
-
-            // Update M
-            let k_index = out_m_wf.k_index(k);
-            let sub: i32 = affine_wavefront_cond_fetch(in_m_sub_wf, k).first() + 1;
-            let max_m: i32 = *vec![sub, del].iter().max().unwrap();
-
-            out_m_wf.offsets[k_index].set(0, sub);
-        }
-    };
-
-    let assign_offsets_idm = |wavefronts: &mut types::WaveFronts| {
-        let wf_set: &mut Option<types::WaveFrontSet> = &mut wavefronts.wavefront_set[score];
-        let wf_set: &mut types::WaveFrontSet = wf_set.as_mut().unwrap();
-
-        let out_m_wf: &mut types::WaveFront = &mut wf_set.m.as_mut().unwrap();
-        let out_d_wf: &mut types::WaveFront = &mut wf_set.d.as_mut().unwrap();
-        let out_i_wf: &mut types::WaveFront = &mut wf_set.i.as_mut().unwrap();
-
-        // eprintln!("{:#?}", awf_set);
-
+    let assign_offsets_idm = |wf_set: &mut types::WaveFrontSet| -> Result<(), types::AlignError> {
         let maybe_in_m_sub_wf: Option<&types::WaveFront> = awf_set.in_m_sub;
         let maybe_in_m_gap_wf: Option<&types::WaveFront> = awf_set.in_m_gap;
         let maybe_in_d_ext_wf: Option<&types::WaveFront> = awf_set.in_d_ext;
         let maybe_in_i_ext_wf: Option<&types::WaveFront> = awf_set.in_i_ext;
-
-        // compute min_hi
-        let min_hi: Option<i32> = vec![
-            maybe_in_d_ext_wf,
-            maybe_in_i_ext_wf,
-            maybe_in_m_gap_wf,
-            maybe_in_m_sub_wf,
-        ]
-        .into_iter()
-        .filter(|x| x.is_some())
-        .map(|maybe_wf| maybe_wf.map(|wf| wf.hi))
-        .min()
-        .unwrap();
-        let min_hi = min_hi.unwrap();
-
-        let max_lo: Option<i32> = vec![
-            maybe_in_d_ext_wf,
-            maybe_in_i_ext_wf,
-            maybe_in_m_gap_wf,
-            maybe_in_m_sub_wf,
+        let maybe_in_m_gap2_wf: Option<&types::WaveFront> = awf_set.in_m_gap2;
+        let maybe_in_d2_ext_wf: Option<&types::WaveFront> = awf_set.in_d2_ext;
+        let maybe_in_i2_ext_wf: Option<&types::WaveFront> = awf_set.in_i2_ext;
+
+        // in the order M, I, D then I2 and D2 when there is a second gap piece
+        let outs: Vec<&mut [types::Offset]> = [
+            wf_set.m.as_mut(),
+            wf_set.i.as_mut(),
+            wf_set.d.as_mut(),
+            wf_set.i2.as_mut(),
+            wf_set.d2.as_mut(),
         ]
         .into_iter()
-        .filter(|x| x.is_some())
-        .map(|maybe_wf| maybe_wf.map(|wf| wf.lo))
-        .max()
-        .unwrap();
+        .flatten()
+        .map(|wf| &mut wf.offsets[..])
+        .collect();
 
-        let max_lo = max_lo.unwrap();
-
-        for k in lo..=hi {
+        for_each_diagonal(outs, lo, config, |k, outs, index| {
             // Update I
-            let k_index: usize = out_i_wf.k_index(k);
-            let ins_m = maybe_in_m_gap_wf
-                .and_then(|m_gap| Some(affine_wavefront_cond_fetch(m_gap, k - 1).first()));
-            let ins_i = maybe_in_i_ext_wf
-                .and_then(|i_ext| Some(affine_wavefront_cond_fetch(i_ext, k - 1).first()));
-            let ins: i32 = vec![ins_m, ins_i]
-                .into_iter()
-                .max()
-                .unwrap()
-                .map(|i| i + 1)
-                .unwrap();
-            // let ins: i32 = maybe_ins.unwrap_or(-10);
-
-            out_i_wf.offsets[k_index].set(0, ins);
+            let ins_m = maybe_in_m_gap_wf.map(|m_gap| furthest(m_gap, k - 1, k, 1));
+            let ins_i = maybe_in_i_ext_wf.map(|i_ext| furthest(i_ext, k - 1, k, 1));
+            let ins: i32 = max!(ins_m, ins_i).unwrap_or(NULL_OFFSET);
+
+            outs[1][index].set(0, ins);
 
             // Update D
-            let k_index: usize = out_d_wf.k_index(k);
-            let del_m = maybe_in_m_gap_wf
-                .and_then(|m_gap| Some(affine_wavefront_cond_fetch(m_gap, k + 1).first()));
-            let del_i = maybe_in_d_ext_wf
-                .and_then(|d_ext| Some(affine_wavefront_cond_fetch(d_ext, k + 1).first()));
-            let del: i32 = vec![del_m, del_i].into_iter().max().unwrap().unwrap();
-            // let del: i32 = maybe_del.unwrap_or(-10);
+            let del_m = maybe_in_m_gap_wf.map(|m_gap| furthest(m_gap, k + 1, k, 0));
+            let del_i = maybe_in_d_ext_wf.map(|d_ext| furthest(d_ext, k + 1, k, 0));
+            let del: i32 = max!(del_m, del_i).unwrap_or(NULL_OFFSET);
+
+            outs[2][index].set(0, del);
 
-            out_d_wf.offsets[k_index].set(0, del);
+            // Update I2 and D2
+            let (mut ins2, mut del2): (i32, i32) = (NULL_OFFSET, NULL_OFFSET);
+            if outs.len() == 5 {
+                let ins2_m = maybe_in_m_gap2_wf.map(|m_gap| furthest(m_gap, k - 1, k, 1));
+                let ins2_i = maybe_in_i2_ext_wf.map(|i_ext| furthest(i_ext, k - 1, k, 1));
+                ins2 = max!(ins2_m, ins2_i).unwrap_or(NULL_OFFSET);
+
+                outs[3][index].set(0, ins2);
+
+                let del2_m = maybe_in_m_gap2_wf.map(|m_gap| furthest(m_gap, k + 1, k, 0));
+                let del2_d = maybe_in_d2_ext_wf.map(|d_ext| furthest(d_ext, k + 1, k, 0));
+                del2 = max!(del2_m, del2_d).unwrap_or(NULL_OFFSET);
+
+                outs[4][index].set(0, del2);
+            }
 
             // Update M
-            let k_index: usize = out_m_wf.k_index(k);
-            let sub_m: Option<i32> = maybe_in_m_sub_wf
-                .and_then(|m_sub| Some(affine_wavefront_cond_fetch(m_sub, k).first()))
-                .map(|x| if x == NULL_OFFSET { NULL_OFFSET } else { x + 1 });
+            let sub_m: i32 = maybe_in_m_sub_wf
+                .map(|m_sub| furthest(m_sub, k, k, 1))
+                .unwrap_or(NULL_OFFSET);
 
-            let sub: i32 = vec![sub_m, Some(ins), Some(del)]
-                .into_iter()
-                .max()
-                .unwrap()
-                .unwrap();
+            let sub: i32 = max!(sub_m, ins, del, ins2, del2);
 
             // let sub = maybe_sub.unwrap_or(-10);
-            out_m_wf.offsets[k_index].set(0, sub);
-        }
+            outs[0][index].set(0, sub);
+        });
+
+        Ok(())
     };
 
-    if verbosity > 3 {
-        eprintln!("\twavefronts to allocate: {:?}", wavefronts_to_allocate);
-    }
+    // with linear gaps the gap_open of fetch_wf is 0 so in_m_gap is M at s - e
+    let assign_offsets_linear =
+        |wf_set: &mut types::WaveFrontSet| -> Result<(), types::AlignError> {
+            let out_m_wf: &mut types::WaveFront = wf_set.m.as_mut().unwrap();
+
+            let outs: Vec<&mut [types::Offset]> = vec![&mut out_m_wf.offsets[..]];
+            for_each_diagonal(outs, lo, config, |k, outs, index| {
+                let sub: Option<i32> = awf_set.in_m_sub.map(|m_sub| furthest(m_sub, k, k, 1));
+                let ins: Option<i32> = awf_set.in_m_gap.map(|m_gap| furthest(m_gap, k - 1, k, 1));
+                let del: Option<i32> = awf_set.in_m_gap.map(|m_gap| furthest(m_gap, k + 1, k, 0));
+
+                outs[0][index].set(0, max!(sub, ins, del).unwrap_or(NULL_OFFSET));
+            });
+
+            Ok(())
+        };
 
     match wavefronts_to_allocate[..] {
-        [types::WfType::M] => {
-            // eprintln!("\t\tkernel: 0");
-            assign_offsets_m(wavefronts);
+        [types::WfType::M] if !config.distance.has_gap_wavefronts() => {
+            assign_offsets_linear(&mut out_wf_set)?;
         }
-        [types::WfType::M, types::WfType::I] => {
-            // eprintln!("\t\tkernel: 2");
-            assign_offsets_im(wavefronts);
-        }
-        [types::WfType::M, types::WfType::D] => {
-            // eprintln!("\t\tkernel: 1");
-            assign_offsets_dm(wavefronts);
+        [types::WfType::M] => {
+            assign_offsets_m(&mut out_wf_set)?;
         }
-        [types::WfType::M, types::WfType::I, types::WfType::D] => {
-            // eprintln!("\t\tkernel: 3");
-            assign_offsets_idm(wavefronts);
+        [types::WfType::M, types::WfType::I, types::WfType::D]
+        | [types::WfType::M, types::WfType::I, types::WfType::D, types::WfType::I2, types::WfType::D2] =>
+        {
+            assign_offsets_idm(&mut out_wf_set)?;
         }
         _ => {
-            panic!("weird kernel: {:?}", wavefronts_to_allocate);
+            return Err(types::AlignError::Internal(format!(
+                "no kernel to compute wavefronts {:?}",
+                wavefronts_to_allocate
+            )));
         }
     };
 
-    // Show results of expansion
-    if verbosity > 3 {
-        for k in lo..=hi {
-            eprint!("\t\t k {} ", k);
-
-            match wavefronts.get_m_wavefront(score as i32) {
-                Some(m_wf) => {
-                    eprint!(
-                        "\tM {} ",
-                        m_wf.bar(k)
-                            .map(|v| format!("{:?}", v))
-                            .unwrap_or(String::from("None"))
-                    )
-                }
-                _ => {}
-            };
-
-            match wavefronts.get_i_wavefront(score as i32) {
-                Some(m_wf) => {
-                    eprint!(
-                        "\tI {} ",
-                        m_wf.bar(k)
-                            .map(|v| format!("{:?}", v))
-                            .unwrap_or(String::from("None"))
-                    )
-                }
-                _ => {}
-            };
-
-            match wavefronts.get_d_wavefront(score as i32) {
-                Some(m_wf) => {
-                    eprint!(
-                        "\tD {} ",
-                        m_wf.bar(k)
-                            .map(|v| format!("{:?}", v))
-                            .unwrap_or(String::from("None"))
-                    )
-                }
-                _ => {}
-            };
+    if let Some(buffer) = backtrace.as_mut() {
+        wf_backtrace_next(&awf_set, &mut out_wf_set, buffer, config, (tlen, qlen));
+    }
 
-            eprintln!();
-        }
+    wavefronts.set(score, out_wf_set);
+    wavefronts.backtrace = backtrace;
+    wf_backtrace_compact(wavefronts);
+
+    // the offsets of each diagonal
+    if tracing::enabled!(tracing::Level::TRACE) {
+        let offsets = |wf_type: types::WfType, k: i32| -> Option<&[i32]> {
+            Some(
+                wavefronts
+                    .option_get(score)?
+                    .get(wf_type)?
+                    .bar(k)?
+                    .as_slice(),
+            )
+        };
 
-        eprintln!();
+        for k in lo..=hi {
+            tracing::trace!(
+                k,
+                i = ?offsets(types::WfType::I, k),
+                d = ?offsets(types::WfType::D, k),
+                m = ?offsets(types::WfType::M, k),
+                "offsets"
+            );
+        }
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
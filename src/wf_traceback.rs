@@ -1,17 +1,25 @@
+use super::cigar::{Cigar, CigarOp};
 use super::types;
 use super::utils::{self, backtrace as backtrace_utils};
+use fbox::macros::max;
+
+const NULL_OFFSET: i32 = -10;
 
 pub fn wf_traceback<G>(
     all_wavefronts: &types::WaveFronts,
     score: usize,
     config: &types::Config,
     traceback_lambda: &mut G,
-) -> String
+) -> Result<Cigar, types::AlignError>
 where
     G: FnMut((i32, i32), (i32, i32)) -> bool,
 {
-    if config.verbosity > 0 {
-        eprintln!("\n\t[wfa::wf_backtrace]");
+    let _span = tracing::debug_span!("wf_traceback", score).entered();
+
+    // the gaps of the second piece are on the I2 and D2 wavefronts which only
+    // wf_traceback_from follows
+    if config.distance.second_gap_piece().is_some() {
+        return wf_traceback_two_piece(all_wavefronts, score, config, traceback_lambda);
     }
 
     let x: i32 = config.penalties.mismatch;
@@ -24,24 +32,32 @@ where
     let mut k = all_wavefronts.a_k as i32;
 
     // start at the furthest offset on the m-wavefront i.e. the end of the alignment
-    let m_wf = all_wavefronts.get_m_wavefront(score as i32).unwrap();
+    let m_wf = all_wavefronts.get_m_wavefront(score as i32).ok_or(
+        types::AlignError::TracebackInconsistency {
+            score: score as i32,
+            k,
+            offset: 0,
+        },
+    )?;
     // let wave_length = m_wf.len();
     // let hi = m_wf.hi;
     // let k_index = utils::compute_k_index(wave_length, k, hi);
 
     // offset
-    let m_s_k: &types::Offset = m_wf
-        .get_offset(k)
-        .expect("[wflambda::wf_extend] fail unwrap k={k}");
+    let m_s_k: &types::Offset =
+        m_wf.get_offset(k)
+            .ok_or(types::AlignError::DiagonalOutOfBounds {
+                k,
+                lo: m_wf.lo,
+                hi: m_wf.hi,
+            })?;
     // let m_s_k: types::Offset = m_wf.offsets[k_index];
-    let offsets: &types::Offset = m_wf.bar(k).unwrap();
+    let offsets: &types::Offset = m_s_k;
 
     let mut v = utils::compute_v_new(offsets, k);
     let mut h = utils::compute_h_new(offsets, k);
 
-    if config.verbosity > 5 {
-        eprintln!("\t\t({}, {})", v, h);
-    }
+    tracing::trace!(v, h, "traceback end");
 
     let mut backtrace_op = types::BacktraceOperation::MatchMismatch;
 
@@ -49,28 +65,12 @@ where
     let mut offset: types::Offset = m_s_k.clone();
     let mut offset: i32 = m_s_k.max();
 
-    // eprintln!("score {}", score);
-
     while v > 0 && h > 0 && s > 0 {
         // compute scores
         let gap_open_score: i32 = s - o - e;
         let gap_extend_score: i32 = s - e;
         let mismatch_score: i32 = s - x;
 
-        if config.verbosity > 4 {
-            eprintln!(
-                "\t\tscore: {} \n\
-                       \t\tOperation: {:?} \n\
-                       \t\t{{\n\
-                       \t\t\tg_o: {} \n\
-                       \t\t\tg_e: {} \n\
-                       \t\t\tx: {} \n\
-                       \t\t}}\
-                       ",
-                s, backtrace_op, gap_open_score, gap_extend_score, mismatch_score
-            );
-        }
-
         let del_ext: Option<i32> = if backtrace_op == types::BacktraceOperation::Insertion {
             None
         } else {
@@ -107,17 +107,19 @@ where
             .max()
             .unwrap();
 
-        if config.verbosity > 4 {
-            let res = vec![del_ext, del_open, ins_ext, ins_open, misms];
-            eprintln!(
-                "\t\tdel_ext, del_open, ins_ext, ins_open, misms\n\
-                       \t\tops {:?} \n\
-                       \t\toffset {:?} \n\
-                       \t\tmax_all {:?} \n\
-                       \t\tbacktrace_op {:?}",
-                res, offset, max_all, backtrace_op
-            );
-        }
+        tracing::trace!(
+            score = s,
+            k,
+            offset,
+            state = ?backtrace_op,
+            ?del_ext,
+            ?del_open,
+            ?ins_ext,
+            ?ins_open,
+            ?misms,
+            ?max_all,
+            "traceback candidates"
+        );
 
         // Traceback Matches
         if max_all.is_some()
@@ -131,7 +133,7 @@ where
                 num_matches,
                 k,
                 traceback_lambda,
-            );
+            )?;
 
             offset = max_all.unwrap();
         }
@@ -156,7 +158,7 @@ where
             // Update state
             s = gap_extend_score;
             k -= 1;
-            // offset.data.iter_mut().for_each(|offset| *offset -= 1);
+            // offset.iter_mut().for_each(|offset| *offset -= 1);
             offset -= 1;
             backtrace_op = types::BacktraceOperation::Insertion;
         } else if max_all == ins_open {
@@ -165,7 +167,7 @@ where
             // Update state
             s = gap_open_score;
             k -= 1;
-            // offset.data.iter_mut().for_each(|offset| *offset -= 1);
+            // offset.iter_mut().for_each(|offset| *offset -= 1);
             offset -= 1;
             backtrace_op = types::BacktraceOperation::MatchMismatch;
         } else if max_all == misms {
@@ -174,18 +176,26 @@ where
 
             // Update state
             s = mismatch_score;
-            // offset.data.iter_mut().for_each(|offset| *offset -= 1);
+            // offset.iter_mut().for_each(|offset| *offset -= 1);
             offset -= 1;
         } else {
-            panic!("Backtrace error: No link found during backtrace");
+            return Err(types::AlignError::TracebackInconsistency {
+                score: s,
+                k,
+                offset,
+            });
         }
 
         v = utils::compute_v(offset, k);
         h = utils::compute_h(offset, k);
 
-        if config.verbosity > 5 {
-            eprintln!("\t\t({}, {}) s {}", v, h, s);
-        }
+        tracing::trace!(
+            score = s,
+            v,
+            h,
+            op = ?cigar.as_bytes().last().map(|op| *op as char),
+            "traceback step"
+        );
     }
 
     // reached the end of one or both of the sequences
@@ -198,7 +208,7 @@ where
             num_matches,
             k,
             traceback_lambda,
-        );
+        )?;
     } else {
         // add indels
         while v > 0 {
@@ -212,6 +222,341 @@ where
         }
     }
 
-    let reversed_cigar = cigar.chars().rev().collect::<String>();
-    reversed_cigar
+    // the ops were pushed from the end of the alignment
+    let reversed_cigar: Cigar = cigar.chars().rev().filter_map(CigarOp::from_char).collect();
+    Ok(reversed_cigar)
+}
+
+/// Traceback of an end to end alignment with two gap pieces
+fn wf_traceback_two_piece<G>(
+    wavefronts: &types::WaveFronts,
+    score: usize,
+    config: &types::Config,
+    traceback_lambda: &mut G,
+) -> Result<Cigar, types::AlignError>
+where
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    use types::WfType;
+
+    let k: i32 = wavefronts.a_k;
+    let offset: i32 = wavefronts
+        .get_m_wavefront(score as i32)
+        .and_then(|m_wf| m_wf.get_offset(k))
+        .map(|offsets| offsets.max())
+        .ok_or(types::AlignError::TracebackInconsistency {
+            score: score as i32,
+            k,
+            offset: 0,
+        })?;
+
+    let is_start = |s: i32, wf_type: WfType, k: i32, _: i32| -> bool {
+        s == 0 && wf_type == WfType::M && k == 0
+    };
+
+    let mut ops: Vec<CigarOp> = Vec::new();
+    wf_traceback_from(
+        wavefronts,
+        config,
+        (score as i32, k, offset, WfType::M),
+        is_start,
+        &mut ops,
+    )?;
+
+    let cigar: Cigar = ops.into_iter().rev().collect();
+    check_matches(&cigar, traceback_lambda)?;
+
+    Ok(cigar)
+}
+
+/// Follow a path back from (score, k, offset) on the given wavefront until
+/// is_start says the alignment started at the cell (score, wavefront, k, offset)
+/// Ops are pushed in reverse and the (score, k, wavefront) of the start is returned
+/// A path on the M wavefront can only start at the start of the text or of
+/// the query i.e. at offset max(k, 0)
+///
+/// Unlike [wf_traceback] the path can begin and end in any wavefront, which
+/// alignments made up of several pieces need
+pub fn wf_traceback_from<S>(
+    wavefronts: &types::WaveFronts,
+    config: &types::Config,
+    (mut s, mut k, mut offset, mut wf_type): (i32, i32, i32, types::WfType),
+    is_start: S,
+    ops: &mut Vec<CigarOp>,
+) -> Result<(i32, i32, types::WfType), types::AlignError>
+where
+    S: Fn(i32, types::WfType, i32, i32) -> bool,
+{
+    use types::WfType;
+
+    let x: i32 = config.penalties.mismatch;
+    let o: i32 = config.penalties.gap_open;
+    let e: i32 = config.penalties.gap_extend;
+
+    let tlen: i32 = wavefronts.max_k as i32;
+    let qlen: i32 = -wavefronts.min_k as i32;
+
+    // linear gaps go straight from M to M
+    let linear: bool = !config.distance.has_gap_wavefronts();
+
+    // the gap open and extend of the I and D wavefronts, or of I2 and D2
+    let (o2, e2): (i32, i32) = config.distance.second_gap_piece().unwrap_or((o, e));
+    let gap_piece = |wf_type: WfType| -> (i32, i32) {
+        match wf_type {
+            WfType::I2 | WfType::D2 => (o2, e2),
+            _ => (o, e),
+        }
+    };
+
+    // the same candidates wf_next computed for (score, k)
+    let furthest = |score: i32, wf_type: WfType, from_k: i32, k: i32, delta: i32| -> i32 {
+        let maybe_wf: Option<&types::WaveFront> = match wf_type {
+            WfType::M => wavefronts.get_m_wavefront(score),
+            WfType::I => wavefronts.get_i_wavefront(score),
+            WfType::D => wavefronts.get_d_wavefront(score),
+            WfType::I2 => wavefronts.get_i2_wavefront(score),
+            WfType::D2 => wavefronts.get_d2_wavefront(score),
+        };
+
+        maybe_wf
+            .and_then(|wf| wf.get_offset(from_k))
+            .and_then(|offsets| {
+                offsets
+                    .iter()
+                    .map(|offset| offset + delta)
+                    .filter(|offset| *offset >= 0 && *offset <= tlen && offset - k <= qlen)
+                    .max()
+            })
+            .unwrap_or(NULL_OFFSET)
+    };
+
+    loop {
+        let inconsistent = types::AlignError::TracebackInconsistency {
+            score: s,
+            k,
+            offset,
+        };
+
+        match wf_type {
+            WfType::M => {
+                let sub: i32 = furthest(s - x, WfType::M, k, k, 1);
+                let (ins, del): (i32, i32) = if linear {
+                    (
+                        furthest(s - e, WfType::M, k - 1, k, 1),
+                        furthest(s - e, WfType::M, k + 1, k, 0),
+                    )
+                } else {
+                    (
+                        furthest(s, WfType::I, k, k, 0),
+                        furthest(s, WfType::D, k, k, 0),
+                    )
+                };
+                let ins2: i32 = furthest(s, WfType::I2, k, k, 0);
+                let del2: i32 = furthest(s, WfType::D2, k, k, 0);
+                let origin: i32 = max!(k, 0);
+                let start: i32 = if is_start(s, WfType::M, k, origin) {
+                    origin
+                } else {
+                    NULL_OFFSET
+                };
+
+                // where the extension began
+                let begin: i32 = *[sub, ins, del, ins2, del2, start].iter().max().unwrap();
+                if begin < 0 || begin > offset {
+                    return Err(inconsistent);
+                }
+
+                (begin..offset).for_each(|_| ops.push(CigarOp::Match));
+                offset = begin;
+
+                if begin == sub {
+                    ops.push(CigarOp::Mismatch);
+                    s -= x;
+                    offset -= 1;
+                } else if begin == ins && linear {
+                    ops.push(CigarOp::Insertion);
+                    s -= e;
+                    k -= 1;
+                    offset -= 1;
+                } else if begin == ins {
+                    wf_type = WfType::I;
+                } else if begin == del && linear {
+                    ops.push(CigarOp::Deletion);
+                    s -= e;
+                    k += 1;
+                } else if begin == del {
+                    wf_type = WfType::D;
+                } else if begin == ins2 {
+                    wf_type = WfType::I2;
+                } else if begin == del2 {
+                    wf_type = WfType::D2;
+                } else {
+                    return Ok((s, k, WfType::M));
+                }
+            }
+            WfType::I | WfType::I2 => {
+                if is_start(s, wf_type, k, offset) {
+                    return Ok((s, k, wf_type));
+                }
+
+                let (o, e) = gap_piece(wf_type);
+                if furthest(s - o - e, WfType::M, k - 1, k, 1) == offset {
+                    s -= o + e;
+                    wf_type = WfType::M;
+                } else if furthest(s - e, wf_type, k - 1, k, 1) == offset {
+                    s -= e;
+                } else {
+                    return Err(inconsistent);
+                }
+
+                ops.push(CigarOp::Insertion);
+                k -= 1;
+                offset -= 1;
+            }
+            WfType::D | WfType::D2 => {
+                if is_start(s, wf_type, k, offset) {
+                    return Ok((s, k, wf_type));
+                }
+
+                let (o, e) = gap_piece(wf_type);
+                if furthest(s - o - e, WfType::M, k + 1, k, 0) == offset {
+                    s -= o + e;
+                    wf_type = WfType::M;
+                } else if furthest(s - e, wf_type, k + 1, k, 0) == offset {
+                    s -= e;
+                } else {
+                    return Err(inconsistent);
+                }
+
+                ops.push(CigarOp::Deletion);
+                k += 1;
+            }
+        }
+    }
+}
+
+/// Traceback of an ends-free alignment that ends on (k, offset) at the given score
+/// The unaligned ends of the text and query are insertions and deletions
+/// that the score doesn't count
+pub fn wf_traceback_ends_free<G>(
+    wavefronts: &types::WaveFronts,
+    score: usize,
+    (k, offset): (i32, i32),
+    config: &types::Config,
+    traceback_lambda: &mut G,
+) -> Result<Cigar, types::AlignError>
+where
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    use types::WfType;
+
+    let _span = tracing::debug_span!("wf_traceback_ends_free", score, k, offset).entered();
+
+    let tlen: i32 = wavefronts.max_k as i32;
+    let qlen: i32 = -wavefronts.min_k as i32;
+
+    // any diagonal of the initial wavefront is a start
+    let is_start = |s: i32, wf_type: WfType, k: i32, _: i32| -> bool {
+        s == 0
+            && wf_type == WfType::M
+            && wavefronts
+                .get_m_wavefront(0)
+                .is_some_and(|m_wf| m_wf.in_bounds(k))
+    };
+
+    let mut ops: Vec<CigarOp> = Vec::new();
+
+    // the end that was left unaligned
+    let (v, h) = (utils::compute_v(offset, k), utils::compute_h(offset, k));
+    ops.extend(std::iter::repeat_n(CigarOp::Deletion, (qlen - v) as usize));
+    ops.extend(std::iter::repeat_n(CigarOp::Insertion, (tlen - h) as usize));
+
+    let (_, start_k, _) = wf_traceback_from(
+        wavefronts,
+        config,
+        (score as i32, k, offset, WfType::M),
+        is_start,
+        &mut ops,
+    )?;
+
+    // and the start
+    let (op, skipped) = if start_k > 0 {
+        (CigarOp::Insertion, start_k)
+    } else {
+        (CigarOp::Deletion, -start_k)
+    };
+    ops.extend(std::iter::repeat_n(op, skipped as usize));
+
+    let cigar: Cigar = ops.into_iter().rev().collect();
+    check_matches(&cigar, traceback_lambda)?;
+
+    Ok(cigar)
+}
+
+/// Traceback of an extension that ends on (k, offset) at the given score
+/// The rest of the query is soft clipped, the rest of the text is left out
+pub fn wf_traceback_extension<G>(
+    wavefronts: &types::WaveFronts,
+    score: usize,
+    (k, offset): (i32, i32),
+    config: &types::Config,
+    traceback_lambda: &mut G,
+) -> Result<Cigar, types::AlignError>
+where
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    use types::WfType;
+
+    let _span = tracing::debug_span!("wf_traceback_extension", score, k, offset).entered();
+
+    let qlen: i32 = -wavefronts.min_k as i32;
+    let clipped: i32 = qlen - utils::compute_v(offset, k);
+
+    let mut ops: Vec<CigarOp> = vec![CigarOp::SoftClip; clipped as usize];
+
+    // extensions start at the start of both sequences
+    let is_start = |s: i32, wf_type: WfType, k: i32, _: i32| -> bool {
+        s == 0 && wf_type == WfType::M && k == 0
+    };
+    wf_traceback_from(
+        wavefronts,
+        config,
+        (score as i32, k, offset, WfType::M),
+        is_start,
+        &mut ops,
+    )?;
+
+    let cigar: Cigar = ops.into_iter().rev().collect();
+    check_matches(&cigar, traceback_lambda)?;
+
+    Ok(cigar)
+}
+
+/// Ask the traceback lambda about every run of matches in the cigar
+pub fn check_matches<G>(cigar: &Cigar, traceback_lambda: &mut G) -> Result<(), types::AlignError>
+where
+    G: FnMut((i32, i32), (i32, i32)) -> bool,
+{
+    let (mut v, mut h) = (0, 0);
+    for &(op, len) in cigar.runs() {
+        let len = len as i32;
+
+        if op == CigarOp::Match && !traceback_lambda((v, v + len), (h, h + len)) {
+            return Err(types::AlignError::LambdaContractViolation(format!(
+                "traceback lambda rejected the matches at query {:?} text {:?}",
+                (v, v + len),
+                (h, h + len)
+            )));
+        }
+
+        if op.consumes_query() {
+            v += len;
+        }
+
+        if op.consumes_text() {
+            h += len;
+        }
+    }
+
+    Ok(())
 }